@@ -0,0 +1,431 @@
+//!
+//! Tag detector backends
+//!
+//! We have a few AprilTag detectors to choose from. They all take the same frames and hand back the
+//! same detections, so they can be compared on the same frames and swapped without touching any of
+//! the pose code.
+//!
+
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::slice;
+
+use apriltag::{DetectorBuilder, Family, Image};
+use apriltag_sys::{apriltag_family_t, image_u8_t};
+use cu_sensor_payloads::CuImage;
+use cu29::prelude::*;
+use image::{DynamicImage, GrayImage};
+
+use crate::family::TagFamily;
+
+/// Decision margin at and above which a detection gets full weight
+const FULL_WEIGHT_MARGIN: f64 = 50.0;
+/// Size at and above which a tag gets full weight, as the square root of its area (px)
+const FULL_WEIGHT_SIZE: f64 = 50.0;
+/// Least weight either a low margin or a small size can bring a tag down to
+const MIN_WEIGHT: f64 = 0.1;
+
+/// A tag found by one of the detector backends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagDetection {
+    pub family: TagFamily,
+    pub id: usize,
+    /// Corners in pixel coordinates
+    ///
+    /// These wrap counter-clockwise around the tag, starting from its bottom left corner, which is
+    /// what the C library does.
+    pub corners: [[f64; 2]; 4],
+    /// How confident the backend is in the detection
+    ///
+    /// Backends that don't report one use [`f32::INFINITY`], so their detections get past margin
+    /// thresholds.
+    pub decision_margin: f32,
+    /// How far sub-pixel refinement moved the corners, at most (px)
+    ///
+    /// This is 0 if they weren't refined.
+    pub corner_correction: f64,
+}
+impl TagDetection {
+    /// How much to trust this detection when solving for pose, relative to the others
+    ///
+    /// Tags with low decision margins or that only cover a few pixels count for less. A tag seen at
+    /// a steep angle covers fewer pixels too, so that's accounted for by its area.
+    pub fn weight(&self) -> f64 {
+        let margin = self.decision_margin as f64;
+        let margin_factor = if margin.is_finite() {
+            (margin / FULL_WEIGHT_MARGIN).clamp(MIN_WEIGHT, 1.0)
+        } else {
+            1.0
+        };
+
+        // Shoelace formula
+        let area = (0..4)
+            .map(|i| {
+                let ([x0, y0], [x1, y1]) = (self.corners[i], self.corners[(i + 1) % 4]);
+                x0 * y1 - x1 * y0
+            })
+            .sum::<f64>()
+            .abs()
+            / 2.0;
+        let size_factor = (area.sqrt() / FULL_WEIGHT_SIZE).clamp(MIN_WEIGHT, 1.0);
+
+        margin_factor * size_factor
+    }
+}
+
+/// An AprilTag detector
+pub trait TagDetector {
+    /// Find tags in a grayscale frame
+    fn detect(&mut self, image: &CuImage<Vec<u8>>) -> Vec<TagDetection>;
+}
+
+/// Which detector an [`AprilTags`](crate::AprilTags) task uses
+///
+/// This is set with the task's `detector` key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectorBackend {
+    /// The reference C library
+    #[default]
+    Apriltag,
+    /// The pure-Rust detector from the `aprilgrid` crate, which we use for calibration
+    Aprilgrid,
+    /// Our own detector (CAT)
+    Chalkydri,
+}
+impl DetectorBackend {
+    /// Create a detector for a tag family
+    pub fn build(self, family: TagFamily, bits_corrected: u32) -> CuResult<Box<dyn TagDetector>> {
+        Ok(match self {
+            Self::Apriltag => Box::new(AprilTagDetector::new(family, bits_corrected)),
+            Self::Aprilgrid => Box::new(AprilGridDetector::new(family)?),
+            Self::Chalkydri => Box::new(CatDetector::new(family, bits_corrected)),
+        })
+    }
+}
+impl std::str::FromStr for DetectorBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apriltag" => Ok(Self::Apriltag),
+            "aprilgrid" => Ok(Self::Aprilgrid),
+            "chalkydri" => Ok(Self::Chalkydri),
+            _ => Err(format!("invalid detector: {s}")),
+        }
+    }
+}
+
+/// The reference C library
+pub struct AprilTagDetector {
+    family: TagFamily,
+    detector: apriltag::Detector,
+}
+impl AprilTagDetector {
+    pub fn new(family: TagFamily, bits_corrected: u32) -> Self {
+        let detector = DetectorBuilder::default()
+            .add_family_bits(
+                family.name().parse::<Family>().unwrap(),
+                bits_corrected as usize,
+            )
+            .build()
+            .unwrap();
+
+        Self { family, detector }
+    }
+}
+impl TagDetector for AprilTagDetector {
+    fn detect(&mut self, image: &CuImage<Vec<u8>>) -> Vec<TagDetection> {
+        let image = image_from_cuimage(image);
+
+        self.detector
+            .detect(&image)
+            .iter()
+            .map(|detection| TagDetection {
+                family: self.family,
+                id: detection.id(),
+                corners: detection.corners(),
+                decision_margin: detection.decision_margin(),
+                corner_correction: 0.0,
+            })
+            .collect()
+    }
+}
+
+fn image_from_cuimage<A>(cu_image: &CuImage<A>) -> ManuallyDrop<Image>
+where
+    A: ArrayLike<Element = u8>,
+{
+    unsafe {
+        // Try to emulate what the C code is doing on the heap to avoid double free
+        let buffer_ptr = cu_image.buffer_handle.with_inner(|inner| inner.as_ptr());
+        let low_level_img = Box::new(image_u8_t {
+            buf: buffer_ptr as *mut u8,
+            width: cu_image.format.width as i32,
+            height: cu_image.format.height as i32,
+            stride: cu_image.format.stride as i32,
+        });
+        let ptr = Box::into_raw(low_level_img);
+        ManuallyDrop::new(Image::from_raw(ptr))
+    }
+}
+
+/// The `aprilgrid` crate's detector
+///
+/// It doesn't report decision margins or let us pick how many bits to correct.
+pub struct AprilGridDetector {
+    family: TagFamily,
+    detector: aprilgrid::detector::TagDetector,
+}
+impl AprilGridDetector {
+    pub fn new(family: TagFamily) -> CuResult<Self> {
+        let aprilgrid_family = match family {
+            TagFamily::Tag36h11 => aprilgrid::TagFamily::T36H11,
+            TagFamily::Tag25h9 => aprilgrid::TagFamily::T25H9,
+            TagFamily::Tag16h5 => aprilgrid::TagFamily::T16H5,
+            _ => {
+                return Err(CuError::from(format!("aprilgrid doesn't support {family}")));
+            }
+        };
+
+        Ok(Self {
+            family,
+            detector: aprilgrid::detector::TagDetector::new(&aprilgrid_family, None),
+        })
+    }
+}
+impl TagDetector for AprilGridDetector {
+    fn detect(&mut self, image: &CuImage<Vec<u8>>) -> Vec<TagDetection> {
+        let (gray, width, height) = packed_gray(image);
+        let Some(gray) = GrayImage::from_raw(width as u32, height as u32, gray) else {
+            return Vec::new();
+        };
+
+        self.detector
+            .detect(&DynamicImage::ImageLuma8(gray))
+            .into_iter()
+            .map(|(id, corners)| TagDetection {
+                family: self.family,
+                id: id as usize,
+                corners: corners.map(|(x, y)| [x as f64, y as f64]),
+                decision_margin: f32::INFINITY,
+                corner_correction: 0.0,
+            })
+            .collect()
+    }
+}
+
+/// Our own detector
+pub struct CatDetector {
+    family: TagFamily,
+    source: cat::TagFamily,
+    bits_corrected: u32,
+    /// The detector and the frame size it was set up for
+    ///
+    /// CAT needs to know the frame size up front, so this gets set up on the first frame.
+    detector: Option<(cat::Detector, usize, usize)>,
+}
+impl CatDetector {
+    pub fn new(family: TagFamily, bits_corrected: u32) -> Self {
+        Self {
+            family,
+            source: cat_family(family),
+            bits_corrected,
+            detector: None,
+        }
+    }
+}
+impl TagDetector for CatDetector {
+    fn detect(&mut self, image: &CuImage<Vec<u8>>) -> Vec<TagDetection> {
+        let (gray, width, height) = packed_gray(image);
+
+        let detector = match &mut self.detector {
+            Some((detector, w, h)) if (*w, *h) == (width, height) => detector,
+            detector => {
+                let mut new = cat::Detector::new(width, height, &[]);
+                new.add_family(self.source.clone(), self.bits_corrected);
+
+                &mut detector.insert((new, width, height)).0
+            }
+        };
+
+        detector
+            .detect(&gray)
+            .into_iter()
+            .map(|detection| TagDetection {
+                family: self.family,
+                id: detection.id(),
+                corners: detection.corners(),
+                decision_margin: detection.decision_margin(),
+                corner_correction: 0.0,
+            })
+            .collect()
+    }
+}
+
+/// Create one of the C library's tag families
+fn c_family(
+    family: TagFamily,
+) -> (
+    *mut apriltag_family_t,
+    unsafe extern "C" fn(*mut apriltag_family_t),
+) {
+    use apriltag_sys::*;
+
+    unsafe {
+        match family {
+            TagFamily::Tag36h11 => (tag36h11_create(), tag36h11_destroy),
+            TagFamily::Tag25h9 => (tag25h9_create(), tag25h9_destroy),
+            TagFamily::Tag16h5 => (tag16h5_create(), tag16h5_destroy),
+            TagFamily::TagCircle21h7 => (tagCircle21h7_create(), tagCircle21h7_destroy),
+            TagFamily::TagCircle49h12 => (tagCircle49h12_create(), tagCircle49h12_destroy),
+            TagFamily::TagCustom48h12 => (tagCustom48h12_create(), tagCustom48h12_destroy),
+            TagFamily::TagStandard41h12 => (tagStandard41h12_create(), tagStandard41h12_destroy),
+            TagFamily::TagStandard52h13 => (tagStandard52h13_create(), tagStandard52h13_destroy),
+        }
+    }
+}
+
+/// Copy one of the C library's tag families into CAT's format
+///
+/// The C library is linked in anyway, so its codebooks are already in the binary. This way CAT
+/// doesn't need any family sources on disk.
+fn cat_family(family: TagFamily) -> cat::TagFamily {
+    let (raw, destroy) = c_family(family);
+
+    unsafe {
+        let tf = &*raw;
+        let nbits = tf.nbits as usize;
+        let source = cat::TagFamily {
+            name: family.name().to_owned(),
+            h: tf.h,
+            nbits: tf.nbits,
+            width_at_border: tf.width_at_border as u32,
+            total_width: tf.total_width as u32,
+            reversed_border: tf.reversed_border,
+            bit_x: slice::from_raw_parts(tf.bit_x, nbits)
+                .iter()
+                .map(|&x| x as i32)
+                .collect(),
+            bit_y: slice::from_raw_parts(tf.bit_y, nbits)
+                .iter()
+                .map(|&y| y as i32)
+                .collect(),
+            codes: slice::from_raw_parts(tf.codes, tf.ncodes as usize).to_vec(),
+        };
+        destroy(raw);
+
+        source
+    }
+}
+
+/// Copy a grayscale frame into a buffer without any padding at the end of each row
+fn packed_gray(image: &CuImage<Vec<u8>>) -> (Vec<u8>, usize, usize) {
+    let width = image.format.width as usize;
+    let height = image.format.height as usize;
+    let stride = image.format.stride as usize;
+
+    let gray = image.buffer_handle.with_inner(|inner| {
+        let src = inner.deref();
+        if stride == width {
+            src[..width * height].to_vec()
+        } else {
+            src.chunks(stride)
+                .take(height)
+                .flat_map(|row| &row[..width])
+                .copied()
+                .collect()
+        }
+    });
+
+    (gray, width, height)
+}
+
+/// Render a tag with the C library, with each cell `scale` pixels wide and a white margin around it
+///
+/// Returns the frame and where the corners of the tag's border should be detected.
+#[cfg(test)]
+fn render_tag(family: TagFamily, id: usize, scale: usize) -> (CuImage<Vec<u8>>, [[f64; 2]; 4]) {
+    use cu_sensor_payloads::CuImageBufferFormat;
+
+    let (raw, destroy) = c_family(family);
+    let (cells, size, width_at_border) = unsafe {
+        let image = apriltag_sys::apriltag_to_image(raw, id as u32);
+        let size = (*image).width as usize;
+        let stride = (*image).stride as usize;
+        let cells = (0..size * size)
+            .map(|i| *(*image).buf.add(i / size * stride + i % size))
+            .collect::<Vec<_>>();
+        apriltag_sys::image_u8_destroy(image);
+
+        let width_at_border = (*raw).width_at_border as usize;
+        destroy(raw);
+
+        (cells, size, width_at_border)
+    };
+
+    let margin = 2;
+    let width = (size + 2 * margin) * scale;
+    let gray = (0..width * width)
+        .map(|i| {
+            let (x, y) = ((i % width) / scale, (i / width) / scale);
+            match (x.checked_sub(margin), y.checked_sub(margin)) {
+                (Some(x), Some(y)) if x < size && y < size => cells[y * size + x],
+                _ => 255,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Pixel centers are at whole coordinates, so edges are halfway between them
+    let lo = ((margin + (size - width_at_border) / 2) * scale) as f64 - 0.5;
+    let hi = lo + (width_at_border * scale) as f64;
+    let image = CuImage::new(
+        CuImageBufferFormat {
+            width: width as u32,
+            height: width as u32,
+            stride: width as u32,
+            pixel_format: *b"GRAY",
+        },
+        CuHandle::new_detached(gray),
+    );
+
+    (image, [[lo, hi], [hi, hi], [hi, lo], [lo, lo]])
+}
+
+#[test]
+fn cat_decodes_tag36h11() {
+    let (image, expected) = render_tag(TagFamily::Tag36h11, 42, 8);
+
+    let detections = CatDetector::new(TagFamily::Tag36h11, 2).detect(&image);
+    assert_eq!(detections.len(), 1);
+    assert_eq!(detections[0].family, TagFamily::Tag36h11);
+    assert_eq!(detections[0].id, 42);
+    for (corner, expected) in detections[0].corners.iter().zip(expected) {
+        let err = (corner[0] - expected[0]).hypot(corner[1] - expected[1]);
+        assert!(err < 1.5, "{corner:?} != {expected:?}");
+    }
+}
+
+#[test]
+fn backends_agree_on_corners() {
+    let (image, expected) = render_tag(TagFamily::Tag36h11, 7, 8);
+
+    for backend in [
+        DetectorBackend::Apriltag,
+        DetectorBackend::Aprilgrid,
+        DetectorBackend::Chalkydri,
+    ] {
+        let detections = backend
+            .build(TagFamily::Tag36h11, 2)
+            .unwrap()
+            .detect(&image);
+        assert_eq!(detections.len(), 1, "{backend:?}");
+        assert_eq!(detections[0].id, 7, "{backend:?}");
+
+        // Same corners, starting from the same one
+        for (corner, expected) in detections[0].corners.iter().zip(expected) {
+            let err = (corner[0] - expected[0]).hypot(corner[1] - expected[1]);
+            assert!(err < 1.5, "{backend:?}: {corner:?} != {expected:?}");
+        }
+    }
+}
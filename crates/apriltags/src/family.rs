@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bincode::{Decode, Encode};
+use chalkydri_sqpnp::Iso3;
+use cu29::prelude::CuResult;
+
+use crate::detector::{DetectorBackend, TagDetector};
+
+/// An AprilTag family
+///
+/// IDs are only unique within a family, so anything that reports an ID should report its family
+/// too.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize,
+)]
+pub enum TagFamily {
+    /// What's on the field
+    #[default]
+    #[serde(rename = "tag36h11")]
+    Tag36h11,
+    #[serde(rename = "tag25h9")]
+    Tag25h9,
+    /// What used to be on the field
+    #[serde(rename = "tag16h5")]
+    Tag16h5,
+    #[serde(rename = "tagCircle21h7")]
+    TagCircle21h7,
+    #[serde(rename = "tagCircle49h12")]
+    TagCircle49h12,
+    #[serde(rename = "tagCustom48h12")]
+    TagCustom48h12,
+    #[serde(rename = "tagStandard41h12")]
+    TagStandard41h12,
+    #[serde(rename = "tagStandard52h13")]
+    TagStandard52h13,
+}
+impl TagFamily {
+    /// The family's name, as the AprilTag library spells it
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Tag36h11 => "tag36h11",
+            Self::Tag25h9 => "tag25h9",
+            Self::Tag16h5 => "tag16h5",
+            Self::TagCircle21h7 => "tagCircle21h7",
+            Self::TagCircle49h12 => "tagCircle49h12",
+            Self::TagCustom48h12 => "tagCustom48h12",
+            Self::TagStandard41h12 => "tagStandard41h12",
+            Self::TagStandard52h13 => "tagStandard52h13",
+        }
+    }
+
+    /// What the family is called in whacknet packets
+    ///
+    /// Robot code decodes these, so they can't change.
+    pub const fn number(&self) -> u8 {
+        match self {
+            Self::Tag36h11 => 0,
+            Self::Tag25h9 => 1,
+            Self::Tag16h5 => 2,
+            Self::TagCircle21h7 => 3,
+            Self::TagCircle49h12 => 4,
+            Self::TagCustom48h12 => 5,
+            Self::TagStandard41h12 => 6,
+            Self::TagStandard52h13 => 7,
+        }
+    }
+}
+impl FromStr for TagFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned()))
+            .map_err(|_| format!("invalid tag family: {s}"))
+    }
+}
+impl fmt::Display for TagFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Configuration for one of the tag families an [`AprilTags`](crate::AprilTags) task detects
+///
+/// These are passed in as a JSON list under the task's `families` key:
+///
+/// ```json
+/// [
+///   { "family": "tag36h11", "bits_corrected": 3 },
+///   { "family": "tag16h5", "bits_corrected": 0, "field_layout": "practice" }
+/// ]
+/// ```
+///
+/// Families can have different `tag_size`s, each tag is solved at its own family's size.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyConfig {
+    pub family: TagFamily,
+    #[serde(default = "FamilyConfig::default_bits_corrected")]
+    pub bits_corrected: u32,
+    /// The field layout this family's tags are in
+    ///
+    /// If this isn't set, the family follows the active field layout. Switching the active layout
+    /// over the network switches this family too.
+    #[serde(default)]
+    pub field_layout: Option<String>,
+    /// How big this family's tags are, edge to edge of the black border (m)
+    #[serde(default = "FamilyConfig::default_tag_size")]
+    pub tag_size: f64,
+}
+impl FamilyConfig {
+    const fn default_bits_corrected() -> u32 {
+        3
+    }
+
+    const fn default_tag_size() -> f64 {
+        chalkydri_sqpnp::TAG_SIZE
+    }
+}
+
+/// A detector for a single tag family
+pub(crate) struct FamilyDetector {
+    pub detector: Box<dyn TagDetector>,
+    /// This family's own field layout, if it doesn't follow the active one
+    pub tags: Option<HashMap<usize, Iso3>>,
+    /// How big this family's tags are (m)
+    pub tag_size: f64,
+}
+impl FamilyDetector {
+    pub fn new(
+        backend: DetectorBackend,
+        family: TagFamily,
+        bits_corrected: u32,
+        tag_size: f64,
+        tags: Option<HashMap<usize, Iso3>>,
+    ) -> CuResult<Self> {
+        Ok(Self {
+            detector: backend.build(family, bits_corrected)?,
+            tags,
+            tag_size,
+        })
+    }
+}
@@ -14,12 +14,8 @@ pub struct AprilTagFieldLayout {
     pub field: Field,
 }
 impl AprilTagFieldLayout {
-    /// Attempt to load field layout from field.json
-    pub fn load() -> Result<HashMap<usize, Iso3>, ()> {
-        let mut f = File::open("field.json").expect("field.json must exist");
-
-        let layout: AprilTagFieldLayout = serde_json::from_reader(&mut f).unwrap();
-
+    /// Get the pose of every tag in the layout, indexed by ID
+    pub fn tags(&self) -> HashMap<usize, Iso3> {
         let mut tags: HashMap<usize, Iso3> = HashMap::new();
         for LayoutTag {
             id,
@@ -28,7 +24,7 @@ impl AprilTagFieldLayout {
                     translation,
                     rotation: LayoutRotation { quaternion },
                 },
-        } in layout.tags.clone()
+        } in self.tags.clone()
         {
             // Turn the field layout values into Rust datatypes
             let translation = na::Translation3::new(translation.x, translation.y, translation.z);
@@ -40,7 +36,97 @@ impl AprilTagFieldLayout {
             tags.insert(id as usize, isometry);
         }
 
-        Ok(tags)
+        tags
+    }
+}
+
+/// Every field layout we know about, and which one is in use
+///
+/// `field.json` is always available as [`FieldLayouts::DEFAULT`]. Any layouts in the Chalkydri
+/// config's `field_layouts` table are available under their own names, so the active layout can be
+/// switched at runtime (welded vs. AndyMark fields, practice fields, etc.).
+#[derive(Debug, Clone)]
+pub struct FieldLayouts {
+    layouts: HashMap<String, HashMap<usize, Iso3>>,
+    active: String,
+}
+impl FieldLayouts {
+    /// Name of the layout loaded from field.json
+    pub const DEFAULT: &str = "default";
+
+    /// Load all the field layouts, selecting the one named by the config's `field_layout`
+    pub fn load() -> Result<Self, Error> {
+        let mut layouts = HashMap::new();
+
+        if let Some(layout) = File::open("field.json")
+            .ok()
+            .and_then(|mut f| serde_json::from_reader::<_, AprilTagFieldLayout>(&mut f).ok())
+        {
+            layouts.insert(Self::DEFAULT.to_owned(), layout.tags());
+        }
+
+        let (selected, configured) = {
+            let cfg = Cfg.read();
+            (cfg.field_layout.clone(), cfg.field_layouts.clone())
+        };
+        for (name, layout) in configured.unwrap_or_default() {
+            match serde_json::from_value::<AprilTagFieldLayout>(layout) {
+                Ok(layout) => {
+                    layouts.insert(name, layout.tags());
+                }
+                Err(err) => {
+                    warn!("field layout '{name}' is invalid: {err}");
+                }
+            }
+        }
+
+        if layouts.is_empty() {
+            return Err(Error::NoFieldLayouts);
+        }
+
+        // Without a layout selected in the config, there has to be a field.json to fall back on
+        let selected = match selected {
+            Some(selected) => selected,
+            None if layouts.contains_key(Self::DEFAULT) => Self::DEFAULT.to_owned(),
+            None => return Err(Error::FieldLayoutNotSelected),
+        };
+
+        let mut field_layouts = Self {
+            layouts,
+            active: Self::DEFAULT.to_owned(),
+        };
+        field_layouts.select(&selected)?;
+
+        Ok(field_layouts)
+    }
+
+    /// Name of the layout in use
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// Tags in the layout in use
+    pub fn tags(&self) -> &HashMap<usize, Iso3> {
+        &self.layouts[&self.active]
+    }
+
+    /// Tags in a specific layout, whether or not it's in use
+    pub fn get(&self, name: &str) -> Result<&HashMap<usize, Iso3>, Error> {
+        self.layouts
+            .get(name)
+            .ok_or_else(|| Error::FieldLayoutDoesNotExist {
+                id: name.to_owned(),
+            })
+    }
+
+    /// Switch to another layout
+    ///
+    /// The active layout is left alone if `name` doesn't exist.
+    pub fn select(&mut self, name: &str) -> Result<&HashMap<usize, Iso3>, Error> {
+        self.get(name)?;
+        self.active = name.to_owned();
+
+        Ok(self.tags())
     }
 }
 
@@ -0,0 +1,449 @@
+//!
+//! Temporal pose filtering
+//!
+//! Every frame gets solved on its own, so noise and the occasional flipped solution go straight to
+//! the RIO. These filters smooth poses over time per camera, using the frames' capture timestamps,
+//! and throw out poses that don't agree with what came before them.
+//!
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+use whacknet::{RobotPose, VisionUncertainty};
+
+/// Frames further apart than this don't say anything about each other (µs)
+const MAX_GAP: u64 = 500_000;
+/// How many outliers in a row it takes to decide the robot really did move there
+const MAX_REJECTIONS: usize = 5;
+/// Smallest std devs we'll gate with, so overconfident solves can't reject everything after them
+const MIN_STD_DEV: f64 = 0.05;
+/// Largest std devs a pose can have and still be filtered
+///
+/// The solver reports [`f64::MAX`] for poses it doesn't trust at all. Squaring that overflows, and
+/// the NaNs it leads to never go away.
+const MAX_STD_DEV: f64 = 10.0;
+
+/// How much we expect the robot's velocity to change, for x and y (m²/s³)
+const TRANSLATION_NOISE: f64 = 4.0;
+/// How much we expect the robot's angular velocity to change (rad²/s³)
+const ROTATION_NOISE: f64 = 16.0;
+/// How unsure we are of the robot's velocity when we start tracking it (m²/s² or rad²/s²)
+const INITIAL_VELOCITY_VARIANCE: f64 = 4.0;
+/// Outlier gate for the Kalman filter
+///
+/// This is the 99.7% point of the chi-squared distribution with 3 degrees of freedom.
+const KALMAN_GATE: f64 = 14.16;
+
+/// How many poses the median filter looks at
+const MEDIAN_WINDOW: usize = 5;
+/// Outlier gate for the median filter, in std devs from the median
+const MEDIAN_GATE: f64 = 3.0;
+
+/// Which filter an [`AprilTags`](crate::AprilTags) task runs its poses through
+///
+/// This is set with the task's `pose_filter` key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoseFilterKind {
+    /// Publish every pose as-is
+    #[default]
+    None,
+    /// Constant velocity Kalman filter
+    Kalman,
+    /// Median of the last few poses
+    Median,
+}
+impl std::str::FromStr for PoseFilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "kalman" => Ok(Self::Kalman),
+            "median" => Ok(Self::Median),
+            _ => Err(format!("invalid pose filter: {s}")),
+        }
+    }
+}
+
+/// A temporal pose filter for one camera
+#[derive(Debug)]
+pub enum PoseFilter {
+    None,
+    Kalman(KalmanFilter),
+    Median(MedianFilter),
+}
+impl PoseFilter {
+    pub fn new(kind: PoseFilterKind) -> Self {
+        match kind {
+            PoseFilterKind::None => Self::None,
+            PoseFilterKind::Kalman => Self::Kalman(KalmanFilter::default()),
+            PoseFilterKind::Median => Self::Median(MedianFilter::default()),
+        }
+    }
+
+    /// Feed in a pose solved from a frame captured at `time` (µs)
+    ///
+    /// Returns the pose and std devs to publish, or `None` if the pose was thrown out. Poses with
+    /// std devs over [`MAX_STD_DEV`] are always thrown out, unless there's no filter.
+    pub fn update(
+        &mut self,
+        time: u64,
+        pose: &RobotPose,
+        std_dev: &VisionUncertainty,
+    ) -> Option<(RobotPose, VisionUncertainty)> {
+        let usable = [std_dev.x, std_dev.y, std_dev.rot]
+            .iter()
+            .all(|s| s.is_finite() && *s <= MAX_STD_DEV);
+        if !usable && !matches!(self, Self::None) {
+            return None;
+        }
+
+        match self {
+            Self::None => Some((*pose, *std_dev)),
+            Self::Kalman(filter) => filter.update(time, pose, std_dev),
+            Self::Median(filter) => filter.update(time, pose, std_dev),
+        }
+    }
+
+    /// Forget everything, like when the field layout changes
+    pub fn reset(&mut self) {
+        match self {
+            Self::None => {}
+            Self::Kalman(filter) => *filter = KalmanFilter::default(),
+            Self::Median(filter) => *filter = MedianFilter::default(),
+        }
+    }
+}
+
+/// Wrap an angle into [-π, π]
+fn wrap(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Position and velocity along one axis, and how sure we are of them
+#[derive(Debug, Clone, Copy)]
+struct Axis {
+    pos: f64,
+    vel: f64,
+    cov: [[f64; 2]; 2],
+}
+impl Axis {
+    fn new(pos: f64, variance: f64) -> Self {
+        Self {
+            pos,
+            vel: 0.0,
+            cov: [[variance, 0.0], [0.0, INITIAL_VELOCITY_VARIANCE]],
+        }
+    }
+
+    /// Move the state forward in time
+    fn predict(&mut self, dt: f64, noise: f64) {
+        let [[p00, p01], [p10, p11]] = self.cov;
+
+        self.pos += self.vel * dt;
+        self.cov = [
+            [
+                p00 + dt * (p10 + p01) + dt * dt * p11 + noise * dt.powi(3) / 3.0,
+                p01 + dt * p11 + noise * dt * dt / 2.0,
+            ],
+            [p10 + dt * p11 + noise * dt * dt / 2.0, p11 + noise * dt],
+        ];
+    }
+
+    /// Fold in a measurement, given how far it is from the prediction
+    fn correct(&mut self, innovation: f64, variance: f64) {
+        let [[p00, p01], [p10, p11]] = self.cov;
+        let s = p00 + variance;
+        let (k0, k1) = (p00 / s, p10 / s);
+
+        self.pos += k0 * innovation;
+        self.vel += k1 * innovation;
+        self.cov = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+/// Constant velocity Kalman filter on x, y, and heading
+///
+/// Each axis is filtered separately, which is close enough for how little they're correlated.
+#[derive(Debug, Default)]
+pub struct KalmanFilter {
+    /// Capture time of the last pose we used (µs)
+    last_time: Option<u64>,
+    axes: Option<[Axis; 3]>,
+    rejections: usize,
+}
+impl KalmanFilter {
+    fn update(
+        &mut self,
+        time: u64,
+        pose: &RobotPose,
+        std_dev: &VisionUncertainty,
+    ) -> Option<(RobotPose, VisionUncertainty)> {
+        let measured = [pose.x, pose.y, pose.rot];
+        let variances = [std_dev.x, std_dev.y, std_dev.rot].map(|s| s.max(MIN_STD_DEV).powi(2));
+
+        let stale = self
+            .last_time
+            .is_none_or(|last| time.saturating_sub(last) > MAX_GAP);
+        let Some(axes) = self.axes.as_mut().filter(|_| !stale) else {
+            return Some(self.restart(time, pose, std_dev));
+        };
+
+        let dt = time.saturating_sub(self.last_time.unwrap_or(time)) as f64 / 1_000_000.0;
+        let mut predicted = *axes;
+        let noise = [TRANSLATION_NOISE, TRANSLATION_NOISE, ROTATION_NOISE];
+        for (axis, noise) in predicted.iter_mut().zip(noise) {
+            axis.predict(dt, noise);
+        }
+
+        let innovations = [
+            measured[0] - predicted[0].pos,
+            measured[1] - predicted[1].pos,
+            wrap(measured[2] - predicted[2].pos),
+        ];
+        let distance: f64 = (0..3)
+            .map(|i| innovations[i].powi(2) / (predicted[i].cov[0][0] + variances[i]))
+            .sum();
+        if distance > KALMAN_GATE {
+            self.rejections += 1;
+            if self.rejections >= MAX_REJECTIONS {
+                return Some(self.restart(time, pose, std_dev));
+            }
+            return None;
+        }
+
+        for i in 0..3 {
+            predicted[i].correct(innovations[i], variances[i]);
+        }
+        predicted[2].pos = wrap(predicted[2].pos);
+
+        *axes = predicted;
+        self.last_time = Some(time);
+        self.rejections = 0;
+
+        Some((
+            RobotPose {
+                x: axes[0].pos,
+                y: axes[1].pos,
+                rot: axes[2].pos,
+            },
+            VisionUncertainty {
+                x: axes[0].cov[0][0].sqrt(),
+                y: axes[1].cov[0][0].sqrt(),
+                rot: axes[2].cov[0][0].sqrt(),
+            },
+        ))
+    }
+
+    /// Start tracking from a pose
+    fn restart(
+        &mut self,
+        time: u64,
+        pose: &RobotPose,
+        std_dev: &VisionUncertainty,
+    ) -> (RobotPose, VisionUncertainty) {
+        self.axes = Some([
+            Axis::new(pose.x, std_dev.x.max(MIN_STD_DEV).powi(2)),
+            Axis::new(pose.y, std_dev.y.max(MIN_STD_DEV).powi(2)),
+            Axis::new(pose.rot, std_dev.rot.max(MIN_STD_DEV).powi(2)),
+        ]);
+        self.last_time = Some(time);
+        self.rejections = 0;
+
+        (*pose, *std_dev)
+    }
+}
+
+/// Median of the last few poses
+///
+/// This is slower to follow the robot than the Kalman filter, but it doesn't need any tuning.
+#[derive(Debug, Default)]
+pub struct MedianFilter {
+    window: VecDeque<(u64, RobotPose, VisionUncertainty)>,
+    rejections: usize,
+}
+impl MedianFilter {
+    fn update(
+        &mut self,
+        time: u64,
+        pose: &RobotPose,
+        std_dev: &VisionUncertainty,
+    ) -> Option<(RobotPose, VisionUncertainty)> {
+        self.window
+            .retain(|(t, _, _)| *t <= time && time - t <= MAX_GAP);
+
+        // Don't gate until there's enough to go on
+        if self.window.len() >= 3 {
+            let median = self.median();
+            let outlier = (pose.x - median.x).abs() > MEDIAN_GATE * std_dev.x.max(MIN_STD_DEV)
+                || (pose.y - median.y).abs() > MEDIAN_GATE * std_dev.y.max(MIN_STD_DEV)
+                || wrap(pose.rot - median.rot).abs() > MEDIAN_GATE * std_dev.rot.max(MIN_STD_DEV);
+
+            if outlier {
+                self.rejections += 1;
+                if self.rejections < MAX_REJECTIONS {
+                    return None;
+                }
+                self.window.clear();
+            }
+        }
+        self.rejections = 0;
+
+        if self.window.len() == MEDIAN_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back((time, *pose, *std_dev));
+
+        // The median of n samples is about 1.25/√n times as noisy as one of them
+        let n = self.window.len() as f64;
+        let scale = 1.2533 / n.sqrt();
+        let mean = |f: fn(&VisionUncertainty) -> f64| {
+            self.window.iter().map(|(_, _, s)| f(s)).sum::<f64>() / n
+        };
+
+        Some((
+            self.median(),
+            VisionUncertainty {
+                x: mean(|s| s.x) * scale,
+                y: mean(|s| s.y) * scale,
+                rot: mean(|s| s.rot) * scale,
+            },
+        ))
+    }
+
+    /// Median of each axis in the window
+    fn median(&self) -> RobotPose {
+        fn median(mut values: Vec<f64>) -> f64 {
+            values.sort_by(f64::total_cmp);
+            let mid = values.len() / 2;
+            if values.len().is_multiple_of(2) {
+                (values[mid - 1] + values[mid]) / 2.0
+            } else {
+                values[mid]
+            }
+        }
+
+        // Headings are unwrapped around the newest one, so a window straddling ±π still works
+        let newest = self.window.back().map_or(0.0, |(_, pose, _)| pose.rot);
+
+        RobotPose {
+            x: median(self.window.iter().map(|(_, p, _)| p.x).collect()),
+            y: median(self.window.iter().map(|(_, p, _)| p.y).collect()),
+            rot: wrap(median(
+                self.window
+                    .iter()
+                    .map(|(_, p, _)| newest + wrap(p.rot - newest))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+#[test]
+fn kalman_rejects_flips() {
+    let mut filter = PoseFilter::new(PoseFilterKind::Kalman);
+    let std_dev = VisionUncertainty {
+        x: 0.1,
+        y: 0.1,
+        rot: 0.05,
+    };
+    let pose = |x: f64| RobotPose {
+        x,
+        y: 2.0,
+        rot: 0.5,
+    };
+
+    for i in 0..10 {
+        assert!(filter.update(i * 20_000, &pose(1.0), &std_dev).is_some());
+    }
+
+    // A flipped solution a few meters away
+    assert!(filter.update(200_000, &pose(4.0), &std_dev).is_none());
+
+    let (filtered, filtered_std_dev) = filter.update(220_000, &pose(1.0), &std_dev).unwrap();
+    assert!((filtered.x - 1.0).abs() < 0.01);
+    assert!(filtered_std_dev.x < std_dev.x);
+
+    // If it keeps saying so, the robot must really be there
+    for i in 0..MAX_REJECTIONS as u64 {
+        filter.update(240_000 + i * 20_000, &pose(4.0), &std_dev);
+    }
+    let (filtered, _) = filter.update(400_000, &pose(4.0), &std_dev).unwrap();
+    assert!((filtered.x - 4.0).abs() < 0.01);
+}
+
+#[test]
+fn kalman_ignores_untrusted_poses() {
+    let mut filter = PoseFilter::new(PoseFilterKind::Kalman);
+    let std_dev = VisionUncertainty {
+        x: 0.1,
+        y: 0.1,
+        rot: 0.05,
+    };
+    let untrusted = VisionUncertainty {
+        x: f64::MAX,
+        y: f64::MAX,
+        rot: f64::MAX,
+    };
+    let pose = RobotPose {
+        x: 1.0,
+        y: 2.0,
+        rot: 0.5,
+    };
+
+    // Not even to start from
+    assert!(filter.update(0, &pose, &untrusted).is_none());
+
+    for i in 1..10 {
+        assert!(filter.update(i * 20_000, &pose, &std_dev).is_some());
+    }
+    for i in 10..10 + MAX_REJECTIONS as u64 {
+        assert!(filter.update(i * 20_000, &pose, &untrusted).is_none());
+    }
+
+    let (filtered, filtered_std_dev) = filter.update(400_000, &pose, &std_dev).unwrap();
+    assert!((filtered.x - 1.0).abs() < 0.01);
+    assert!(filtered_std_dev.x.is_finite() && filtered_std_dev.rot.is_finite());
+}
+
+#[test]
+fn median_rejects_flips() {
+    let mut filter = PoseFilter::new(PoseFilterKind::Median);
+    let std_dev = VisionUncertainty {
+        x: 0.1,
+        y: 0.1,
+        rot: 0.05,
+    };
+
+    for (i, rot) in [PI - 0.01, -PI + 0.01, PI - 0.02, -PI + 0.02]
+        .iter()
+        .enumerate()
+    {
+        let pose = RobotPose {
+            x: 1.0,
+            y: 2.0,
+            rot: *rot,
+        };
+        assert!(filter.update(i as u64 * 20_000, &pose, &std_dev).is_some());
+    }
+
+    let flipped = RobotPose {
+        x: 1.0,
+        y: 2.0,
+        rot: 0.0,
+    };
+    assert!(filter.update(100_000, &flipped, &std_dev).is_none());
+
+    let pose = RobotPose {
+        x: 1.0,
+        y: 2.0,
+        rot: PI,
+    };
+    let (filtered, _) = filter.update(120_000, &pose, &std_dev).unwrap();
+    assert!(wrap(filtered.rot - PI).abs() < 0.02);
+}
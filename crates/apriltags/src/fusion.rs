@@ -0,0 +1,154 @@
+//!
+//! Multi-camera fusion
+//!
+//! Each camera solves its own frames, but when two of them see tags at the same time, one solve
+//! over all of their corners is far better conditioned. Cameras in the same fusion group hand what
+//! they saw to one of them, which solves one pose from all of it. The others don't solve at all, so
+//! no tag makes it to the RIO twice.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use chalkydri_core::prelude::Mutex;
+use chalkydri_sqpnp::CameraObservation;
+
+/// How far apart two frames can be captured and still be fused (µs)
+const FUSION_WINDOW: u64 = 15_000;
+/// How long the solving camera can go without a frame before another one takes over (µs)
+const SOLVER_TIMEOUT: u64 = 250_000;
+
+/// Every fusion group, by name
+static GROUPS: LazyLock<Mutex<HashMap<String, FusionGroup>>> = LazyLock::new(Default::default);
+
+/// Cameras that solve for the robot's pose together
+///
+/// AprilTag tasks join one with the `fusion_group` key.
+#[derive(Clone, Default)]
+pub struct FusionGroup {
+    state: Arc<Mutex<FusionState>>,
+}
+
+#[derive(Default)]
+struct FusionState {
+    /// The latest observation from each camera that hasn't been fused yet, and its capture time
+    pending: HashMap<u8, (u64, CameraObservation)>,
+    /// Which camera solves for the group, and when its latest frame was captured (µs)
+    solver: Option<(u8, u64)>,
+}
+
+impl FusionGroup {
+    /// Join the fusion group with this name, creating it if nobody has yet
+    pub fn join(name: &str) -> Self {
+        GROUPS.lock().entry(name.to_owned()).or_default().clone()
+    }
+
+    /// Share an observation from a frame captured at `time` (µs)
+    ///
+    /// If this camera solves for the group, this returns the other cameras' observations from
+    /// around the same time, to solve together with ours. Otherwise ours is left for the solving
+    /// camera to pick up, and this returns `None`. The solving camera has to call this for every
+    /// frame, even ones without any tags, so the others know it's still there.
+    pub fn exchange(
+        &self,
+        cam_id: u8,
+        time: u64,
+        observation: &CameraObservation,
+    ) -> Option<Vec<CameraObservation>> {
+        let mut state = self.state.lock();
+
+        // Take over if the solving camera has gone quiet
+        let solving = state
+            .solver
+            .is_none_or(|(id, last)| id == cam_id || time.saturating_sub(last) > SOLVER_TIMEOUT);
+        if !solving {
+            if !observation.tags.is_empty() {
+                state.pending.insert(cam_id, (time, observation.clone()));
+            }
+            return None;
+        }
+        state.solver = Some((cam_id, time));
+
+        // Observations are only handed out once, so each frame ends up in at most one pose
+        let mut fused = Vec::new();
+        for (id, (captured, obs)) in std::mem::take(&mut state.pending) {
+            if id == cam_id {
+                continue;
+            }
+
+            if time.abs_diff(captured) <= FUSION_WINDOW {
+                fused.push(obs);
+            } else if captured > time {
+                // Frames captured after ours can still go with one of our later ones
+                state.pending.insert(id, (captured, obs));
+            }
+        }
+
+        Some(fused)
+    }
+}
+
+#[test]
+fn one_camera_solves() {
+    let group = FusionGroup::default();
+    let observation = CameraObservation {
+        tags: vec![chalkydri_sqpnp::Iso3::identity()],
+        bearings: vec![chalkydri_sqpnp::Vec3::z(); 4],
+        weights: Vec::new(),
+        tag_sizes: Vec::new(),
+        robot_to_cam: chalkydri_sqpnp::Iso3::identity(),
+    };
+
+    // The first camera to show up solves, and the other hands its tags over
+    assert_eq!(group.exchange(1, 0, &observation).map(|o| o.len()), Some(0));
+    assert!(group.exchange(2, 5_000, &observation).is_none());
+    assert_eq!(
+        group.exchange(1, 16_000, &observation).map(|o| o.len()),
+        Some(1)
+    );
+    assert_eq!(
+        group.exchange(1, 32_000, &observation).map(|o| o.len()),
+        Some(0)
+    );
+
+    // Until it stops getting frames
+    assert!(group.exchange(2, 200_000, &observation).is_none());
+    assert!(group.exchange(2, 300_000, &observation).is_some());
+    assert!(group.exchange(1, 310_000, &observation).is_none());
+}
+
+#[test]
+fn later_frames_wait_for_the_solver() {
+    let group = FusionGroup::default();
+    let observation = CameraObservation {
+        tags: vec![chalkydri_sqpnp::Iso3::identity()],
+        bearings: vec![chalkydri_sqpnp::Vec3::z(); 4],
+        weights: Vec::new(),
+        tag_sizes: Vec::new(),
+        robot_to_cam: chalkydri_sqpnp::Iso3::identity(),
+    };
+
+    // The second camera's frame is too far ahead of the solver's to fuse yet
+    assert_eq!(group.exchange(1, 0, &observation).map(|o| o.len()), Some(0));
+    assert!(group.exchange(2, 40_000, &observation).is_none());
+    assert_eq!(
+        group.exchange(1, 20_000, &observation).map(|o| o.len()),
+        Some(0)
+    );
+    // But it's still there for the solver's next one
+    assert_eq!(
+        group.exchange(1, 35_000, &observation).map(|o| o.len()),
+        Some(1)
+    );
+
+    // Frames the solver has moved past are dropped
+    assert!(group.exchange(2, 50_000, &observation).is_none());
+    assert_eq!(
+        group.exchange(1, 70_000, &observation).map(|o| o.len()),
+        Some(0)
+    );
+    assert_eq!(
+        group.exchange(1, 75_000, &observation).map(|o| o.len()),
+        Some(0)
+    );
+}
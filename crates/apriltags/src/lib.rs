@@ -11,21 +11,24 @@ extern crate chalkydri_sqpnp;
 extern crate cu_bincode as bincode;
 extern crate serde_json;
 
+mod detector;
+mod family;
 mod field_layout;
+mod filter;
+mod fusion;
+mod refine;
+#[cfg(feature = "rerun")]
+mod viz;
 
 use std::collections::HashMap;
-use std::mem::ManuallyDrop;
-
-use apriltag::{Detector, DetectorBuilder, Family, Image};
-
-use apriltag_sys::image_u8_t;
+use std::time::Duration;
 
 use bincode::de::Decoder;
 use bincode::error::DecodeError;
 use bincode::{Decode, Encode};
 use camera_intrinsic_model::{GenericModel, OpenCVModel5};
 use chalkydri_core::tracing;
-use chalkydri_sqpnp::SqPnP;
+use chalkydri_sqpnp::{CameraObservation, Reprojection, SqPnP, UncertaintyModel};
 use cu_sensor_payloads::CuImage;
 use cu_spatial_payloads::Pose as CuPose;
 use cu29::prelude::*;
@@ -34,18 +37,39 @@ use serde::ser::SerializeTuple;
 use serde::{Deserialize, Deserializer, Serialize};
 
 use chalkydri_sqpnp::Iso3;
-use whacknet::{Comm, CommBundleId, RobotPose, VisionUncertainty};
+use whacknet::{Comm, CommBundleId, RobotPose, TagObservation, VisionUncertainty};
 
-use crate::field_layout::AprilTagFieldLayout;
+use crate::family::FamilyDetector;
+use crate::field_layout::FieldLayouts;
+use crate::filter::PoseFilter;
 
-// the maximum number of detections that can be returned by the detector
-const MAX_DETECTIONS: usize = 16;
+pub use crate::detector::{
+    AprilGridDetector, AprilTagDetector, CatDetector, DetectorBackend, TagDetection, TagDetector,
+};
+pub use crate::family::{FamilyConfig, TagFamily};
+pub use crate::field_layout::FieldLayouts;
+pub use crate::filter::PoseFilterKind;
+pub use crate::fusion::FusionGroup;
+pub use crate::refine::{GrayView, refine_corners};
 
-// Defaults
-const FAMILY: &str = "tag36h11";
+// the maximum number of detections that can be returned by the detector
+const MAX_DETECTIONS: usize = whacknet::MAX_DETECTIONS;
+
+/// What a camera made of a frame, for tasks downstream
+#[derive(Default, Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct PoseEstimate {
+    pub cam_id: u8,
+    /// How many tags this camera detected, even if no pose could be solved from them
+    pub own_tag_count: u8,
+    /// How many tags went into the pose, including ones from other cameras in its fusion group
+    pub tag_count: u8,
+    /// The filtered robot pose, if one was solved
+    pub pose: Option<RobotPose>,
+}
 
 #[derive(Default, Debug, Clone, Encode)]
 pub struct AprilTagDetections {
+    pub families: CuArrayVec<TagFamily, MAX_DETECTIONS>,
     pub ids: CuArrayVec<usize, MAX_DETECTIONS>,
     pub poses: CuArrayVec<CuPose<f32>, MAX_DETECTIONS>,
     pub decision_margins: CuArrayVec<f32, MAX_DETECTIONS>,
@@ -53,10 +77,12 @@ pub struct AprilTagDetections {
 
 impl Decode<()> for AprilTagDetections {
     fn decode<D: Decoder<Context = ()>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let families = CuArrayVec::<TagFamily, MAX_DETECTIONS>::decode(decoder)?;
         let ids = CuArrayVec::<usize, MAX_DETECTIONS>::decode(decoder)?;
         let poses = CuArrayVec::<CuPose<f32>, MAX_DETECTIONS>::decode(decoder)?;
         let decision_margins = CuArrayVec::<f32, MAX_DETECTIONS>::decode(decoder)?;
         Ok(AprilTagDetections {
+            families,
             ids,
             poses,
             decision_margins,
@@ -68,17 +94,20 @@ impl Decode<()> for AprilTagDetections {
 // This is so it can be logged with debug!.
 impl Serialize for AprilTagDetections {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let CuArrayVec(families) = &self.families;
         let CuArrayVec(ids) = &self.ids;
         let CuArrayVec(poses) = &self.poses;
         let CuArrayVec(decision_margins) = &self.decision_margins;
         let mut tup = serializer.serialize_tuple(ids.len())?;
 
-        ids.iter()
+        families
+            .iter()
+            .zip(ids.iter())
             .zip(poses.iter())
             .zip(decision_margins.iter())
-            .map(|((id, pose), margin)| (id, pose, margin))
-            .for_each(|(id, pose, margin)| {
-                tup.serialize_element(&(id, pose, margin)).unwrap();
+            .map(|(((family, id), pose), margin)| (family, id, pose, margin))
+            .for_each(|(family, id, pose, margin)| {
+                tup.serialize_element(&(family, id, pose, margin)).unwrap();
             });
 
         tup.end()
@@ -96,7 +125,7 @@ impl<'de> Deserialize<'de> for AprilTagDetections {
             type Value = AprilTagDetections;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a tuple of (id, pose, decision_margin)")
+                formatter.write_str("a tuple of (family, id, pose, decision_margin)")
             }
 
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -104,7 +133,9 @@ impl<'de> Deserialize<'de> for AprilTagDetections {
                 A: serde::de::SeqAccess<'de>,
             {
                 let mut detections = AprilTagDetections::new();
-                while let Some((id, pose, decision_margin)) = seq.next_element()? {
+                while let Some((family, id, pose, decision_margin)) = seq.next_element()? {
+                    let CuArrayVec(families) = &mut detections.families;
+                    families.push(family);
                     let CuArrayVec(ids) = &mut detections.ids;
                     ids.push(id);
                     let CuArrayVec(poses) = &mut detections.poses;
@@ -127,16 +158,19 @@ impl AprilTagDetections {
     pub fn filtered_by_decision_margin(
         &self,
         threshold: f32,
-    ) -> impl Iterator<Item = (usize, &CuPose<f32>, f32)> {
+    ) -> impl Iterator<Item = (TagFamily, usize, &CuPose<f32>, f32)> {
+        let CuArrayVec(families) = &self.families;
         let CuArrayVec(ids) = &self.ids;
         let CuArrayVec(poses) = &self.poses;
         let CuArrayVec(decision_margins) = &self.decision_margins;
 
-        ids.iter()
+        families
+            .iter()
+            .zip(ids.iter())
             .zip(poses.iter())
             .zip(decision_margins.iter())
-            .filter_map(move |((id, pose), margin)| {
-                (*margin > threshold).then_some((*id, pose, *margin))
+            .filter_map(move |(((family, id), pose), margin)| {
+                (*margin > threshold).then_some((*family, *id, pose, *margin))
             })
     }
 }
@@ -164,13 +198,18 @@ impl<'r> ResourceBindings<'r> for Resources<'r> {
 #[derive(Reflect)]
 #[reflect(from_reflect = false)]
 pub struct AprilTags {
+    /// One detector per tag family
     #[reflect(ignore)]
-    detector: Detector,
+    families: Vec<FamilyDetector>,
     #[reflect(ignore)]
     solver: SqPnP,
     #[reflect(ignore)]
     tags: HashMap<usize, Iso3>,
     #[reflect(ignore)]
+    layouts: FieldLayouts,
+    /// The last field layout request we handled
+    layout_generation: u64,
+    #[reflect(ignore)]
     comm: Comm,
     #[reflect(ignore)]
     cam_model: GenericModel<f64>,
@@ -179,6 +218,86 @@ pub struct AprilTags {
     #[reflect(ignore)]
     robot_to_cam: Option<Iso3>,
     yaw: f64,
+    #[reflect(ignore)]
+    heading_mode: HeadingMode,
+    #[reflect(ignore)]
+    pose_solver: PoseSolver,
+    /// Whether to refine corners to sub-pixel accuracy before solving
+    refine_corners: bool,
+    /// Whether to refine poses against the corners in pixels after solving
+    refine_pose: bool,
+    /// Smooths poses over time and throws out outliers
+    #[reflect(ignore)]
+    filter: PoseFilter,
+    /// Other cameras we solve together with
+    #[reflect(ignore)]
+    fusion: Option<FusionGroup>,
+    /// Solves for each tag on its own, if we're sending every tag to the RIO
+    #[reflect(ignore)]
+    tag_solver: Option<SqPnP>,
+    /// Where we log what the solver saw, if Rerun is configured
+    #[cfg(feature = "rerun")]
+    #[reflect(ignore)]
+    viz: Option<viz::CameraViz>,
+}
+
+/// Where the robot's heading comes from when solving for its pose
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadingMode {
+    /// Only solve when there's a fresh reading from the gyro
+    #[default]
+    Gyro,
+    /// Use the gyro when there's a fresh reading, otherwise trust vision's yaw
+    ///
+    /// This is what you want for bench testing and localizing while the robot is disabled.
+    Auto,
+    /// Ignore the gyro and trust vision's yaw
+    Vision,
+}
+impl std::str::FromStr for HeadingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gyro" => Ok(Self::Gyro),
+            "auto" => Ok(Self::Auto),
+            "vision" => Ok(Self::Vision),
+            _ => Err(format!("invalid heading mode: {s}")),
+        }
+    }
+}
+
+/// How the robot's pose is solved for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoseSolver {
+    /// Solve for a full 6-DoF pose with SqPnP, then pivot it to agree with the gyro
+    #[default]
+    Full,
+    /// Only solve for x, y, and heading, taking the camera's height, roll, and pitch from
+    /// `robot_to_cam`
+    ///
+    /// This is much more stable with a single tag far away. It doesn't refine poses against pixels,
+    /// so it can't be used with `refine_pose`.
+    Planar,
+    /// Like [`Self::Planar`], but with the heading fixed from the gyro, so only x and y are solved
+    /// for
+    ///
+    /// Without a fresh gyro reading, the heading is solved for like [`Self::Planar`].
+    PlanarGyro,
+}
+impl std::str::FromStr for PoseSolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "planar" => Ok(Self::Planar),
+            "planar_gyro" => Ok(Self::PlanarGyro),
+            _ => Err(format!("invalid pose solver: {s}")),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Default, Serialize, Deserialize)]
@@ -194,28 +313,94 @@ pub struct RobotToCamOffset {
     pub z: f64,
 }
 
-fn image_from_cuimage<A>(cu_image: &CuImage<A>) -> ManuallyDrop<Image>
-where
-    A: ArrayLike<Element = u8>,
-{
-    unsafe {
-        // Try to emulate what the C code is doing on the heap to avoid double free
-        let buffer_ptr = cu_image.buffer_handle.with_inner(|inner| inner.as_ptr());
-        let low_level_img = Box::new(image_u8_t {
-            buf: buffer_ptr as *mut u8,
-            width: cu_image.format.width as i32,
-            height: cu_image.format.height as i32,
-            stride: cu_image.format.stride as i32,
-        });
-        let ptr = Box::into_raw(low_level_img);
-        ManuallyDrop::new(Image::from_raw(ptr))
+impl AprilTags {
+    /// Swap in a different field layout if one was requested over the network
+    ///
+    /// Every family switches, even ones configured with their own `field_layout`, so the layout we
+    /// report back is the one every tag is solved against.
+    fn sync_field_layout(&mut self) {
+        let Some((generation, name)) = self.comm.requested_field_layout(self.layout_generation)
+        else {
+            return;
+        };
+        self.layout_generation = generation;
+
+        match self.layouts.select(&name) {
+            Ok(tags) => {
+                self.tags = tags.clone();
+                for family in &mut self.families {
+                    family.tags = None;
+                }
+                #[cfg(feature = "rerun")]
+                viz::log_field(&self.tags);
+                // Poses from the old layout are in a different frame
+                self.filter.reset();
+                tracing::info!("switched to field layout '{name}'");
+            }
+            Err(err) => {
+                tracing::warn!("failed to switch field layout: {err}");
+            }
+        }
+
+        self.comm
+            .set_active_field_layout(generation, self.layouts.active());
     }
 }
 
+/// Solve for a single tag's pose relative to the camera
+///
+/// Returns `None` if the solver couldn't find one.
+fn observe_tag(
+    solver: &mut SqPnP,
+    family: TagFamily,
+    id: usize,
+    tag_size: f64,
+    corners: &[Vector2<f64>],
+    unprojected: &[chalkydri_sqpnp::Vec3],
+) -> Option<TagObservation> {
+    // Solving in the tag's frame gives us the camera's pose relative to the tag
+    let (rot, pos, _) = solver.solve_robot_pose(
+        &[Iso3::identity()],
+        unprojected,
+        None,
+        Some(&[tag_size]),
+        &Iso3::identity(),
+        None,
+        SIGN_FLIP_CONST,
+    )?;
+    let tag_to_cam = Iso3::from_parts(
+        nalgebra::Translation3::from(pos),
+        nalgebra::UnitQuaternion::from_rotation_matrix(&rot),
+    )
+    .inverse();
+
+    let translation = tag_to_cam.translation.vector;
+    let rotation = tag_to_cam.rotation;
+    Some(TagObservation {
+        id: id as u32,
+        family: family.number(),
+        corners: std::array::from_fn(|i| [corners[i].x as f32, corners[i].y as f32]),
+        translation: [
+            translation.x as f32,
+            translation.y as f32,
+            translation.z as f32,
+        ],
+        rotation: [
+            rotation.w as f32,
+            rotation.i as f32,
+            rotation.j as f32,
+            rotation.k as f32,
+        ],
+        ambiguity: chalkydri_sqpnp::tag_ambiguity(unprojected) as f32,
+        ..Default::default()
+    })
+}
+
 impl Freezable for AprilTags {}
 
-impl CuSinkTask for AprilTags {
+impl CuTask for AprilTags {
     type Input<'m> = input_msg!((CuImage<Vec<u8>>, CuDuration));
+    type Output<'m> = output_msg!(PoseEstimate);
     type Resources<'r> = Resources<'r>;
 
     fn new(_config: Option<&ComponentConfig>, resources: Self::Resources<'_>) -> CuResult<Self>
@@ -225,12 +410,75 @@ impl CuSinkTask for AprilTags {
         let comm = resources.comm.0.clone();
 
         if let Some(config) = _config {
-            let family_cfg: String = config.get("family").unwrap().unwrap_or(FAMILY.to_string());
-            let family: Family = family_cfg.parse().unwrap();
-            let bits_corrected: u32 = config.get("bits_corrected").unwrap().unwrap_or(3);
+            // Either a list of families, or just one
+            let families: Vec<FamilyConfig> = match config.get::<String>("families").unwrap() {
+                Some(families) => serde_json::from_str(&families)
+                    .map_err(|err| CuError::from(format!("invalid tag families: {err}")))?,
+                None => {
+                    let family: TagFamily = config
+                        .get::<String>("family")
+                        .unwrap()
+                        .map(|family| family.parse())
+                        .transpose()
+                        .map_err(CuError::from)?
+                        .unwrap_or_default();
+                    let bits_corrected: u32 = config.get("bits_corrected").unwrap().unwrap_or(3);
+                    let tag_size: f64 = config
+                        .get("tag_size")
+                        .unwrap()
+                        .unwrap_or(chalkydri_sqpnp::TAG_SIZE);
+
+                    vec![FamilyConfig {
+                        family,
+                        bits_corrected,
+                        field_layout: None,
+                        tag_size,
+                    }]
+                }
+            };
             let cam_id: u8 = config.get("cam_id").unwrap().unwrap();
             let robot_to_cam_str: String = config.get("robot_to_cam").unwrap().unwrap();
             let calib = config.get::<String>("calib").unwrap().unwrap();
+            let heading_mode: HeadingMode = config
+                .get::<String>("heading_mode")
+                .unwrap()
+                .map(|mode| mode.parse().unwrap())
+                .unwrap_or_default();
+            let pose_solver: PoseSolver = config
+                .get::<String>("pose_solver")
+                .unwrap()
+                .map(|solver| solver.parse().unwrap())
+                .unwrap_or_default();
+            let backend: DetectorBackend = config
+                .get::<String>("detector")
+                .unwrap()
+                .map(|backend| backend.parse().unwrap())
+                .unwrap_or_default();
+            let refine_corners: bool = config.get("refine_corners").unwrap().unwrap_or(false);
+            let refine_pose: bool = config.get("refine_pose").unwrap().unwrap_or(false);
+            let send_detections: bool = config.get("send_detections").unwrap().unwrap_or(false);
+            let pose_filter: PoseFilterKind = config
+                .get::<String>("pose_filter")
+                .unwrap()
+                .map(|kind| kind.parse().unwrap())
+                .unwrap_or_default();
+            let fusion = config
+                .get::<String>("fusion_group")
+                .unwrap()
+                .map(|name| FusionGroup::join(&name));
+
+            // Refinement projects through this camera's model, so it can only refine this camera's
+            // own full solves
+            if refine_pose && pose_solver != PoseSolver::Full {
+                return Err(CuError::from(
+                    "refine_pose only works with the full pose solver",
+                ));
+            }
+            if refine_pose && fusion.is_some() {
+                return Err(CuError::from(
+                    "refine_pose doesn't work with a fusion_group",
+                ));
+            }
 
             let robot_to_cam_offsets: RobotToCamOffset =
                 serde_json::from_str(&robot_to_cam_str).unwrap();
@@ -255,60 +503,140 @@ impl CuSinkTask for AprilTags {
 
             let cam_model: GenericModel<f64> = serde_json::from_str(&calib).unwrap();
 
-            let detector = DetectorBuilder::default()
-                .add_family_bits(family, bits_corrected as usize)
-                .build()
-                .unwrap();
+            // How much to trust vision, tunable without recompiling
+            let uncertainty_model: UncertaintyModel = config
+                .get::<String>("uncertainty_model")
+                .unwrap()
+                .map(|model| model.parse().unwrap())
+                .unwrap_or_default();
+            let mut solver = SqPnP::new().uncertainty_model(uncertainty_model);
+            if let Some(scalar) = config.get::<f64>("xy_std_dev_scalar").unwrap() {
+                solver = solver.xy_std_dev_scalar(scalar);
+            }
+            if let Some(scalar) = config.get::<f64>("theta_std_dev_scalar").unwrap() {
+                solver = solver.theta_std_dev_scalar(scalar);
+            }
+            if let Some(rms) = config.get::<f64>("max_trustable_rms").unwrap() {
+                solver = solver.max_trustable_rms(rms);
+            }
+            if let Some(degrees) = config.get::<f64>("max_gyro_delta").unwrap() {
+                solver = solver.max_gyro_delta(degrees);
+            }
+            if let Some(noise) = config.get::<f64>("min_image_noise").unwrap() {
+                solver = solver.min_image_noise(noise);
+            }
+            if let Some(rms) = config.get::<f64>("max_trustable_pixel_rms").unwrap() {
+                solver = solver.max_trustable_pixel_rms(rms);
+            }
 
-            let solver = SqPnP::new();
+            let layouts = FieldLayouts::load().unwrap();
+            comm.set_active_field_layout(0, layouts.active());
+            comm.register_camera(cam_id);
+
+            let families = families
+                .into_iter()
+                .map(|cfg| {
+                    let tags = cfg
+                        .field_layout
+                        .map(|name| layouts.get(&name).cloned())
+                        .transpose()
+                        .map_err(|err| CuError::from(err.to_string()))?;
+                    FamilyDetector::new(backend, cfg.family, cfg.bits_corrected, cfg.tag_size, tags)
+                })
+                .collect::<CuResult<Vec<_>>>()?;
+
+            #[cfg(feature = "rerun")]
+            viz::log_field(layouts.tags());
 
             return Ok(Self {
                 cam_id,
-                detector,
+                families,
                 solver,
-                tags: AprilTagFieldLayout::load().unwrap(),
+                tags: layouts.tags().clone(),
+                layouts,
+                layout_generation: 0,
                 comm,
                 cam_model,
                 last_time: None,
                 robot_to_cam: Some(robot_to_cam),
                 yaw: robot_to_cam_offsets.yaw,
+                heading_mode,
+                pose_solver,
+                refine_corners,
+                refine_pose,
+                filter: PoseFilter::new(pose_filter),
+                fusion,
+                tag_solver: send_detections.then(SqPnP::new),
+                #[cfg(feature = "rerun")]
+                viz: viz::CameraViz::new(cam_id, robot_to_cam),
             });
         }
+        let layouts = FieldLayouts::load().unwrap();
+        comm.set_active_field_layout(0, layouts.active());
+
         Ok(Self {
             cam_id: u8::MAX,
-            detector: DetectorBuilder::default()
-                .add_family_bits(FAMILY.parse::<Family>().unwrap(), 1)
-                .build()
-                .unwrap(),
+            families: vec![FamilyDetector::new(
+                DetectorBackend::default(),
+                TagFamily::default(),
+                1,
+                chalkydri_sqpnp::TAG_SIZE,
+                None,
+            )?],
             solver: SqPnP::new(),
-            tags: AprilTagFieldLayout::load().unwrap(),
+            tags: layouts.tags().clone(),
+            layouts,
+            layout_generation: 0,
             comm,
             cam_model: GenericModel::OpenCVModel5(OpenCVModel5::zeros()),
             last_time: None,
             robot_to_cam: None,
             yaw: 0.0,
+            heading_mode: HeadingMode::default(),
+            pose_solver: PoseSolver::default(),
+            refine_corners: false,
+            refine_pose: false,
+            filter: PoseFilter::new(PoseFilterKind::None),
+            fusion: None,
+            tag_solver: None,
+            #[cfg(feature = "rerun")]
+            viz: None,
         })
     }
 
-    fn process<'i>(&mut self, clock: &RobotClock, input: &Self::Input<'i>) -> CuResult<()> {
+    fn process(
+        &mut self,
+        clock: &RobotClock,
+        input: &Self::Input<'_>,
+        output: &mut Self::Output<'_>,
+    ) -> CuResult<()> {
+        output.clear_payload();
+        self.sync_field_layout();
+
         let Tov::Time(time) = input.tov() else {
             return Ok(());
         };
         if let Some(payload) = input.payload() {
             use chalkydri_sqpnp::Vec3;
 
-            let image = image_from_cuimage(&payload.0);
-            let detections = self.detector.detect(&image);
-            if detections.len() > 0 {
-                let mut camera_pts: Vec<Vec3> = Vec::new();
-                let mut world_pts: Vec<Iso3> = Vec::new();
+            self.comm.report_frame(self.cam_id);
+
+            let mut camera_pts: Vec<Vec3> = Vec::new();
+            let mut pixel_pts: Vec<Vector2<f64>> = Vec::new();
+            let mut world_pts: Vec<Iso3> = Vec::new();
+            let mut weights: Vec<f64> = Vec::new();
+            let mut sizes: Vec<f64> = Vec::new();
+            let mut observations: Vec<TagObservation> = Vec::new();
+            for family in self.families.iter_mut() {
+                let mut detections = family.detector.detect(&payload.0);
+                if self.refine_corners {
+                    refine::refine_detections(&payload.0, &mut detections);
+                }
+                // Each family looks its IDs up in its own layout, so they can't collide
+                let tags = family.tags.as_ref().unwrap_or(&self.tags);
                 'det_proc: for detection in detections.iter() {
-                    let Some(tag) = self.tags.get(&detection.id()) else {
-                        continue 'det_proc;
-                    };
-
                     let corners = detection
-                        .corners()
+                        .corners
                         .into_iter()
                         .map(|corner| Vector2::new(corner[0], corner[1]))
                         .collect::<Vec<_>>();
@@ -321,22 +649,172 @@ impl CuSinkTask for AprilTags {
                         .collect::<Vec<_>>();
 
                     // Only use it if the corners could be unprojected
-                    if unprojected.len() == 4 {
-                        world_pts.push(tag.clone());
-                        camera_pts.extend_from_slice(unprojected.as_slice()); //I didn't check, make sure these are normalized
+                    if unprojected.len() != 4 {
+                        continue 'det_proc;
                     }
+
+                    // Tags that aren't on the field can still be useful to the robot
+                    if let Some(tag_solver) = &mut self.tag_solver
+                        && observations.len() < MAX_DETECTIONS
+                        && let Some(observation) = observe_tag(
+                            tag_solver,
+                            detection.family,
+                            detection.id,
+                            family.tag_size,
+                            &corners,
+                            &unprojected,
+                        )
+                    {
+                        observations.push(observation);
+                    }
+
+                    let Some(tag) = tags.get(&detection.id) else {
+                        continue 'det_proc;
+                    };
+
+                    world_pts.push(tag.clone());
+                    weights.push(detection.weight());
+                    sizes.push(family.tag_size);
+                    camera_pts.extend_from_slice(unprojected.as_slice()); //I didn't check, make sure these are normalized
+                    pixel_pts.extend_from_slice(corners.as_slice());
                 }
+            }
 
-                if let Some(gyro_angle) = self.comm.gyro_angle() {
-                    if let Some((cam_to_world_rotation, cam_to_world_translation, std_dev)) =
-                        self.solver.solve_robot_pose(
+            if self.tag_solver.is_some() && !observations.is_empty() {
+                self.comm.publish_detections(
+                    self.cam_id,
+                    clock.now().as_micros() - time.as_micros(),
+                    &observations,
+                );
+            }
+
+            // Use the heading from when the frame was captured, not from when we got around to
+            // solving it
+            let latency = Duration::from_micros(clock.now().as_micros() - time.as_micros());
+
+            #[cfg(feature = "rerun")]
+            if let Some(viz) = &mut self.viz {
+                let format = &payload.0.format;
+                viz.log_frame(
+                    std::time::SystemTime::now() - latency,
+                    &self.cam_model,
+                    (format.width, format.height),
+                    &pixel_pts,
+                );
+            }
+
+            let robot_to_cam = self.robot_to_cam.unwrap_or_else(|| Default::default());
+
+            // Only tags in the layout count, the others can't be solved with
+            let mut tag_count = world_pts.len();
+
+            let own_tag_count = tag_count.try_into().unwrap_or(u8::MAX);
+
+            // Only one camera in a fusion group solves, with everyone's tags
+            let fused = match &self.fusion {
+                Some(fusion) => {
+                    let observation = CameraObservation {
+                        tags: world_pts.clone(),
+                        bearings: camera_pts.clone(),
+                        weights: weights.clone(),
+                        tag_sizes: sizes.clone(),
+                        robot_to_cam,
+                    };
+                    fusion
+                        .exchange(self.cam_id, time.as_micros(), &observation)
+                        .map(|mut observations| {
+                            if !observations.is_empty() {
+                                tracing::debug!("fusing with {} cameras", observations.len());
+                                tag_count +=
+                                    observations.iter().map(|o| o.tags.len()).sum::<usize>();
+                            }
+                            observations.push(observation);
+                            observations
+                        })
+                }
+                None => Some(Vec::new()),
+            };
+
+            if let Some(fused) = fused
+                && tag_count > 0
+            {
+                let gyro_angle = match self.heading_mode {
+                    HeadingMode::Vision => None,
+                    HeadingMode::Gyro | HeadingMode::Auto => self.comm.gyro_angle_at(latency),
+                };
+
+                if gyro_angle.is_some() || self.heading_mode != HeadingMode::Gyro {
+                    let planar = self.pose_solver != PoseSolver::Full;
+                    let fix_heading = self.pose_solver == PoseSolver::PlanarGyro;
+
+                    // Whether other cameras saw tags too
+                    let multi = fused.len() > 1;
+
+                    let solution = match (multi, planar) {
+                        (true, true) => self.solver.solve_robot_pose_planar_multi(
+                            &fused,
+                            gyro_angle,
+                            fix_heading,
+                        ),
+                        (true, false) => {
+                            self.solver
+                                .solve_robot_pose_multi(&fused, gyro_angle, SIGN_FLIP_CONST)
+                        }
+                        (false, true) => self.solver.solve_robot_pose_planar(
+                            &world_pts,
+                            &camera_pts,
+                            Some(&weights),
+                            Some(&sizes),
+                            &robot_to_cam,
+                            gyro_angle,
+                            fix_heading,
+                        ),
+                        (false, false) if self.refine_pose => {
+                            let cam_model = &self.cam_model;
+                            let project = |p: &Vec3| cam_model.project(&[*p]).pop().flatten();
+
+                            self.solver
+                                .solve_robot_pose_refined(
+                                    &world_pts,
+                                    &camera_pts,
+                                    Some(&weights),
+                                    Some(&sizes),
+                                    &Reprojection {
+                                        pixels: &pixel_pts,
+                                        project: &project,
+                                    },
+                                    &robot_to_cam,
+                                    gyro_angle,
+                                    SIGN_FLIP_CONST,
+                                )
+                                .map(|(rot, pos, std_dev, rms)| {
+                                    tracing::debug!("reprojection rms: {rms:.2} px");
+                                    (rot, pos, std_dev)
+                                })
+                        }
+                        (false, false) => self.solver.solve_robot_pose(
                             &world_pts,
                             &camera_pts,
-                            &self.robot_to_cam.unwrap_or_else(|| Default::default()),
+                            Some(&weights),
+                            Some(&sizes),
+                            &robot_to_cam,
                             gyro_angle,
                             SIGN_FLIP_CONST,
-                        )
+                        ),
+                    };
+
+                    if let Some((cam_to_world_rotation, cam_to_world_translation, std_dev)) =
+                        solution
                     {
+                        #[cfg(feature = "rerun")]
+                        if let Some(viz) = &self.viz {
+                            viz.log_pose(
+                                &cam_to_world_rotation,
+                                &cam_to_world_translation,
+                                self.solver.rotation_candidates(),
+                            );
+                        }
+
                         let pose = RobotPose {
                             x: cam_to_world_translation[0],
                             y: cam_to_world_translation[1],
@@ -348,19 +826,43 @@ impl CuSinkTask for AprilTags {
                             rot: std_dev[2],
                         };
 
-                        let ts = clock.now().as_micros() - time.as_micros();
-                        self.comm.publish(
-                            self.cam_id,
-                            detections.len().try_into().unwrap_or(u8::MAX),
-                            ts,
-                            pose.clone(),
-                            uncertainty.clone(),
-                        );
+                        // Log the raw pose, so the filter can be tuned against it later
                         tracing::debug!("detected pose: {pose:?}");
-                        return Ok(());
+
+                        // Outliers fall through to the keepalive below
+                        if let Some((pose, uncertainty)) =
+                            self.filter.update(time.as_micros(), &pose, &uncertainty)
+                        {
+                            let ts = clock.now().as_micros() - time.as_micros();
+                            self.comm.publish(
+                                self.cam_id,
+                                tag_count.try_into().unwrap_or(u8::MAX),
+                                ts,
+                                pose.clone(),
+                                uncertainty.clone(),
+                            );
+                            tracing::debug!("filtered pose: {pose:?}");
+
+                            output.tov = input.tov;
+                            output.set_payload(PoseEstimate {
+                                cam_id: self.cam_id,
+                                own_tag_count,
+                                tag_count: tag_count.try_into().unwrap_or(u8::MAX),
+                                pose: Some(pose),
+                            });
+                            return Ok(());
+                        }
                     }
                 }
             }
+
+            output.tov = input.tov;
+            output.set_payload(PoseEstimate {
+                cam_id: self.cam_id,
+                own_tag_count,
+                tag_count: tag_count.try_into().unwrap_or(u8::MAX),
+                pose: None,
+            });
         }
         let timey_time = clock.now().as_millis();
         let ts = clock.now().as_micros() - time.as_micros();
@@ -0,0 +1,284 @@
+//!
+//! Sub-pixel corner refinement
+//!
+//! Detectors give us corners that are only good to about a pixel, and a pixel is a lot at long
+//! range. Each edge of a tag covers far more pixels than its corners do, so we find the edge along
+//! its whole length, fit a line to it, and intersect neighboring lines to get the corners.
+//!
+
+use std::ops::Deref;
+
+use chalkydri_core::tracing;
+use cu_sensor_payloads::CuImage;
+use cu29::prelude::*;
+
+use crate::detector::TagDetection;
+
+/// How far to either side of a detected edge we look for the real one (px)
+const SEARCH_RADIUS: f64 = 2.0;
+/// Step size when searching for an edge (px)
+const SEARCH_STEP: f64 = 0.25;
+/// Fraction of each edge, measured from either corner, that we don't sample
+///
+/// The two edges meeting at a corner blur into each other near it.
+const CORNER_TRIM: f64 = 0.1;
+/// Edges shorter than this aren't worth refining (px)
+const MIN_EDGE_LEN: f64 = 8.0;
+/// Weakest edge we'll trust, as a difference in brightness across a pixel (out of 255)
+const MIN_GRADIENT: f64 = 8.0;
+
+/// A borrowed 8-bit grayscale frame
+pub struct GrayView<'a> {
+    buf: &'a [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+impl<'a> GrayView<'a> {
+    pub fn new(buf: &'a [u8], width: usize, height: usize, stride: usize) -> Self {
+        Self {
+            buf,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    /// Sample the frame at a point, interpolating between the pixels around it
+    fn sample(&self, x: f64, y: f64) -> Option<f64> {
+        if !(0.0..=(self.width - 1) as f64).contains(&x)
+            || !(0.0..=(self.height - 1) as f64).contains(&y)
+        {
+            return None;
+        }
+
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+        let px = |x: usize, y: usize| self.buf.get(y * self.stride + x).copied().map(f64::from);
+
+        let top = px(x0, y0)? * (1.0 - fx) + px(x1, y0)? * fx;
+        let bottom = px(x0, y1)? * (1.0 - fx) + px(x1, y1)? * fx;
+
+        Some(top * (1.0 - fy) + bottom * fy)
+    }
+}
+
+/// Refine a tag's corners
+///
+/// Returns the refined corners and the largest correction applied to any of them (px). If an edge
+/// can't be found, the corners are returned untouched with a correction of 0.
+pub fn refine_corners(image: &GrayView, corners: &[[f64; 2]; 4]) -> ([[f64; 2]; 4], f64) {
+    let mut lines = [None; 4];
+    for (i, line) in lines.iter_mut().enumerate() {
+        *line = fit_edge(image, corners[i], corners[(i + 1) % 4]);
+    }
+
+    let mut refined = *corners;
+    let mut correction: f64 = 0.0;
+    for (i, corner) in refined.iter_mut().enumerate() {
+        let (Some(before), Some(after)) = (&lines[(i + 3) % 4], &lines[i]) else {
+            return (*corners, 0.0);
+        };
+        let Some(new) = before.intersect(after) else {
+            return (*corners, 0.0);
+        };
+
+        let moved = (new[0] - corner[0]).hypot(new[1] - corner[1]);
+        // The edges we found can't be the ones we were looking for
+        if moved > 2.0 * SEARCH_RADIUS {
+            return (*corners, 0.0);
+        }
+
+        correction = correction.max(moved);
+        *corner = new;
+    }
+
+    (refined, correction)
+}
+
+/// Refine the corners of every detection in a frame
+pub(crate) fn refine_detections(image: &CuImage<Vec<u8>>, detections: &mut [TagDetection]) {
+    let format = &image.format;
+
+    image.buffer_handle.with_inner(|inner| {
+        let view = GrayView::new(
+            inner.deref(),
+            format.width as usize,
+            format.height as usize,
+            format.stride as usize,
+        );
+
+        for detection in detections.iter_mut() {
+            let (corners, correction) = refine_corners(&view, &detection.corners);
+            detection.corners = corners;
+            detection.corner_correction = correction;
+
+            tracing::debug!(
+                "refined {} {} corners by {correction:.2} px",
+                detection.family,
+                detection.id
+            );
+        }
+    });
+}
+
+/// Find the edge between two corners and fit a line to it
+fn fit_edge(image: &GrayView, a: [f64; 2], b: [f64; 2]) -> Option<Line> {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let len = dx.hypot(dy);
+    if len < MIN_EDGE_LEN {
+        return None;
+    }
+    let (nx, ny) = (-dy / len, dx / len);
+
+    // Brightness change across the edge at an offset along the normal
+    let gradient = |x: f64, y: f64, k: f64| {
+        let ahead = image.sample(x + (k + 0.5) * nx, y + (k + 0.5) * ny)?;
+        let behind = image.sample(x + (k - 0.5) * nx, y + (k - 0.5) * ny)?;
+        Some(ahead - behind)
+    };
+
+    let samples = (len / 2.0).clamp(4.0, 32.0) as usize;
+    let sample_points = (0..samples).map(|s| {
+        let t = CORNER_TRIM + (1.0 - 2.0 * CORNER_TRIM) * (s as f64 + 0.5) / samples as f64;
+        (a[0] + t * dx, a[1] + t * dy)
+    });
+
+    // Which way brightness changes across the edge, so we don't latch onto the inner edge of the
+    // border on small tags
+    let polarity: f64 = sample_points
+        .clone()
+        .filter_map(|(x, y)| gradient(x, y, 0.0))
+        .sum::<f64>()
+        .signum();
+
+    let steps = (SEARCH_RADIUS / SEARCH_STEP) as i32;
+    let mut points = Vec::with_capacity(samples);
+    for (x, y) in sample_points {
+        // Look for the strongest change in brightness along the normal
+        let mut best: Option<(i32, f64)> = None;
+        for step in -steps..=steps {
+            let Some(g) = gradient(x, y, step as f64 * SEARCH_STEP) else {
+                continue;
+            };
+            let g = g * polarity;
+            if best.is_none_or(|(_, best)| g > best) {
+                best = Some((step, g));
+            }
+        }
+        let Some((step, g)) = best else {
+            continue;
+        };
+        if g < MIN_GRADIENT || step.abs() == steps {
+            continue;
+        }
+
+        // Fit a parabola through the peak and its neighbors to land between steps
+        let k = step as f64 * SEARCH_STEP;
+        let offset = match (
+            gradient(x, y, k - SEARCH_STEP),
+            gradient(x, y, k + SEARCH_STEP),
+        ) {
+            (Some(before), Some(after)) => {
+                let (before, after) = (before * polarity, after * polarity);
+                let curvature = before - 2.0 * g + after;
+                if curvature < 0.0 {
+                    0.5 * (before - after) / curvature * SEARCH_STEP
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        };
+
+        points.push(([x + (k + offset) * nx, y + (k + offset) * ny], g));
+    }
+
+    Line::fit(&points)
+}
+
+/// A line through a point
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    x: f64,
+    y: f64,
+    dx: f64,
+    dy: f64,
+}
+impl Line {
+    /// Fit a line to some weighted points, minimizing the distance perpendicular to it
+    fn fit(points: &[([f64; 2], f64)]) -> Option<Self> {
+        if points.len() < 3 {
+            return None;
+        }
+
+        let total: f64 = points.iter().map(|(_, w)| w).sum();
+        let x = points.iter().map(|(p, w)| p[0] * w).sum::<f64>() / total;
+        let y = points.iter().map(|(p, w)| p[1] * w).sum::<f64>() / total;
+
+        let (mut cxx, mut cyy, mut cxy) = (0.0, 0.0, 0.0);
+        for ([px, py], w) in points {
+            cxx += w * (px - x) * (px - x);
+            cyy += w * (py - y) * (py - y);
+            cxy += w * (px - x) * (py - y);
+        }
+
+        let theta = 0.5 * (2.0 * cxy).atan2(cxx - cyy);
+        let (dy, dx) = theta.sin_cos();
+
+        Some(Self { x, y, dx, dy })
+    }
+
+    /// Find where two lines cross
+    fn intersect(&self, other: &Self) -> Option<[f64; 2]> {
+        let det = self.dx * other.dy - self.dy * other.dx;
+        if det.abs() < 1e-6 {
+            return None;
+        }
+
+        let t = ((other.x - self.x) * other.dy - (other.y - self.y) * other.dx) / det;
+
+        Some([self.x + t * self.dx, self.y + t * self.dy])
+    }
+}
+
+#[test]
+fn refine_synthetic_corners() {
+    let (width, height) = (64, 64);
+    let corners = [[20.3, 44.6], [45.7, 41.2], [42.4, 17.9], [17.1, 21.4]];
+
+    // Shade each pixel by how much of it the tag covers
+    let inside = |x: f64, y: f64| {
+        (0..4).all(|i| {
+            let ([ax, ay], [bx, by]) = (corners[i], corners[(i + 1) % 4]);
+            (bx - ax) * (y - ay) - (by - ay) * (x - ax) <= 0.0
+        })
+    };
+    let n = 8;
+    let buf = (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as f64, (i / width) as f64);
+            let covered = (0..n * n)
+                .filter(|s| {
+                    let dx = ((s % n) as f64 + 0.5) / n as f64 - 0.5;
+                    let dy = ((s / n) as f64 + 0.5) / n as f64 - 0.5;
+                    inside(x + dx, y + dy)
+                })
+                .count() as f64
+                / (n * n) as f64;
+            (220.0 - 190.0 * covered).round() as u8
+        })
+        .collect::<Vec<_>>();
+    let image = GrayView::new(&buf, width, height, width);
+
+    // What a detector might give us, most of a pixel off
+    let detected = [[20.9, 44.0], [45.1, 41.9], [43.0, 18.4], [16.6, 20.8]];
+    let (refined, correction) = refine_corners(&image, &detected);
+
+    for (corner, expected) in refined.iter().zip(corners) {
+        let err = (corner[0] - expected[0]).hypot(corner[1] - expected[1]);
+        assert!(err < 0.2, "{corner:?} != {expected:?}");
+    }
+    assert!((0.5..1.5).contains(&correction), "{correction}");
+}
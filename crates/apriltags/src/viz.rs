@@ -0,0 +1,256 @@
+//!
+//! Rerun visualization
+//!
+//! With `rerun` set in the Chalkydri config, every AprilTag task streams the field's tags, its
+//! camera's frustum, the corners it detected, and the poses it solved for, so we can see what the
+//! solver saw. Recordings are served on `server_address`, saved to the `.rrd` file at
+//! `recording_path` to go over later, or both.
+//!
+//! This is only built with the `rerun` feature, so robots that don't need it don't pull Rerun in.
+//!
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use camera_intrinsic_model::GenericModel;
+use chalkydri_core::prelude::*;
+use chalkydri_sqpnp::{Iso3, Rot3, Vec3, tag_corners};
+use nalgebra::Vector2;
+use rerun::{
+    AsComponents, MemoryLimit, PlaybackBehavior, RecordingStream, RecordingStreamBuilder,
+    ServerOptions,
+};
+
+/// Where rotation candidates' arrows start from, above the robot so they don't hide in it (m)
+const CANDIDATE_HEIGHT: f64 = 0.5;
+/// Half of the size of the box we draw the robot as (m)
+const ROBOT_HALF_SIZE: [f32; 3] = [0.4, 0.4, 0.1];
+/// How deep to draw camera frustums (m)
+const FRUSTUM_DEPTH: f32 = 0.25;
+
+/// Every stream we're logging to, or `None` if Rerun isn't configured
+static STREAMS: LazyLock<Option<Streams>> = LazyLock::new(Streams::from_config);
+
+struct Streams(Vec<RecordingStream>);
+impl Streams {
+    fn from_config() -> Option<Self> {
+        let config = Cfg.read().rerun.clone()?;
+
+        // Both streams are the same recording as far as the viewer is concerned
+        let recording_id = format!(
+            "chalkydri-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        );
+        let builder = || RecordingStreamBuilder::new("chalkydri").recording_id(&recording_id);
+
+        let mut streams = Vec::new();
+        if let Some(addr) = config.server_address {
+            match addr.parse::<SocketAddr>() {
+                Ok(addr) => match builder().serve_grpc_opts(
+                    addr.ip().to_string(),
+                    addr.port(),
+                    ServerOptions {
+                        playback_behavior: PlaybackBehavior::NewestFirst,
+                        memory_limit: MemoryLimit::from_fraction_of_total(0.25),
+                    },
+                ) {
+                    Ok(stream) => streams.push(stream),
+                    Err(err) => warn!("failed to serve rerun on {addr}: {err}"),
+                },
+                Err(err) => warn!("invalid rerun server address '{addr}': {err}"),
+            }
+        }
+        if let Some(path) = config.recording_path {
+            match builder().save(&path) {
+                Ok(stream) => streams.push(stream),
+                Err(err) => warn!("failed to save rerun recording to {path}: {err}"),
+            }
+        }
+
+        if streams.is_empty() {
+            return None;
+        }
+
+        let streams = Self(streams);
+        streams.log_static("/", &rerun::ViewCoordinates::RIGHT_HAND_Z_UP());
+
+        Some(streams)
+    }
+
+    // A viewer going away isn't worth stopping the pipeline for, so errors are ignored
+
+    fn log(&self, path: &str, data: &impl AsComponents) {
+        for stream in &self.0 {
+            stream.log(path, data).ok();
+        }
+    }
+
+    fn log_static(&self, path: &str, data: &impl AsComponents) {
+        for stream in &self.0 {
+            stream.log_static(path, data).ok();
+        }
+    }
+
+    fn set_time(&self, time: SystemTime) {
+        for stream in &self.0 {
+            stream.set_time("capture_time", time);
+        }
+    }
+}
+
+fn translation(v: &Vec3) -> [f32; 3] {
+    [v.x as f32, v.y as f32, v.z as f32]
+}
+
+fn transform(iso: &Iso3) -> rerun::Transform3D {
+    let q = iso.rotation.coords;
+
+    rerun::Transform3D::from_translation_rotation(
+        translation(&iso.translation.vector),
+        rerun::Quaternion::from_xyzw([q.x as f32, q.y as f32, q.z as f32, q.w as f32]),
+    )
+}
+
+/// Log the tags in a field layout
+///
+/// Does nothing if Rerun isn't configured.
+pub(crate) fn log_field(tags: &HashMap<usize, Iso3>) {
+    let Some(streams) = STREAMS.as_ref() else {
+        return;
+    };
+
+    let (outlines, labels): (Vec<_>, Vec<_>) = tags
+        .iter()
+        .map(|(id, tag)| {
+            let corners = tag_corners(tag);
+            let outline: Vec<[f32; 3]> = corners
+                .iter()
+                .chain(&corners[..1])
+                .map(translation)
+                .collect();
+            (outline, id.to_string())
+        })
+        .unzip();
+
+    streams.log_static(
+        "/field/tags",
+        &rerun::LineStrips3D::new(outlines).with_labels(labels),
+    );
+}
+
+/// Everything one AprilTag task logs
+pub(crate) struct CameraViz {
+    /// Entity path for this camera's poses, with its frustum and detections under it
+    path: String,
+    /// Entity path for the rotation candidates, which are already in the field's frame
+    candidates_path: String,
+    robot_to_cam: Iso3,
+    /// Whether the camera's frustum has been logged yet
+    frustum: bool,
+}
+impl CameraViz {
+    /// Set up logging for a camera, or `None` if Rerun isn't configured
+    pub fn new(cam_id: u8, robot_to_cam: Iso3) -> Option<Self> {
+        STREAMS.as_ref()?;
+
+        Some(Self {
+            path: format!("/poses/cam_{cam_id}"),
+            candidates_path: format!("/candidates/cam_{cam_id}"),
+            robot_to_cam,
+            frustum: false,
+        })
+    }
+
+    /// Log the corners used from a frame, four per tag, and the camera's frustum if it hasn't been
+    /// logged yet
+    pub fn log_frame(
+        &mut self,
+        time: SystemTime,
+        cam_model: &GenericModel<f64>,
+        (width, height): (u32, u32),
+        corners: &[Vector2<f64>],
+    ) {
+        let Some(streams) = STREAMS.as_ref() else {
+            return;
+        };
+        streams.set_time(time);
+
+        if !self.frustum {
+            self.frustum = true;
+
+            streams.log_static(
+                &format!("{}/camera", self.path),
+                &transform(&self.robot_to_cam.inverse()),
+            );
+
+            // The camera model isn't a pinhole, but its field of view is close enough to draw
+            let (w, h) = (width as f64, height as f64);
+            let edges =
+                cam_model.unproject(&[Vector2::new(w / 2.0, 0.0), Vector2::new(w / 2.0, h)]);
+            if let [Some(top), Some(bottom)] = edges[..] {
+                let fov_y = top.angle(&bottom);
+                streams.log_static(
+                    &format!("{}/camera/image", self.path),
+                    &rerun::Pinhole::from_fov(fov_y as f32, (w / h) as f32)
+                        .with_resolution([width as f32, height as f32])
+                        .with_image_plane_distance(FRUSTUM_DEPTH),
+                );
+            }
+        }
+
+        let outlines = corners.chunks_exact(4).map(|tag| {
+            tag.iter()
+                .chain(&tag[..1])
+                .map(|c| [c.x as f32, c.y as f32])
+                .collect::<Vec<_>>()
+        });
+        streams.log(
+            &format!("{}/camera/image/tags", self.path),
+            &rerun::LineStrips2D::new(outlines),
+        );
+    }
+
+    /// Log a solved robot pose, and the rotation candidates the solver chose between
+    ///
+    /// Candidates are world-to-robot rotations, and are drawn as arrows pointing where the robot
+    /// would be facing with each of them.
+    pub fn log_pose(
+        &self,
+        robot_rot: &Rot3,
+        robot_pos: &Vec3,
+        candidates: impl Iterator<Item = (Rot3, f64)>,
+    ) {
+        let Some(streams) = STREAMS.as_ref() else {
+            return;
+        };
+
+        let pose = Iso3::from_parts((*robot_pos).into(), (*robot_rot).into());
+        streams.log(&self.path, &transform(&pose));
+        streams.log(
+            &format!("{}/robot", self.path),
+            &rerun::Boxes3D::from_half_sizes([ROBOT_HALF_SIZE]),
+        );
+
+        let (arrows, labels): (Vec<_>, Vec<_>) = candidates
+            .map(|(world_to_robot, energy)| {
+                let robot_rot = world_to_robot.inverse();
+                (
+                    translation(&(robot_rot * Vec3::x())),
+                    format!("{energy:.3e}"),
+                )
+            })
+            .unzip();
+        let origin = translation(&(robot_pos + Vec3::z() * CANDIDATE_HEIGHT));
+        streams.log(
+            &self.candidates_path,
+            &rerun::Arrows3D::from_vectors(arrows.clone())
+                .with_origins(vec![origin; arrows.len()])
+                .with_labels(labels),
+        );
+    }
+}
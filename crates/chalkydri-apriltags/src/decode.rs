@@ -0,0 +1,173 @@
+//!
+//! Tag decoding
+//!
+//! This is pretty much the same thing the C library does. The quad's homography tells us where each
+//! cell of the tag landed in the image. We sample the border and the ring around it to figure out
+//! what black and white look like, then sample each data bit and look the code up in the family.
+//!
+
+use std::sync::Arc;
+
+use crate::family::TagFamily;
+use crate::homography::Homography;
+use crate::quad::Quad;
+
+/// Corners of a quad in tag coordinates, in the same order as [`Quad::corners`]
+const QUAD_CORNERS: [[f64; 2]; 4] = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+/// Smallest difference between black and white we'll try to decode (out of 255)
+const MIN_CONTRAST: f64 = 10.0;
+
+/// A decoded tag
+///
+/// This has the same shape as the `apriltag` crate's detections, so the two can be swapped.
+#[derive(Debug, Clone)]
+pub struct Detection {
+    family: Arc<TagFamily>,
+    id: usize,
+    hamming: usize,
+    decision_margin: f32,
+    homography: Homography,
+    center: [f64; 2],
+    corners: [[f64; 2]; 4],
+}
+impl Detection {
+    /// The family the tag belongs to
+    pub fn family(&self) -> &TagFamily {
+        &self.family
+    }
+
+    /// The tag's ID
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// How many bits had to be corrected
+    pub fn hamming(&self) -> usize {
+        self.hamming
+    }
+
+    /// Average difference between the data bits and the black/white threshold
+    ///
+    /// Higher is better. Junk that happens to decode usually has a low margin.
+    pub fn decision_margin(&self) -> f32 {
+        self.decision_margin
+    }
+
+    /// The homography from tag coordinates to pixel coordinates
+    pub fn homography(&self) -> &Homography {
+        &self.homography
+    }
+
+    /// The tag's center in pixel coordinates
+    pub fn center(&self) -> [f64; 2] {
+        self.center
+    }
+
+    /// The tag's corners in pixel coordinates
+    ///
+    /// Like the C library, these wrap counter-clockwise around the tag, starting from its bottom
+    /// left corner.
+    pub fn corners(&self) -> [[f64; 2]; 4] {
+        self.corners
+    }
+}
+
+/// Try to decode a quad as a tag from a family
+pub(crate) fn decode_quad(
+    gray: &[u8],
+    width: usize,
+    height: usize,
+    quad: &Quad,
+    family: &Arc<TagFamily>,
+    max_hamming: u32,
+) -> Option<Detection> {
+    if quad.reversed_border != family.reversed_border {
+        return None;
+    }
+
+    let h = Homography::from_correspondences(&QUAD_CORNERS, &quad.corners)?;
+    let cells = family.width_at_border as i32;
+    // Center of a cell in tag coordinates
+    let cell = |i: i32| 2.0 * ((i as f64 + 0.5) / cells as f64 - 0.5);
+    let sample_cell = |x: i32, y: i32| {
+        let [px, py] = h.project(cell(x), cell(y));
+        sample(gray, width, height, px, py)
+    };
+
+    // The border just inside the quad and the ring just outside it are solid, so they tell us
+    // what black and white look like
+    let (mut inside, mut outside) = (0.0, 0.0);
+    for i in 0..cells {
+        for (x, y) in [(i, 0), (i, cells - 1), (0, i), (cells - 1, i)] {
+            inside += sample_cell(x, y)?;
+        }
+        for (x, y) in [(i, -1), (i, cells), (-1, i), (cells, i)] {
+            outside += sample_cell(x, y)?;
+        }
+    }
+    let (inside, outside) = (inside / (4 * cells) as f64, outside / (4 * cells) as f64);
+    let (black, white) = if family.reversed_border {
+        (outside, inside)
+    } else {
+        (inside, outside)
+    };
+    if white - black < MIN_CONTRAST {
+        return None;
+    }
+    let threshold = (black + white) / 2.0;
+
+    let mut code = 0u64;
+    let (mut white_score, mut white_count) = (0.0, 0);
+    let (mut black_score, mut black_count) = (0.0, 0);
+    for (&x, &y) in family.bit_x.iter().zip(family.bit_y.iter()) {
+        let v = sample_cell(x, y)? - threshold;
+
+        code <<= 1;
+        if v > 0.0 {
+            code |= 1;
+            white_score += v;
+            white_count += 1;
+        } else {
+            black_score -= v;
+            black_count += 1;
+        }
+    }
+    let decision_margin = f64::min(
+        white_score / white_count.max(1) as f64,
+        black_score / black_count.max(1) as f64,
+    );
+
+    let (id, hamming, rotation) = family.decode(code, max_hamming)?;
+
+    // Line the homography up with the tag's real orientation
+    let homography = h.rotated(rotation);
+    let corners =
+        [[-1.0, 1.0], [1.0, 1.0], [1.0, -1.0], [-1.0, -1.0]].map(|[x, y]| homography.project(x, y));
+
+    Some(Detection {
+        family: family.clone(),
+        id,
+        hamming: hamming as usize,
+        decision_margin: decision_margin as f32,
+        homography,
+        center: homography.project(0.0, 0.0),
+        corners,
+    })
+}
+
+/// Sample a grayscale image at a point, interpolating between the pixels around it
+fn sample(gray: &[u8], width: usize, height: usize, x: f64, y: f64) -> Option<f64> {
+    if !(0.0..=(width - 1) as f64).contains(&x) || !(0.0..=(height - 1) as f64).contains(&y) {
+        return None;
+    }
+
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let px = |x: usize, y: usize| gray[y * width + x] as f64;
+
+    let top = px(x0, y0) * (1.0 - fx) + px(x1, y0) * fx;
+    let bottom = px(x0, y1) * (1.0 - fx) + px(x1, y1) * fx;
+
+    Some(top * (1.0 - fy) + bottom * fy)
+}
@@ -0,0 +1,226 @@
+//!
+//! AprilTag families
+//!
+//! A family is a codebook plus a description of where each bit lives on the tag. Rather than copying
+//! the reference library's tables by hand, we read its family sources (`tag36h11.c` and friends)
+//! directly. They're plain lists of assignments, so this doesn't pull in any C.
+//!
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// An AprilTag family
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagFamily {
+    /// Name of the family (`tag36h11`)
+    pub name: String,
+    /// Minimum hamming distance between any two codes
+    pub h: u32,
+    /// Number of data bits
+    pub nbits: u32,
+    /// Width of the tag in cells, up to and including the outer edge of its border
+    pub width_at_border: u32,
+    /// Width of the tag in cells, including the white ring around it
+    pub total_width: u32,
+    /// Whether the border is white on black, instead of black on white
+    pub reversed_border: bool,
+    /// Cell column of each bit, from the most significant bit down
+    ///
+    /// This can be negative or past the border for families with bits outside of it.
+    pub bit_x: Vec<i32>,
+    /// Cell row of each bit, from the most significant bit down
+    pub bit_y: Vec<i32>,
+    /// The codebook, indexed by tag ID
+    pub codes: Vec<u64>,
+}
+impl TagFamily {
+    /// Load a family from one of the reference library's family sources
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let src = fs::read_to_string(path)?;
+
+        Self::from_c_source(&src).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Parse a family from one of the reference library's family sources
+    ///
+    /// We only look at the `tf->field = value;` and `tf->field[i] = value;` statements, so anything
+    /// else in the file is ignored.
+    pub fn from_c_source(src: &str) -> Result<Self, String> {
+        let mut name = None;
+        let mut h = None;
+        let mut nbits = None;
+        let mut width_at_border = None;
+        let mut total_width = None;
+        let mut reversed_border = false;
+        let mut bit_x = Vec::new();
+        let mut bit_y = Vec::new();
+        let mut codes = Vec::new();
+
+        for statement in src.split(';') {
+            // Skip past the start of the function
+            let statement = statement
+                .rsplit(['{', '}'])
+                .next()
+                .unwrap_or_default()
+                .trim();
+            let Some(assignment) = statement.strip_prefix("tf->") else {
+                continue;
+            };
+            let Some((lhs, rhs)) = assignment.split_once('=') else {
+                continue;
+            };
+            let lhs = lhs.trim();
+            let rhs = rhs.trim();
+
+            // Array elements
+            if let Some((field, index)) = lhs.split_once('[') {
+                let index: usize = index
+                    .trim_end_matches(']')
+                    .parse()
+                    .map_err(|_| format!("invalid index in `{statement}`"))?;
+                let value =
+                    parse_int(rhs).ok_or_else(|| format!("invalid value in `{statement}`"))?;
+
+                let array = match field {
+                    "codes" => &mut codes,
+                    "bit_x" => &mut bit_x,
+                    "bit_y" => &mut bit_y,
+                    _ => continue,
+                };
+                if array.len() <= index {
+                    array.resize(index + 1, None);
+                }
+                array[index] = Some(value);
+
+                continue;
+            }
+
+            match lhs {
+                "name" => {
+                    name = rhs.split('"').nth(1).map(str::to_owned);
+                }
+                "reversed_border" => {
+                    reversed_border = rhs == "true" || rhs == "1";
+                }
+                "h" | "nbits" | "width_at_border" | "total_width" => {
+                    let value = parse_int(rhs)
+                        .and_then(|value| u32::try_from(value).ok())
+                        .ok_or_else(|| format!("invalid value in `{statement}`"))?;
+
+                    match lhs {
+                        "h" => h = Some(value),
+                        "nbits" => nbits = Some(value),
+                        "width_at_border" => width_at_border = Some(value),
+                        _ => total_width = Some(value),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let name = name.ok_or("missing name")?;
+        let nbits = nbits.ok_or("missing nbits")?;
+        let width_at_border = width_at_border.ok_or("missing width_at_border")?;
+        if nbits == 0 || nbits > 64 {
+            return Err(format!("unsupported number of bits: {nbits}"));
+        }
+        if width_at_border < 3 {
+            return Err(format!("invalid width_at_border: {width_at_border}"));
+        }
+
+        let complete = |array: Vec<Option<i128>>, field: &str| {
+            if array.len() != nbits as usize {
+                return Err(format!("expected {nbits} entries in {field}"));
+            }
+            array
+                .into_iter()
+                .map(|value| value.ok_or_else(|| format!("{field} has gaps")))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let bit_x = complete(bit_x, "bit_x")?;
+        let bit_y = complete(bit_y, "bit_y")?;
+        let codes = codes
+            .into_iter()
+            .map(|code| code.ok_or("codes has gaps").map(|code| code as u64))
+            .collect::<Result<Vec<_>, _>>()?;
+        if codes.is_empty() {
+            return Err("missing codes".to_owned());
+        }
+
+        Ok(Self {
+            name,
+            h: h.ok_or("missing h")?,
+            nbits,
+            width_at_border,
+            total_width: total_width.unwrap_or(width_at_border + 2),
+            reversed_border,
+            bit_x: bit_x.into_iter().map(|x| x as i32).collect(),
+            bit_y: bit_y.into_iter().map(|y| y as i32).collect(),
+            codes,
+        })
+    }
+
+    /// Rotate a code by 90 degrees
+    ///
+    /// The bits are laid out in four identical quadrants, so this is just a rotation of the bits.
+    /// Odd families have a center bit, which stays put.
+    pub fn rotate90(&self, code: u64) -> u64 {
+        let nbits = self.nbits;
+        let (p, l) = if nbits % 4 == 1 {
+            (nbits - 1, 1)
+        } else {
+            (nbits, 0)
+        };
+
+        let rotated =
+            ((code >> l) << (p / 4 + l)) | ((code >> (3 * p / 4 + l)) << l) | (code & l as u64);
+
+        rotated & self.mask()
+    }
+
+    /// Find the closest code to a sampled one
+    ///
+    /// Returns the tag ID, how many bits had to be corrected, and how many times the sampled code
+    /// was rotated to match.
+    pub fn decode(&self, code: u64, max_hamming: u32) -> Option<(usize, u32, u32)> {
+        let mut best: Option<(usize, u32, u32)> = None;
+
+        let mut rotated = code & self.mask();
+        for rotation in 0..4 {
+            for (id, &candidate) in self.codes.iter().enumerate() {
+                let hamming = (candidate ^ rotated).count_ones();
+                if hamming <= max_hamming && best.is_none_or(|(_, best, _)| hamming < best) {
+                    best = Some((id, hamming, rotation));
+                }
+            }
+            rotated = self.rotate90(rotated);
+        }
+
+        best
+    }
+
+    fn mask(&self) -> u64 {
+        if self.nbits == 64 {
+            u64::MAX
+        } else {
+            (1 << self.nbits) - 1
+        }
+    }
+}
+
+/// Parse a C integer literal, like `-2`, `11` or `0x0000000d7e00984bUL`
+fn parse_int(s: &str) -> Option<i128> {
+    let s = s.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+
+    Some(if negative { -value } else { value })
+}
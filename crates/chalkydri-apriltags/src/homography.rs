@@ -0,0 +1,62 @@
+//!
+//! Homographies
+//!
+//! A homography maps points on one plane to points on another. We use them to map tag coordinates
+//! (-1 to 1 on both axes, covering the tag up to the outer edge of its border) into the image.
+//!
+
+use nalgebra::{Matrix3, SMatrix, SVector, Vector3};
+
+/// A 3x3 homography
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography(pub Matrix3<f64>);
+impl Homography {
+    /// Solve for the homography that maps four points onto four others
+    ///
+    /// This is the direct linear transform with the bottom right entry fixed to 1, which is fine
+    /// for anything that isn't viewed exactly edge-on.
+    pub fn from_correspondences(from: &[[f64; 2]; 4], to: &[[f64; 2]; 4]) -> Option<Self> {
+        let mut a = SMatrix::<f64, 8, 8>::zeros();
+        let mut b = SVector::<f64, 8>::zeros();
+
+        for (i, (&[x, y], &[u, v])) in from.iter().zip(to.iter()).enumerate() {
+            let row = i * 2;
+            a.row_mut(row)
+                .copy_from_slice(&[x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u]);
+            a.row_mut(row + 1)
+                .copy_from_slice(&[0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v]);
+            b[row] = u;
+            b[row + 1] = v;
+        }
+
+        let h = a.lu().solve(&b)?;
+
+        Some(Self(Matrix3::new(
+            h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0,
+        )))
+    }
+
+    /// Map a point through the homography
+    #[inline(always)]
+    pub fn project(&self, x: f64, y: f64) -> [f64; 2] {
+        let p = self.0 * Vector3::new(x, y, 1.0);
+
+        [p.x / p.z, p.y / p.z]
+    }
+
+    /// Rotate the source plane by a number of quarter turns
+    ///
+    /// This is how a quad's homography gets lined up with the tag's real orientation once it has
+    /// been decoded.
+    pub fn rotated(&self, quarter_turns: u32) -> Self {
+        let (s, c) = match quarter_turns % 4 {
+            0 => (0.0, 1.0),
+            1 => (1.0, 0.0),
+            2 => (0.0, -1.0),
+            _ => (-1.0, 0.0),
+        };
+        let r = Matrix3::new(c, -s, 0.0, s, c, 0.0, 0.0, 0.0, 1.0);
+
+        Self(self.0 * r)
+    }
+}
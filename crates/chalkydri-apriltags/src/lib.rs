@@ -1,42 +1,39 @@
-#![feature(
-    portable_simd,
-    alloc_layout_extra,
-    slice_as_chunks,
-    sync_unsafe_cell,
-    array_chunks
-)]
+#![feature(portable_simd, alloc_layout_extra, sync_unsafe_cell)]
 #![warn(clippy::infinite_loop)]
 
-#[macro_use]
-extern crate statrs;
 #[cfg(feature = "multi-thread")]
 extern crate rayon;
 
-//mod decode;
+mod decode;
+mod family;
+mod homography;
 // mod pose_estimation;
+mod quad;
 pub mod utils;
 
-use libblur::{BlurImage, BlurImageMut};
-use nalgebra::Vector;
-use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 // use pose_estimation::pose_estimation;
-use ril::{Line, Rgb};
-use statrs::statistics::{Data, Distribution, Max, Median, Min, OrderStatistics};
+use statrs::statistics::{Data, Max, Median, Min, OrderStatistics};
 // TODO: ideally we'd use alloc here and only pull in libstd for sync::atomic when the multi-thread feature is enabled
 use std::{
     alloc::{alloc, alloc_zeroed, dealloc, Layout},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        Arc,
     },
-    time::Instant,
 };
 
 #[cfg(feature = "multi-thread")]
 use rayon::iter::{ParallelBridge, ParallelIterator};
 
+use image::{Rgb, RgbImage};
+
 use crate::utils::*;
 
+pub use crate::decode::Detection;
+pub use crate::family::TagFamily;
+pub use crate::homography::Homography;
+pub use crate::quad::Quad;
+
 /// Union-Find data structure for connected components
 #[derive(Debug, Clone)]
 pub struct UnionFind {
@@ -112,17 +109,6 @@ impl Drop for UnionFind {
     }
 }
 
-#[derive(Clone, Default, Debug)]
-struct ClusterHash {
-    hash: u32,
-    id: u64,
-    data: Vec<(usize, usize)>,
-}
-
-fn u64hash_2(x: u64) -> u32 {
-    ((x >> 32) ^ x) as u32
-}
-
 /// Raw buffers used by a [`detector`](Detector)
 ///
 /// We need a separate struct for this so the compiler will treat them as thread-safe.
@@ -143,9 +129,13 @@ pub struct Detector {
     /// Raw buffers used by the detector
     bufs: DetectorBufs,
     valid_tags: &'static [usize],
+    /// Tag families to decode and how many bits to correct for each
+    families: Vec<(Arc<TagFamily>, u32)>,
     points_len: AtomicUsize,
     /// Checked edges (x1, y1, x2, y2)
     lines: Vec<(usize, usize, usize, usize)>,
+    /// Quads found in the last frame
+    quads: Vec<Quad>,
     /// Width of input frames
     width: usize,
     /// Height of input frames
@@ -172,14 +162,24 @@ impl Detector {
             Self {
                 bufs: DetectorBufs { buf, points },
                 valid_tags,
+                families: Vec::new(),
                 points_len,
                 lines: Vec::new(),
+                quads: Vec::new(),
                 width,
                 height,
             }
         }
     }
 
+    /// Add a tag family to decode
+    ///
+    /// `bits_corrected` is the most bits we'll flip to make a code match. Going above 3 for
+    /// `tag36h11` will let a lot of junk through.
+    pub fn add_family(&mut self, family: TagFamily, bits_corrected: u32) {
+        self.families.push((Arc::new(family), bits_corrected));
+    }
+
     /// Calculate otsu value
     ///
     /// [Otsu's method](https://en.wikipedia.org/wiki/Otsu%27s_method) is an adaptive thresholding
@@ -197,7 +197,6 @@ impl Detector {
                 let mut pixels = Vec::new();
 
                 const BLOCK_SIZE: usize = 5;
-                const C: u8 = 4;
 
                 unsafe {
                     let x_min = x.saturating_sub(BLOCK_SIZE.saturating_div(2));
@@ -244,14 +243,12 @@ impl Detector {
                             *self.bufs.buf.add(i) = Color::Other;
                         }
                         //*self.bufs.buf.add(i) = *self.bufs.buf.add(px(x - 1, y - 1, self.width));
+                    } else if p >= data.upper_quartile() as u8 {
+                        *self.bufs.buf.add(i) = Color::White;
+                    } else if p <= data.lower_quartile() as u8 {
+                        *self.bufs.buf.add(i) = Color::Black;
                     } else {
-                        if p >= data.upper_quartile() as u8 {
-                            *self.bufs.buf.add(i) = Color::White;
-                        } else if p <= data.lower_quartile() as u8 {
-                            *self.bufs.buf.add(i) = Color::Black;
-                        } else {
-                            *self.bufs.buf.add(i) = Color::Other;
-                        }
+                        *self.bufs.buf.add(i) = Color::Other;
                     }
                 }
             }
@@ -260,20 +257,112 @@ impl Detector {
 
     /// Process an RGB frame
     ///
-    /// FAST needs a 3x3 circle around each pixel, so we only process pixels within a 3x3 pixel
-    /// padding.
-    pub fn process_frame(&mut self, input: &[u8]) {
+    /// See [Self::detect].
+    pub fn process_frame(&mut self, input: &[u8]) -> Vec<Detection> {
         // Check that the input is RGB
         assert_eq!(input.len(), self.width * self.height * 3);
 
-        let mut copy = input.to_vec();
-        //let adaptive_thresh = Instant::now();
-        self.calc_otsu(&mut copy);
-        //dbg!(adaptive_thresh.elapsed());
+        let gray: Vec<u8> = input.chunks_exact(3).map(grayscale).collect();
+
+        self.detect(&gray)
+    }
+
+    /// Find and decode tags in a grayscale frame
+    pub fn detect(&mut self, gray: &[u8]) -> Vec<Detection> {
+        // Check that the input is grayscale
+        assert_eq!(gray.len(), self.width * self.height);
+
+        self.threshold(gray);
+
+        let mut uf = self.connected_components();
+        let buf = unsafe { core::slice::from_raw_parts(self.bufs.buf, self.width * self.height) };
+
+        self.quads = quad::boundary_clusters(buf, self.width, self.height, &mut uf)
+            .iter()
+            .filter_map(|cluster| quad::fit_quad(cluster))
+            .collect();
 
-        //unsafe {
-        //    self.thresh(input);
-        //}
+        let mut detections = Vec::new();
+        for quad in &self.quads {
+            let detection = self.families.iter().find_map(|(family, bits_corrected)| {
+                decode::decode_quad(gray, self.width, self.height, quad, family, *bits_corrected)
+            });
+
+            if let Some(detection) = detection {
+                if self.valid_tags.is_empty() || self.valid_tags.contains(&detection.id()) {
+                    detections.push(detection);
+                }
+            }
+        }
+
+        detections
+    }
+
+    /// Threshold a grayscale frame
+    ///
+    /// This is the C library's approach: the frame is split into 4x4 tiles, and each pixel is
+    /// compared against the midpoint of the darkest and lightest pixels in the tiles around it.
+    /// Pixels in areas without enough contrast are left as [Color::Other], which keeps big flat
+    /// areas from turning into noise.
+    pub fn threshold(&mut self, gray: &[u8]) {
+        const TILE_SIZE: usize = 4;
+        const MIN_CONTRAST: u8 = 5;
+
+        let (width, height) = (self.width, self.height);
+        let (tiles_x, tiles_y) = (width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE));
+
+        // Find the extremes of each tile
+        let mut tile_min = vec![u8::MAX; tiles_x * tiles_y];
+        let mut tile_max = vec![u8::MIN; tiles_x * tiles_y];
+        for y in 0..height {
+            for x in 0..width {
+                let p = gray[y * width + x];
+                let t = (y / TILE_SIZE) * tiles_x + x / TILE_SIZE;
+                tile_min[t] = tile_min[t].min(p);
+                tile_max[t] = tile_max[t].max(p);
+            }
+        }
+
+        // Spread them to neighboring tiles, so edges right on a tile boundary aren't missed
+        let mut min = vec![u8::MAX; tiles_x * tiles_y];
+        let mut max = vec![u8::MIN; tiles_x * tiles_y];
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                let t = ty * tiles_x + tx;
+                for ny in ty.saturating_sub(1)..=(ty + 1).min(tiles_y - 1) {
+                    for nx in tx.saturating_sub(1)..=(tx + 1).min(tiles_x - 1) {
+                        min[t] = min[t].min(tile_min[ny * tiles_x + nx]);
+                        max[t] = max[t].max(tile_max[ny * tiles_x + nx]);
+                    }
+                }
+            }
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let t = (y / TILE_SIZE) * tiles_x + x / TILE_SIZE;
+                let (lo, hi) = (min[t], max[t]);
+
+                let color = if hi - lo < MIN_CONTRAST {
+                    Color::Other
+                } else if gray[i] > lo + (hi - lo) / 2 {
+                    Color::White
+                } else {
+                    Color::Black
+                };
+                unsafe {
+                    *self.bufs.buf.add(i) = color;
+                }
+            }
+        }
+    }
+
+    /// Run corner detection and edge checking on the thresholded frame
+    ///
+    /// FAST needs a 3x3 circle around each pixel, so we only process pixels within a 3x3 pixel
+    /// padding.
+    pub fn find_edges(&mut self) {
         // Reset points_len to 0
         self.points_len.store(0, Ordering::SeqCst);
         // Clear the lines Vec
@@ -282,8 +371,6 @@ impl Detector {
         self.detect_corners();
 
         self.check_edges();
-
-        //pose_estimation(intrinsics);
     }
 
     /// Run corner detection
@@ -381,13 +468,13 @@ impl Detector {
                     && (p3.is_black() ^ p7.is_black() ^ p11.is_black() ^ p15.is_black())
                 {
                     // Furthest top center
-                    let p1 = *buf.add(px(x, y - 3, width));
+                    let _p1 = *buf.add(px(x, y - 3, width));
                     // Furthest middle right
-                    let p5 = *buf.add(px(x + 3, y, width));
+                    let _p5 = *buf.add(px(x + 3, y, width));
                     // Furthest bottom center
-                    let p9 = *buf.add(px(x, y + 3, width));
+                    let _p9 = *buf.add(px(x, y + 3, width));
                     // Furthest middle left
-                    let p13 = *buf.add(px(x - 3, y, width));
+                    let _p13 = *buf.add(px(x - 3, y, width));
 
                     // Add p to the corner buffer
                     *self
@@ -463,14 +550,13 @@ impl Detector {
             // check if the midways are black pixels,
             // and if the pixels to the right and left of these midways are black pixels as well.
 
-            if mw1top.is_good() && mw2top.is_good() && mw1bottom.is_good() && mw2bottom.is_good() {
-                if (mw1top.is_black() ^ mw2bottom.is_black())
-                    && (mw2top.is_black() ^ mw1bottom.is_black())
-                    && (mw1top == mw2top)
-                {
-                    // midway one has black pixels on both sides
-                    self.lines.push((x1, y1, x2, y2));
-                }
+            if (mw1top.is_good() && mw2top.is_good() && mw1bottom.is_good() && mw2bottom.is_good())
+                && (mw1top.is_black() ^ mw2bottom.is_black())
+                && (mw2top.is_black() ^ mw1bottom.is_black())
+                && (mw1top == mw2top)
+            {
+                // midway one has black pixels on both sides
+                self.lines.push((x1, y1, x2, y2));
             }
         }
     }
@@ -612,57 +698,63 @@ impl Detector {
     //    .collect()
     //}
 
+    /// Draw the thresholded frame, checked edges, and fitted quads to `lines.png`
     pub fn draw(&self) {
-        let mut img = ril::Image::new(self.width as u32, self.height as u32, Rgb::black());
+        let mut img = RgbImage::new(self.width as u32, self.height as u32);
         let mut conn_comp = self.connected_components();
         for x in 0..self.width {
             for y in 0..self.height {
-                img.set_pixel(
+                img.put_pixel(
                     x as u32,
                     y as u32,
                     match unsafe { *self.bufs.buf.add(px(x, y, self.width)) } {
-                        Color::Black => Rgb::black(),
-                        Color::White => Rgb::white(),
-                        Color::Other => Rgb::from_hex("777777").unwrap(),
+                        Color::Black => Rgb([0, 0, 0]),
+                        Color::White => Rgb([255, 255, 255]),
+                        Color::Other => Rgb([0x77, 0x77, 0x77]),
                     },
                 );
             }
         }
+
+        let mut draw_line = |(x1, y1): (f64, f64), (x2, y2): (f64, f64), color: Rgb<u8>| {
+            let steps = (x2 - x1).abs().max((y2 - y1).abs()).ceil().max(1.0) as usize;
+            for step in 0..=steps {
+                let t = step as f64 / steps as f64;
+                let (x, y) = (x1 + (x2 - x1) * t, y1 + (y2 - y1) * t);
+                if let Some(pixel) = img.get_pixel_mut_checked(x.round() as u32, y.round() as u32) {
+                    *pixel = color;
+                }
+            }
+        };
+
         for (x1, y1, x2, y2) in self.lines.clone() {
-            //img.draw(
-            //    &ril::draw::Ellipse::circle(x1 as u32, y1 as u32, 2)
-            //        .with_fill(Rgb::from_hex("ffa500").unwrap()),
-            //);
-            //img.draw(
-            //    &ril::draw::Ellipse::circle(x2 as u32, y2 as u32, 2)
-            //        .with_fill(Rgb::from_hex("ff0000").unwrap()),
-            //);
-            //img.draw(&Line::new(
-            //    (x1 as u32, y1 as u32),
-            //    (x2 as u32, y2 as u32),
-            //    Rgb::from_hex("ff0000").unwrap(),
-            //));
             if conn_comp.find(unsafe { px(x1, y1, self.width) })
                 == conn_comp.find(unsafe { px(x2, y2, self.width) })
             {
-                img.draw(&Line::new(
-                    (x1 as u32, y1 as u32),
-                    (x2 as u32, y2 as u32),
-                    Rgb::from_hex("00ff00").unwrap(),
-                ));
+                draw_line(
+                    (x1 as f64, y1 as f64),
+                    (x2 as f64, y2 as f64),
+                    Rgb([0, 255, 0]),
+                );
             }
-            //            let (parent_x, parent_y) = (parent_id % self.width, parent_id / self.width);
-            //            img.draw(
-            //                &ril::draw::Ellipse::circle(parent_x as u32, parent_y as u32, 2)
-            //                    .with_fill(Rgb::from_hex("0000ff").unwrap()),
-            //            );
         }
-        img.save(ril::ImageFormat::Png, "lines.png").unwrap();
+        for quad in &self.quads {
+            for i in 0..4 {
+                let [x1, y1] = quad.corners[i];
+                let [x2, y2] = quad.corners[(i + 1) % 4];
+                draw_line((x1, y1), (x2, y2), Rgb([255, 0, 0]));
+            }
+        }
+
+        img.save("lines.png").unwrap();
     }
 }
 impl Clone for Detector {
     fn clone(&self) -> Self {
-        Self::new(self.width, self.height, &[])
+        let mut det = Self::new(self.width, self.height, self.valid_tags);
+        det.families = self.families.clone();
+
+        det
     }
 }
 impl Drop for Detector {
@@ -679,3 +771,136 @@ impl Drop for Detector {
         }
     }
 }
+
+/// A small 16-bit family, written the same way as the reference library's family sources
+#[cfg(test)]
+const TEST_FAMILY: &str = r#"
+apriltag_family_t *tagTest16_create()
+{
+   apriltag_family_t *tf = calloc(1, sizeof(apriltag_family_t));
+   tf->name = strdup("tagTest16");
+   tf->h = 5;
+   tf->ncodes = 3;
+   tf->codes = calloc(3, sizeof(uint64_t));
+   tf->codes[0] = 0x00000000000027c8UL;
+   tf->codes[1] = 0x00000000000031b6UL;
+   tf->codes[2] = 0x0000000000003859UL;
+   tf->nbits = 16;
+   tf->bit_x = calloc(16, sizeof(uint32_t));
+   tf->bit_y = calloc(16, sizeof(uint32_t));
+   tf->bit_x[0] = 1; tf->bit_y[0] = 1;
+   tf->bit_x[1] = 2; tf->bit_y[1] = 1;
+   tf->bit_x[2] = 3; tf->bit_y[2] = 1;
+   tf->bit_x[3] = 2; tf->bit_y[3] = 2;
+   tf->bit_x[4] = 4; tf->bit_y[4] = 1;
+   tf->bit_x[5] = 4; tf->bit_y[5] = 2;
+   tf->bit_x[6] = 4; tf->bit_y[6] = 3;
+   tf->bit_x[7] = 3; tf->bit_y[7] = 2;
+   tf->bit_x[8] = 4; tf->bit_y[8] = 4;
+   tf->bit_x[9] = 3; tf->bit_y[9] = 4;
+   tf->bit_x[10] = 2; tf->bit_y[10] = 4;
+   tf->bit_x[11] = 3; tf->bit_y[11] = 3;
+   tf->bit_x[12] = 1; tf->bit_y[12] = 4;
+   tf->bit_x[13] = 1; tf->bit_y[13] = 3;
+   tf->bit_x[14] = 1; tf->bit_y[14] = 2;
+   tf->bit_x[15] = 2; tf->bit_y[15] = 3;
+   tf->width_at_border = 6;
+   tf->total_width = 8;
+   tf->reversed_border = false;
+   return tf;
+}
+"#;
+
+/// Render a tag into a grayscale frame, placed by mapping tag coordinates into the frame
+#[cfg(test)]
+fn render_tag(
+    family: &TagFamily,
+    id: usize,
+    (width, height): (usize, usize),
+    to_frame: impl Fn(f64, f64) -> [f64; 2],
+    from_frame: impl Fn(f64, f64) -> [f64; 2],
+) -> (Vec<u8>, [[f64; 2]; 4]) {
+    let cells = family.width_at_border as i32;
+    let code = family.codes[id];
+
+    let mut gray = vec![220u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let [tx, ty] = from_frame(x as f64, y as f64);
+            let cx = ((tx + 1.0) / 2.0 * cells as f64).floor() as i32;
+            let cy = ((ty + 1.0) / 2.0 * cells as f64).floor() as i32;
+            if !(0..cells).contains(&cx) || !(0..cells).contains(&cy) {
+                continue;
+            }
+
+            let bit = (0..family.nbits as usize)
+                .find(|&i| family.bit_x[i] == cx && family.bit_y[i] == cy)
+                .map(|i| (code >> (family.nbits as usize - 1 - i)) & 1 == 1);
+            gray[y * width + x] = if bit == Some(true) { 220 } else { 30 };
+        }
+    }
+
+    // Bottom left, bottom right, top right, top left, as the tag sees them
+    let corners = [[-1.0, 1.0], [1.0, 1.0], [1.0, -1.0], [-1.0, -1.0]].map(|[x, y]| to_frame(x, y));
+
+    (gray, corners)
+}
+
+#[test]
+fn detect_rotated_tags() {
+    let family = TagFamily::from_c_source(TEST_FAMILY).unwrap();
+    assert_eq!(family.name, "tagTest16");
+    assert_eq!(family.codes.len(), 3);
+
+    let (width, height) = (240, 200);
+    let mut det = Detector::new(width, height, &[]);
+    det.add_family(family.clone(), 0);
+
+    for (id, degrees) in [(0, 0.0), (1, 20.0), (2, 100.0), (0, 200.0), (1, 290.0)] {
+        let (s, c) = f64::to_radians(degrees).sin_cos();
+        let scale = 50.0;
+        let (ox, oy) = (117.3, 96.8);
+
+        let (gray, expected) = render_tag(
+            &family,
+            id,
+            (width, height),
+            |x, y| [ox + scale * (c * x - s * y), oy + scale * (s * x + c * y)],
+            |x, y| {
+                let (x, y) = ((x - ox) / scale, (y - oy) / scale);
+                [c * x + s * y, -s * x + c * y]
+            },
+        );
+
+        let detections = det.detect(&gray);
+        assert_eq!(detections.len(), 1, "rotated {degrees}°");
+        let detection = &detections[0];
+        assert_eq!(detection.id(), id);
+        assert_eq!(detection.hamming(), 0);
+        assert!(detection.decision_margin() > 50.0);
+
+        for (corner, expected) in detection.corners().iter().zip(expected.iter()) {
+            let err = (corner[0] - expected[0]).hypot(corner[1] - expected[1]);
+            assert!(err < 1.0, "rotated {degrees}°: {corner:?} != {expected:?}");
+        }
+    }
+}
+
+#[test]
+fn correct_flipped_bits() {
+    let family = TagFamily::from_c_source(TEST_FAMILY).unwrap();
+
+    for id in 0..family.codes.len() {
+        let code = family.codes[id];
+        for rotation in 0..4 {
+            let mut rotated = code ^ 0b100;
+            for _ in 0..rotation {
+                rotated = family.rotate90(rotated);
+            }
+
+            let (decoded, hamming, _) = family.decode(rotated, 1).unwrap();
+            assert_eq!((decoded, hamming), (id, 1));
+        }
+        assert_eq!(family.decode(code ^ 0b111, 2), None);
+    }
+}
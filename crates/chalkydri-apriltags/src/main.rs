@@ -1,53 +1,42 @@
 use std::time::{Duration, Instant};
 
-use image::EncodableLayout;
+use chalkydri_apriltags::TagFamily;
 
+/// Usage: `chalkydri-apriltags [image] [family source]`
+///
+/// The family source is one of the reference library's family files, like `tag36h11.c`.
 fn main() {
-    //chalkydri_apriltags::simd::sobel_shi_tomasi(1, 1, 74, 89);
-    let mut img = image::open("test.png").unwrap();
-    //img = img.blur(0.1);
-    let mut imgg = img.to_rgb8();
-    let mut img = imgg.to_vec();
-    let img = img.as_mut_slice();
+    let mut args = std::env::args().skip(1);
+    let image_path = args.next().unwrap_or_else(|| "test.png".to_owned());
+    let family_path = args.next().unwrap_or_else(|| "tag36h11.c".to_owned());
+
+    let img = image::open(image_path).unwrap().to_luma8();
+    let family = TagFamily::load(family_path).unwrap();
 
     let st = Instant::now();
     let mut det =
-        chalkydri_apriltags::Detector::new(imgg.width() as usize, imgg.height() as usize, &[]);
+        chalkydri_apriltags::Detector::new(img.width() as usize, img.height() as usize, &[]);
+    det.add_family(family, 3);
     println!("{:?}", st.elapsed());
 
     let mut total = Duration::ZERO;
-    for _ in 0..1_000 {
+    let mut detections = Vec::new();
+    for _ in 0..100 {
         let st = Instant::now();
-        det.process_frame(img);
-        det.draw();
-
-        //let conn_comp = Instant::now();
-        det.connected_components();
-        //dbg!(conn_comp.elapsed());
-        break;
+        detections = det.detect(img.as_raw());
         total += st.elapsed();
     }
-    println!("{:?}", total / 1_000);
-
-    drop(det);
-
-    //chalkydri_apriltags::myalgo::myalgo(img);
-    //chalkydri_apriltags::otsu::otsu(img);
-    /*
-    let mut ins = wgpu::Instance::new(wgpu::InstanceDescriptor {
-        ..Default::default()
-    });
-
-    let adap = futures::executor::block_on(ins.request_adapter(&wgpu::RequestAdapterOptions {
-        power_preference: wgpu::PowerPreference::HighPerformance,
-        ..Default::default()
-    }))
-    .unwrap();
-
-    if let Ok((dev, queue)) =
-        futures::executor::block_on(adap.request_device(&DeviceDescriptor::default(), None))
-    {
-        chalkydri_apriltags::det(dev, queue);
+    println!("{:?}", total / 100);
+
+    for detection in detections {
+        println!(
+            "{} {} (margin {:.1}): {:?}",
+            detection.family().name,
+            detection.id(),
+            detection.decision_margin(),
+            detection.corners()
+        );
     }
-    */
+
+    det.draw();
 }
@@ -0,0 +1,265 @@
+//!
+//! Quad fitting
+//!
+//! Every boundary between a black and a white component in the thresholded image is a potential
+//! tag border. We collect the points along each boundary, find four rough corners, fit a line to
+//! each edge, and intersect neighboring edges to get the final corners.
+//!
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use crate::utils::Color;
+use crate::UnionFind;
+
+/// Smallest component that can be on either side of a tag's border (px)
+const MIN_COMPONENT_SIZE: usize = 25;
+/// Fewest boundary points we'll try to fit a quad to
+const MIN_CLUSTER_POINTS: usize = 24;
+/// Fraction of each edge, measured from either corner, left out of line fitting
+///
+/// Corners get rounded off by blur and thresholding, so points near them pull the lines off.
+const CORNER_TRIM: f64 = 0.15;
+/// Largest mean squared error allowed when fitting a line to an edge (px²)
+const MAX_LINE_FIT_MSE: f64 = 10.0;
+/// Shortest edge we'll accept (px)
+const MIN_EDGE_LEN: f64 = 6.0;
+/// Corners sharper than this or flatter than 180° minus this are rejected
+const CRITICAL_ANGLE: f64 = 10.0 * PI / 180.0;
+
+/// A point on the boundary between a black and a white component
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BoundaryPoint {
+    x: f64,
+    y: f64,
+    /// Direction from black to white
+    gx: f64,
+    gy: f64,
+}
+
+/// A quadrilateral that might be a tag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quad {
+    /// Corners in pixel coordinates, going clockwise
+    pub corners: [[f64; 2]; 4],
+    /// Whether the quad is lighter inside than outside
+    pub reversed_border: bool,
+}
+
+/// Collect the points along every boundary between a black and a white component
+///
+/// Points are halfway between neighboring pixels, so they land on the actual edge.
+pub(crate) fn boundary_clusters(
+    buf: &[Color],
+    width: usize,
+    height: usize,
+    uf: &mut UnionFind,
+) -> Vec<Vec<BoundaryPoint>> {
+    let mut clusters: HashMap<(usize, usize), Vec<BoundaryPoint>> = HashMap::new();
+
+    for y in 0..height - 1 {
+        for x in 1..width - 1 {
+            let i = y * width + x;
+            let p = buf[i];
+            if !p.is_good() {
+                continue;
+            }
+
+            let rep0 = uf.find(i);
+            if uf.get_size(rep0) < MIN_COMPONENT_SIZE {
+                continue;
+            }
+
+            // Right, down, down-left, and down-right, so each pair of pixels is only visited once
+            for (dx, dy) in [(1, 0), (0, 1), (-1, 1), (1, 1)] {
+                let j = (y as isize + dy) as usize * width + (x as isize + dx) as usize;
+                let q = buf[j];
+                if !q.is_good() || q == p {
+                    continue;
+                }
+
+                let rep1 = uf.find(j);
+                if uf.get_size(rep1) < MIN_COMPONENT_SIZE {
+                    continue;
+                }
+
+                let sign = if q.is_white() { 1.0 } else { -1.0 };
+                clusters
+                    .entry((rep0.min(rep1), rep0.max(rep1)))
+                    .or_default()
+                    .push(BoundaryPoint {
+                        x: x as f64 + dx as f64 / 2.0,
+                        y: y as f64 + dy as f64 / 2.0,
+                        gx: dx as f64 * sign,
+                        gy: dy as f64 * sign,
+                    });
+            }
+        }
+    }
+
+    // Nothing bigger than a few laps around the frame can be a tag
+    let max_points = 8 * (width + height);
+    clusters
+        .into_values()
+        .filter(|cluster| (MIN_CLUSTER_POINTS..=max_points).contains(&cluster.len()))
+        .collect()
+}
+
+/// Try to fit a quad to a boundary
+pub(crate) fn fit_quad(points: &[BoundaryPoint]) -> Option<Quad> {
+    let n = points.len() as f64;
+    let cx = points.iter().map(|p| p.x).sum::<f64>() / n;
+    let cy = points.iter().map(|p| p.y).sum::<f64>() / n;
+
+    // If the gradient points inward, the inside is the white side
+    let outward: f64 = points
+        .iter()
+        .map(|p| (p.x - cx) * p.gx + (p.y - cy) * p.gy)
+        .sum();
+    let reversed_border = outward < 0.0;
+
+    // Rough corners: the point farthest from the center, the point farthest from that, and the
+    // points farthest from the line between them on either side
+    let dist2 = |p: &BoundaryPoint, x: f64, y: f64| (p.x - x).powi(2) + (p.y - y).powi(2);
+    let farthest = |x: f64, y: f64| {
+        points
+            .iter()
+            .max_by(|a, b| dist2(a, x, y).total_cmp(&dist2(b, x, y)))
+            .unwrap()
+    };
+    let a = farthest(cx, cy);
+    let c = farthest(a.x, a.y);
+    let side = |p: &BoundaryPoint| (c.x - a.x) * (p.y - a.y) - (c.y - a.y) * (p.x - a.x);
+    let b = points.iter().max_by(|p, q| side(p).total_cmp(&side(q)))?;
+    let d = points.iter().min_by(|p, q| side(p).total_cmp(&side(q)))?;
+
+    let diagonal = dist2(a, c.x, c.y).sqrt();
+    if side(b) / diagonal < MIN_EDGE_LEN / 2.0 || -side(d) / diagonal < MIN_EDGE_LEN / 2.0 {
+        return None;
+    }
+
+    let mut rough = [[a.x, a.y], [b.x, b.y], [c.x, c.y], [d.x, d.y]];
+    // Increasing angle is clockwise, because y points down
+    rough.sort_by(|p, q| {
+        (p[1] - cy)
+            .atan2(p[0] - cx)
+            .total_cmp(&(q[1] - cy).atan2(q[0] - cx))
+    });
+
+    // Fit a line to each edge
+    let mut lines = [Line::default(); 4];
+    for (i, line) in lines.iter_mut().enumerate() {
+        let start = rough[i];
+        let end = rough[(i + 1) % 4];
+        let (ex, ey) = (end[0] - start[0], end[1] - start[1]);
+        let len2 = ex * ex + ey * ey;
+
+        let edge_points = points.iter().filter(|p| {
+            let t = ((p.x - start[0]) * ex + (p.y - start[1]) * ey) / len2;
+            if !(CORNER_TRIM..=1.0 - CORNER_TRIM).contains(&t) {
+                return false;
+            }
+
+            // Only take points that are closer to this edge than the others
+            let dist = ((p.x - start[0]) * ey - (p.y - start[1]) * ex).abs() / len2.sqrt();
+            dist < 0.1 * len2.sqrt() + 2.0
+        });
+
+        *line = Line::fit(edge_points)?;
+        if line.mse > MAX_LINE_FIT_MSE {
+            return None;
+        }
+    }
+
+    // Each corner is where the edges on either side of it meet
+    let mut corners = [[0.0; 2]; 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        *corner = lines[(i + 3) % 4].intersect(&lines[i])?;
+    }
+
+    for i in 0..4 {
+        let prev = corners[(i + 3) % 4];
+        let curr = corners[i];
+        let next = corners[(i + 1) % 4];
+        let (ux, uy) = (prev[0] - curr[0], prev[1] - curr[1]);
+        let (vx, vy) = (next[0] - curr[0], next[1] - curr[1]);
+        let (ulen, vlen) = (ux.hypot(uy), vx.hypot(vy));
+
+        if vlen < MIN_EDGE_LEN {
+            return None;
+        }
+        // Must be convex and going clockwise
+        if vx * uy - vy * ux <= 0.0 {
+            return None;
+        }
+        if ((ux * vx + uy * vy) / (ulen * vlen)).abs() > CRITICAL_ANGLE.cos() {
+            return None;
+        }
+    }
+
+    Some(Quad {
+        corners,
+        reversed_border,
+    })
+}
+
+/// A line fit to a set of points
+#[derive(Debug, Default, Clone, Copy)]
+struct Line {
+    /// A point on the line
+    x: f64,
+    y: f64,
+    /// Direction of the line
+    dx: f64,
+    dy: f64,
+    /// Mean squared distance from the points to the line
+    mse: f64,
+}
+impl Line {
+    /// Fit a line to some points, minimizing the distance perpendicular to it
+    fn fit<'a>(points: impl Iterator<Item = &'a BoundaryPoint>) -> Option<Self> {
+        let (mut n, mut sx, mut sy, mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+        for p in points {
+            n += 1.0;
+            sx += p.x;
+            sy += p.y;
+            sxx += p.x * p.x;
+            syy += p.y * p.y;
+            sxy += p.x * p.y;
+        }
+        if n < 3.0 {
+            return None;
+        }
+
+        let (x, y) = (sx / n, sy / n);
+        let cxx = sxx / n - x * x;
+        let cyy = syy / n - y * y;
+        let cxy = sxy / n - x * y;
+
+        // The line runs along the covariance's major axis, and the minor eigenvalue is how far the
+        // points stray from it
+        let theta = 0.5 * (2.0 * cxy).atan2(cxx - cyy);
+        let (dy, dx) = theta.sin_cos();
+        let mse = (cxx + cyy) / 2.0 - ((cxx - cyy).powi(2) / 4.0 + cxy * cxy).sqrt();
+
+        Some(Self {
+            x,
+            y,
+            dx,
+            dy,
+            mse: mse.max(0.0),
+        })
+    }
+
+    /// Find where two lines cross
+    fn intersect(&self, other: &Self) -> Option<[f64; 2]> {
+        let det = self.dx * other.dy - self.dy * other.dx;
+        if det.abs() < 1e-6 {
+            return None;
+        }
+
+        let t = ((other.x - self.x) * other.dy - (other.y - self.y) * other.dx) / det;
+
+        Some([self.x + t * self.dx, self.y + t * self.dy])
+    }
+}
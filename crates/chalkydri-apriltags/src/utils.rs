@@ -46,6 +46,7 @@ pub(crate) fn grayscale(data: &[u8]) -> u8 {
 }
 
 /// Turns p1, p2, p3... into an approximate angle
+#[allow(dead_code)]
 #[rustfmt::skip]
 #[inline(always)]
 pub(crate) fn fast_angle(p: u8) -> f32 {
@@ -70,6 +71,7 @@ pub(crate) fn fast_angle(p: u8) -> f32 {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum Orientation {
     Collinear,
@@ -78,6 +80,7 @@ pub(crate) enum Orientation {
 }
 
 /// Calculate the orientation
+#[allow(dead_code)]
 #[inline(always)]
 pub(crate) fn orientation(
     (px, py): (usize, usize),
@@ -101,7 +104,9 @@ pub(crate) fn orientation(
 }
 
 /// My gift wrapping implementation
+#[allow(dead_code)]
 pub(crate) struct PresentWrapper {}
+#[allow(dead_code)]
 impl PresentWrapper {
     // IDEA: I can take advantage of triangles for the early termination feature.
     // After drawing two lines, I can find the hypotenuse using Pythag theorem.
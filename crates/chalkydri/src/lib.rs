@@ -22,6 +22,7 @@ extern crate tracing;
 extern crate serde;
 
 pub mod cameras;
+pub mod ntables;
 pub mod subsystems;
 pub mod utils;
 
@@ -0,0 +1,413 @@
+//!
+//! NetworkTables publishing
+//!
+//! [`NtBundle`] connects to the robot's NetworkTables 4 server and publishes the topics described
+//! in the book's NetworkTables chapter. Each camera's [`NtPublisher`] sink feeds it that camera's
+//! pose estimates.
+//!
+//! The connection lives on its own thread, so the pipelines never wait on it. When the server goes
+//! away, it keeps trying to reconnect.
+//!
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{Ipv4Addr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chalkydri_apriltags::PoseEstimate;
+use chalkydri_core::prelude::{Cfg, warn};
+use cu29::prelude::*;
+use nt_client::data::Properties;
+use nt_client::{Client, ClientHandle, NTAddr, NewClientOptions};
+use tokio::sync::watch;
+use whacknet::RobotPose;
+
+use crate::utils::gen_team_ip;
+
+/// How long to wait before trying to connect again
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Most often the topics are updated
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(20);
+/// A camera is offline if it hasn't produced a frame in this long
+const CAMERA_TIMEOUT: Duration = Duration::from_secs(1);
+/// How far back frames are counted towards a camera's frame rate
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Where to find the NetworkTables server, and what to call ourselves
+///
+/// [`NtBundle`] reads this from its resource config:
+///
+/// - `host`: the server's IP address or hostname, or `"localhost"` to test against
+///   `tools/ntserver.py`
+/// - `team_number`: finds the RIO at `10.TE.AM.2` when there's no `host`
+/// - `device_id`: what goes in `Chalkydri/Devices/{device_id}/`
+///
+/// Without `host` or `team_number`, the Chalkydri config's `ntables_ip` or `team_number` is used.
+/// Without `device_id`, its `device_name` or the hostname is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtConfig {
+    pub server: Ipv4Addr,
+    pub device_id: String,
+}
+impl NtConfig {
+    /// Read the config from a resource config, falling back to the Chalkydri config
+    pub fn from_component_config(config: Option<&ComponentConfig>) -> CuResult<Self> {
+        let get = |key: &str| -> CuResult<Option<String>> {
+            config
+                .map(|config| config.get::<String>(key))
+                .transpose()
+                .map_err(|err| CuError::from(format!("invalid {key}: {err}")))
+                .map(Option::flatten)
+        };
+        let team_number = config
+            .map(|config| config.get::<u16>("team_number"))
+            .transpose()
+            .map_err(|err| CuError::from(format!("invalid team_number: {err}")))?
+            .flatten();
+
+        let (ntables_ip, cfg_team_number, device_name) = {
+            let cfg = Cfg.read();
+            (
+                cfg.ntables_ip.clone(),
+                cfg.team_number,
+                cfg.device_name.clone(),
+            )
+        };
+
+        let server = match (get("host")?, team_number) {
+            (Some(host), _) => Self::resolve(&host)?,
+            (None, Some(team_number)) => Self::team(team_number)?,
+            (None, None) => match ntables_ip {
+                Some(host) => Self::resolve(&host)?,
+                None => Self::team(cfg_team_number)?,
+            },
+        };
+        let device_id = get("device_id")?.or(device_name).unwrap_or_else(|| {
+            rustix::system::uname()
+                .nodename()
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        Ok(Self { server, device_id })
+    }
+
+    /// Find a team's RIO
+    fn team(team_number: u16) -> CuResult<Ipv4Addr> {
+        gen_team_ip(team_number)
+            .map(Ipv4Addr::from)
+            .ok_or_else(|| CuError::from(format!("invalid team number: {team_number}")))
+    }
+
+    /// Look up a host
+    fn resolve(host: &str) -> CuResult<Ipv4Addr> {
+        if host == "localhost" {
+            return Ok(Ipv4Addr::LOCALHOST);
+        }
+
+        (host, 0)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| {
+                addrs.find_map(|addr| match addr.ip() {
+                    std::net::IpAddr::V4(ip) => Some(ip),
+                    std::net::IpAddr::V6(_) => None,
+                })
+            })
+            .ok_or_else(|| CuError::from(format!("failed to resolve NetworkTables host '{host}'")))
+    }
+}
+
+/// How one camera is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CameraStatus {
+    /// Whether it's produced a frame recently
+    pub online: bool,
+    /// Frames processed per second
+    pub fps: f64,
+    /// How many tags were in the latest frame
+    pub tag_count: u8,
+}
+
+/// Everything we publish
+#[derive(Debug, Default, Clone)]
+struct NtState {
+    /// The latest robot pose from any camera
+    pose: Option<RobotPose>,
+    cameras: BTreeMap<u8, CameraStatus>,
+}
+
+/// A connection to the NetworkTables server
+///
+/// The connection is closed once every clone has been dropped.
+#[derive(Clone)]
+pub struct Nt {
+    state: Arc<watch::Sender<NtState>>,
+}
+impl Nt {
+    /// Start connecting to the server
+    pub fn new(config: NtConfig) -> Self {
+        let (state, rx) = watch::channel(NtState::default());
+
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build NetworkTables runtime");
+            runtime.block_on(run(config, rx));
+        });
+
+        Self {
+            state: Arc::new(state),
+        }
+    }
+
+    /// Publish the robot's pose
+    pub fn set_pose(&self, pose: RobotPose) {
+        self.state.send_modify(|state| state.pose = Some(pose));
+    }
+
+    /// Publish how a camera is doing
+    pub fn set_camera(&self, cam_id: u8, status: CameraStatus) {
+        self.state
+            .send_if_modified(|state| state.cameras.insert(cam_id, status) != Some(status));
+    }
+}
+
+/// Stay connected to the server until [`Nt`] is dropped
+async fn run(config: NtConfig, state: watch::Receiver<NtState>) {
+    loop {
+        let client = Client::new(NewClientOptions {
+            addr: NTAddr::Custom(config.server),
+            name: format!("chalkydri-{}", config.device_id),
+            ..Default::default()
+        });
+        let mut publishing = tokio::spawn(publish(
+            client.handle().clone(),
+            config.device_id.clone(),
+            state.clone(),
+        ));
+
+        tokio::select! {
+            result = client.connect() => {
+                if let Err(err) = result {
+                    warn!("NetworkTables connection to {} failed: {err:?}", config.server);
+                }
+                publishing.abort();
+            }
+            result = &mut publishing => {
+                if let Ok(Err(err)) = result {
+                    warn!("{err}");
+                }
+            }
+        }
+
+        // Nt was dropped
+        if state.has_changed().is_err() {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+/// Publish every topic whenever something changes
+///
+/// Returns `Ok` once [`Nt`] is dropped.
+async fn publish(
+    nt: ClientHandle,
+    device_id: String,
+    mut state: watch::Receiver<NtState>,
+) -> Result<(), String> {
+    macro_rules! publisher {
+        ($name:expr, $ty:ty) => {{
+            let name = $name;
+            nt.topic(name.clone())
+                .publish::<$ty>(Properties::default())
+                .await
+                .map_err(|err| format!("failed to publish {name}: {err:?}"))?
+        }};
+    }
+    macro_rules! set {
+        ($publisher:expr, $value:expr) => {
+            $publisher
+                .set($value)
+                .await
+                .map_err(|err| format!("failed to update NetworkTables: {err:?}"))?
+        };
+    }
+
+    let device = format!("/Chalkydri/Devices/{device_id}");
+    let mut x = publisher!("/Chalkydri/Robot/Position/X".to_owned(), f64);
+    let mut y = publisher!("/Chalkydri/Robot/Position/Y".to_owned(), f64);
+    let mut rotation = publisher!("/Chalkydri/Robot/Rotation".to_owned(), f64);
+    let mut version = publisher!(format!("{device}/Version"), String);
+    set!(version, env!("CARGO_PKG_VERSION").to_owned());
+
+    let mut cameras = HashMap::new();
+    // Publish whatever's there as soon as we connect
+    state.mark_changed();
+    while state.changed().await.is_ok() {
+        let NtState {
+            pose,
+            cameras: statuses,
+        } = state.borrow_and_update().clone();
+
+        if let Some(pose) = pose {
+            set!(x, pose.x);
+            set!(y, pose.y);
+            set!(rotation, pose.rot);
+        }
+
+        for (cam_id, status) in statuses {
+            let camera = format!("{device}/Cameras/{cam_id}");
+            if !cameras.contains_key(&cam_id) {
+                let publishers = (
+                    publisher!(format!("{camera}/Online"), bool),
+                    publisher!(format!("{camera}/FrameRate"), f64),
+                    publisher!(format!("{camera}/TagCount"), i64),
+                );
+                cameras.insert(cam_id, publishers);
+            }
+            let (online, fps, tag_count) = cameras.get_mut(&cam_id).unwrap();
+
+            set!(online, status.online);
+            set!(fps, status.fps);
+            set!(tag_count, status.tag_count as i64);
+        }
+
+        tokio::time::sleep(PUBLISH_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+pub struct NtBundle;
+bundle_resources!(NtBundle: Nt);
+
+impl ResourceBundle for NtBundle {
+    fn build(
+        bundle: BundleContext<Self>,
+        config: Option<&ComponentConfig>,
+        manager: &mut ResourceManager,
+    ) -> CuResult<()> {
+        let nt_key = bundle.key(NtBundleId::Nt);
+        let config = NtConfig::from_component_config(config)?;
+
+        manager.add_owned(nt_key, Nt::new(config))?;
+
+        Ok(())
+    }
+}
+
+pub struct Resources<'r> {
+    pub nt: Borrowed<'r, Nt>,
+}
+impl<'r> ResourceBindings<'r> for Resources<'r> {
+    type Binding = NtBundleId;
+    fn from_bindings(
+        manager: &'r mut ResourceManager,
+        mapping: Option<&ResourceBindingMap<Self::Binding>>,
+    ) -> CuResult<Self> {
+        let key = mapping
+            .expect("nt binding")
+            .get(Self::Binding::Nt)
+            .expect("nt")
+            .typed();
+        Ok(Self {
+            nt: manager.borrow(key)?,
+        })
+    }
+}
+
+/// Publishes one camera's pose estimates and status to NetworkTables
+///
+/// The camera's ID comes from its estimates, so it doesn't show up until its first frame.
+#[derive(Reflect)]
+#[reflect(from_reflect = false)]
+pub struct NtPublisher {
+    cam_id: Option<u8>,
+    #[reflect(ignore)]
+    nt: Nt,
+    /// When recent frames came in
+    #[reflect(ignore)]
+    frames: VecDeque<Instant>,
+    tag_count: u8,
+}
+
+impl Freezable for NtPublisher {}
+
+impl CuSinkTask for NtPublisher {
+    type Input<'m> = input_msg!(PoseEstimate);
+    type Resources<'r> = Resources<'r>;
+
+    fn new(_config: Option<&ComponentConfig>, resources: Self::Resources<'_>) -> CuResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            cam_id: None,
+            nt: resources.nt.0.clone(),
+            frames: VecDeque::new(),
+            tag_count: 0,
+        })
+    }
+
+    fn process(&mut self, _clock: &RobotClock, input: &Self::Input<'_>) -> CuResult<()> {
+        let now = Instant::now();
+
+        if let Some(estimate) = input.payload() {
+            self.frames.push_back(now);
+            self.cam_id = Some(estimate.cam_id);
+            // Fused poses count other cameras' tags too
+            self.tag_count = estimate.own_tag_count;
+            if let Some(pose) = estimate.pose {
+                self.nt.set_pose(pose);
+            }
+        }
+        while self
+            .frames
+            .front()
+            .is_some_and(|first| now.duration_since(*first) > FPS_WINDOW)
+        {
+            self.frames.pop_front();
+        }
+
+        let Some(cam_id) = self.cam_id else {
+            return Ok(());
+        };
+        self.nt.set_camera(
+            cam_id,
+            CameraStatus {
+                online: self
+                    .frames
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) <= CAMERA_TIMEOUT),
+                fps: self.frames.len() as f64 / FPS_WINDOW.as_secs_f64(),
+                tag_count: self.tag_count,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[test]
+fn nt_config() {
+    let mut config = ComponentConfig::new();
+    config.set("team_number", 4533u16);
+    config.set("device_id", "front".to_owned());
+    let team = NtConfig::from_component_config(Some(&config)).unwrap();
+    assert_eq!(team.server, Ipv4Addr::new(10, 45, 33, 2));
+    assert_eq!(team.device_id, "front");
+
+    // A host wins over a team number
+    config.set("host", "localhost".to_owned());
+    let local = NtConfig::from_component_config(Some(&config)).unwrap();
+    assert_eq!(local.server, Ipv4Addr::LOCALHOST);
+
+    let mut config = ComponentConfig::new();
+    config.set("team_number", 25_600u16);
+    assert!(NtConfig::from_component_config(Some(&config)).is_err());
+    // The default Chalkydri config doesn't have a team number either
+    assert!(NtConfig::from_component_config(None).is_err());
+}
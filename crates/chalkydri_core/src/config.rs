@@ -50,6 +50,7 @@ def_cfg! {
     }
     Rerun {
         server_address: Option<String>,
+        recording_path: Option<String>,
     }
     Camera {
         #[serde(skip_deserializing)]
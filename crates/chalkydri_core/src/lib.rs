@@ -24,6 +24,7 @@ pub extern crate toml;
 #[cfg(feature = "config")]
 pub mod config;
 mod error;
+pub mod utils;
 
 pub use error::Error;
 
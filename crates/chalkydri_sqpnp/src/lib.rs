@@ -3,10 +3,12 @@
 #[macro_use]
 extern crate tracing;
 
+mod planar;
+mod refine;
 mod util;
 
 use nalgebra::{
-    Isometry3, Matrix3, Matrix3x4, Point3, Rotation3, SMatrix, SVector, SimdRealField,
+    Isometry3, Matrix2x3, Matrix3, Matrix3x4, Point3, Rotation3, SMatrix, SVector, SimdRealField,
     Translation3, UnitQuaternion,
 };
 use std::{f64::consts::PI, ops::AddAssign};
@@ -19,12 +21,17 @@ pub type Mat15 = SMatrix<f64, 15, 15>;
 pub type Vec15 = SVector<f64, 15>;
 pub type Mat6x9 = SMatrix<f64, 6, 9>;
 pub type Vec6 = SVector<f64, 6>;
+pub type Mat6 = SMatrix<f64, 6, 6>;
+pub type Mat3x6 = SMatrix<f64, 3, 6>;
+pub type Mat2x6 = SMatrix<f64, 2, 6>;
 pub type Vec3 = SVector<f64, 3>;
+pub type Vec2 = SVector<f64, 2>;
 pub type Mat9x3 = SMatrix<f64, 9, 3>;
 pub type Iso3 = Isometry3<f64>;
 pub type Pnt3 = Point3<f64>;
 pub type Rot3 = Rotation3<f64>;
 
+// Defaults for the uncertainty model, which can be changed with the builder methods on `SqPnP`.
 // Increase these to trust vision LESS. Decrease to trust vision MORE.
 const XY_STD_DEV_SCALAR: f64 = 5.0;
 const THETA_STD_DEV_SCALAR: f64 = 2.0;
@@ -34,9 +41,16 @@ const MAX_TRUSTABLE_RMS: f64 = 0.1;
 // With a gradient, you usually want this slightly higher than a hard cutoff.
 const MAX_GYRO_DELTA: f64 = 30.0;
 
-// 2026 tag size in meters
-const TAG_SIZE: f64 = 0.1651;
-const CORNER_DISTANCE: f64 = TAG_SIZE / 2.0;
+// Least noise we'll assume corners have, in normalized image coordinates (pixels over focal length).
+// Residuals from a handful of corners can be tiny by chance, which would make the covariance
+// wildly overconfident.
+const MIN_IMAGE_NOISE: f64 = 1e-3;
+
+// Don't trust refined solves with a higher pixel RMS than this at all
+const MAX_TRUSTABLE_PIXEL_RMS: f64 = 2.0;
+
+/// 2026 tag size in meters
+pub const TAG_SIZE: f64 = 0.1651;
 
 #[inline(always)]
 fn nearest_so3(r_vec: &Vec9) -> Option<Vec9> {
@@ -95,7 +109,7 @@ fn constraints_and_jacobian(r_vec: &Vec9) -> (Vec6, Mat6x9) {
 }
 
 #[inline(always)]
-fn solve_newton(r: &Vec9, omega: &Mat9, h: &Vec6, jac: &Mat6x9) -> Option<Vec9> {
+fn solve_newton(r: &Vec9, omega: &Mat9, linear: &Vec9, h: &Vec6, jac: &Mat6x9) -> Option<Vec9> {
     // SQP (Sequential Quadratic Programming) step using KKT system
     let mut lhs = Mat15::zeros();
     lhs.fixed_view_mut::<9, 9>(0, 0).copy_from(omega);
@@ -103,9 +117,9 @@ fn solve_newton(r: &Vec9, omega: &Mat9, h: &Vec6, jac: &Mat6x9) -> Option<Vec9>
     lhs.fixed_view_mut::<6, 9>(9, 0).copy_from(jac);
 
     let mut rhs = Vec15::zeros();
-    let omega_r = omega * r;
+    let grad = omega * r + linear;
 
-    rhs.fixed_view_mut::<9, 1>(0, 0).copy_from(&(-omega_r));
+    rhs.fixed_view_mut::<9, 1>(0, 0).copy_from(&(-grad));
     rhs.fixed_view_mut::<6, 1>(9, 0).copy_from(&(-h));
 
     match lhs.lu().solve(&rhs) {
@@ -121,20 +135,21 @@ struct LinearSys {
 }
 
 #[inline(always)]
-fn build_linear_system(points_3d: &[Vec3], points_2d: &[Vec3]) -> LinearSys {
+fn build_linear_system(points_3d: &[Vec3], points_2d: &[Vec3], weights: &[f64]) -> LinearSys {
     let n = points_3d.len();
     assert_eq!(n, points_2d.len());
+    assert_eq!(n, weights.len());
 
     let mut q_rr = Mat9::zeros();
     let mut q_rt = Mat9x3::zeros();
     let mut q_tt = Mat3::zeros();
 
-    for (p_3d, p_img) in points_3d.iter().zip(points_2d.iter()) {
-        // Build Projection Matrix P = I - (v*v^T)/(v^T*v)
+    for ((p_3d, p_img), w) in points_3d.iter().zip(points_2d.iter()).zip(weights) {
+        // Build Projection Matrix P = I - (v*v^T)/(v^T*v), scaled by how much we trust the point
         let sq_norm = p_img.norm_squared();
         let inv_norm = 1.0 / sq_norm;
         let v_vt = p_img * p_img.transpose();
-        let p = Mat3::identity() - v_vt.scale(inv_norm);
+        let p = (Mat3::identity() - v_vt.scale(inv_norm)).scale(*w);
 
         q_tt += p;
 
@@ -179,16 +194,318 @@ fn build_linear_system(points_3d: &[Vec3], points_2d: &[Vec3]) -> LinearSys {
     }
 }
 
+/// Pivot a pose around the tags it was solved from, so its heading agrees with the gyro
+///
+/// Small disagreements are mostly left alone, and the pose is turned all the way to match the gyro
+/// once they reach `max_gyro_delta` degrees.
+fn pivot_to_gyro(
+    robot_rot: Rot3,
+    robot_pos: Vec3,
+    tag_centroid: Vec3,
+    gyro: f64,
+    max_gyro_delta: f64,
+) -> (Rot3, Vec3) {
+    let robot_rot_mat = robot_rot.matrix();
+
+    let vision_fwd_x = robot_rot_mat[(0, 0)];
+    let vision_fwd_y = robot_rot_mat[(1, 0)];
+    let vision_yaw = vision_fwd_y.simd_atan2(vision_fwd_x);
+
+    trace!("vision yaw:      {vision_yaw}");
+    let mut delta_yaw = gyro - vision_yaw;
+
+    delta_yaw = (delta_yaw + PI).rem_euclid(2.0 * PI) - PI;
+    trace!("delta yaw norm:  {delta_yaw}");
+
+    let delta_deg = delta_yaw.abs().to_degrees();
+    let mut weight = (delta_deg / max_gyro_delta).clamp(0.0, 1.0);
+    weight = weight * weight * (3.0 - 2.0 * weight);
+
+    let applied_delta_yaw = delta_yaw * weight;
+    trace!("apply delta yaw: {applied_delta_yaw}");
+
+    let cos_dt = applied_delta_yaw.cos();
+    let sin_dt = applied_delta_yaw.sin();
+
+    #[rustfmt::skip]
+    let rot_z = Mat3::new(
+        cos_dt, -sin_dt, 0.0,
+        sin_dt,  cos_dt, 0.0,
+           0.0,     0.0, 1.0,
+    );
+    let rot_z_rot3 = Rotation3::from_matrix(&rot_z);
+
+    let pos_relative_to_tag = robot_pos - tag_centroid;
+    let pivoted_pos = tag_centroid + (rot_z * pos_relative_to_tag);
+    let pivoted_robot_rot = rot_z_rot3 * robot_rot;
+
+    (pivoted_robot_rot, pivoted_pos)
+}
+
+/// How std devs are worked out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UncertaintyModel {
+    /// The pose's actual covariance, from how the corners' projections move with it
+    ///
+    /// This knows about the geometry of each solve, so one far away tag seen at a steep angle
+    /// reports a huge variance across its line of sight, and not much along it. If the covariance
+    /// can't be found, this falls back to [`Self::Linear`].
+    #[default]
+    Covariance,
+    /// Grows with distance, in tag sizes
+    Linear,
+    /// Grows with the square of distance, which punishes far away tags much harder
+    ///
+    /// This agrees with the linear model at 1 m.
+    DistanceSquared,
+    /// Grows as tags take up less of the image
+    ///
+    /// Unlike distance, this also accounts for tags seen at a steep angle.
+    TagArea,
+}
+impl std::str::FromStr for UncertaintyModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "covariance" => Ok(Self::Covariance),
+            "linear" => Ok(Self::Linear),
+            "distance_squared" => Ok(Self::DistanceSquared),
+            "tag_area" => Ok(Self::TagArea),
+            _ => Err(format!("invalid uncertainty model: {s}")),
+        }
+    }
+}
+
+/// Where a tag's corners are, in the same frame as its pose
+///
+/// They're in the same order as detectors give us corners in.
+pub fn tag_corners(tag: &Iso3) -> [Vec3; 4] {
+    sized_tag_corners(tag, TAG_SIZE)
+}
+
+/// Like [`tag_corners`], for tags that aren't [`TAG_SIZE`] (m)
+pub fn sized_tag_corners(tag: &Iso3, size: f64) -> [Vec3; 4] {
+    let s = size / 2.0;
+
+    #[rustfmt::skip]
+    let corner_points = [
+        Pnt3::new(0.0, -s, -s),
+        Pnt3::new(0.0,  s, -s),
+        Pnt3::new(0.0,  s,  s),
+        Pnt3::new(0.0, -s,  s),
+    ];
+
+    corner_points.map(|c| (tag * c).coords)
+}
+
+/// How ambiguous a single tag's pose is, from 0 (unambiguous) to 1 (no idea which is right)
+///
+/// A lone tag can look almost the same tilted either way along the line of sight. This finds both
+/// poses with IPPE (Collins and Bartoli, 2014), and compares how well each one fits the `bearings`
+/// (one per corner, in [`tag_corners`] order), like PhotonVision does.
+pub fn tag_ambiguity(bearings: &[Vec3]) -> f64 {
+    let [Some(rot_a), Some(rot_b)] = ippe_rotations(bearings) else {
+        return 1.0;
+    };
+    let (Some(a), Some(b)) = (
+        object_space_error(&rot_a, bearings),
+        object_space_error(&rot_b, bearings),
+    ) else {
+        return 1.0;
+    };
+
+    let (best, other) = if a < b { (a, b) } else { (b, a) };
+    if other > 0.0 { best / other } else { 1.0 }
+}
+
+/// Both rotations (tag to camera) that could explain how a tag looks
+fn ippe_rotations(bearings: &[Vec3]) -> [Option<Mat3>; 2] {
+    // Homography from the tag's plane (its Y and Z) to normalized image coordinates
+    let corners = tag_corners(&Iso3::identity());
+    let mut dlt = SMatrix::<f64, 9, 9>::zeros();
+    for (p, u) in corners.iter().zip(bearings) {
+        let (x, y) = (u.x / u.z, u.y / u.z);
+        let (a, b) = (p.y, p.z);
+        for row in [
+            SVector::<f64, 9>::from([a, b, 1.0, 0.0, 0.0, 0.0, -x * a, -x * b, -x]),
+            SVector::<f64, 9>::from([0.0, 0.0, 0.0, a, b, 1.0, -y * a, -y * b, -y]),
+        ] {
+            dlt += row * row.transpose();
+        }
+    }
+    let eigen = dlt.symmetric_eigen();
+    let h = eigen.eigenvectors.column(eigen.eigenvalues.imin());
+    if h[8].abs() < f64::EPSILON {
+        return [None, None];
+    }
+    let h = h / h[8];
+
+    // Where the tag's center is in the image, and how the image changes around it
+    let v = Vec2::new(h[2], h[5]);
+    let jac = nalgebra::Matrix2::new(
+        h[0] - h[6] * v.x,
+        h[1] - h[7] * v.x,
+        h[3] - h[6] * v.y,
+        h[4] - h[7] * v.y,
+    );
+
+    // Rotate the line of sight to the tag's center onto the optical axis
+    let rot_v = match v.norm() {
+        0.0 => Mat3::identity(),
+        t => {
+            let s = (v.norm_squared() + 1.0).sqrt();
+            let (cos, sin) = (1.0 / s, (1.0 - 1.0 / (s * s)).sqrt());
+            #[rustfmt::skip]
+            let k = Mat3::new(
+                0.0,  0.0,  v.x,
+                0.0,  0.0,  v.y,
+                -v.x, -v.y, 0.0,
+            ) / t;
+            Mat3::identity() + sin * k + (1.0 - cos) * k * k
+        }
+    };
+    let b = Matrix2x3::new(1.0, 0.0, -v.x, 0.0, 1.0, -v.y) * rot_v.fixed_columns::<2>(0);
+    let Some(a) = b.try_inverse().map(|b| b * jac) else {
+        return [None, None];
+    };
+
+    // Largest singular value of A
+    let aat = a * a.transpose();
+    let gamma = (0.5
+        * (aat.trace() + ((aat[(0, 0)] - aat[(1, 1)]).powi(2) + 4.0 * aat[(0, 1)].powi(2)).sqrt()))
+    .sqrt();
+    if gamma == 0.0 {
+        return [None, None];
+    }
+    let r = a / gamma;
+    let b0 = (1.0 - r[(0, 0)].powi(2) - r[(1, 0)].powi(2))
+        .max(0.0)
+        .sqrt();
+    let mut b1 = (1.0 - r[(0, 1)].powi(2) - r[(1, 1)].powi(2))
+        .max(0.0)
+        .sqrt();
+    if r[(0, 0)] * r[(0, 1)] + r[(1, 0)] * r[(1, 1)] > 0.0 {
+        b1 = -b1;
+    }
+
+    [1.0, -1.0].map(|sign| {
+        let y = Vec3::new(r[(0, 0)], r[(1, 0)], sign * b0);
+        let z = Vec3::new(r[(0, 1)], r[(1, 1)], sign * b1);
+        Some(rot_v * Mat3::from_columns(&[y.cross(&z), y, z]))
+    })
+}
+
+/// Object space error of a tag's corners, with the best translation for the given rotation
+fn object_space_error(rot: &Mat3, bearings: &[Vec3]) -> Option<f64> {
+    let corners = tag_corners(&Iso3::identity());
+    let projectors = bearings
+        .iter()
+        .map(|u| Mat3::identity() - u * u.transpose() / u.norm_squared())
+        .collect::<Vec<_>>();
+
+    let a: Mat3 = projectors.iter().sum();
+    let b: Vec3 = projectors
+        .iter()
+        .zip(&corners)
+        .map(|(q, p)| -(q * rot * p))
+        .sum();
+    let t = a.try_inverse()? * b;
+
+    Some(
+        projectors
+            .iter()
+            .zip(&corners)
+            .map(|(q, p)| (q * (rot * p + t)).norm_squared())
+            .sum(),
+    )
+}
+
+/// Turns NWU axes (X forward, Y left, Z up) into OpenCV's camera axes (X right, Y down, Z forward)
+fn nwu_to_cv() -> Iso3 {
+    #[rustfmt::skip]
+    let nwu_to_cv_rot = Rotation3::from_matrix_unchecked(Matrix3::new(
+         0.0,  0.0, 1.0,
+        -1.0,  0.0, 0.0,
+         0.0, -1.0, 0.0,
+    ));
+
+    Isometry3::from_parts(
+        Translation3::identity(),
+        UnitQuaternion::from_rotation_matrix(&nwu_to_cv_rot),
+    )
+}
+
+/// How big tags look from the camera, as the mean square root of their area in the image plane
+///
+/// A tag seen face on comes out at about its size over its distance.
+fn apparent_size(points_2d: &[Vec3]) -> f64 {
+    let sizes = points_2d.chunks_exact(4).map(|corners| {
+        let area: f64 = (0..4)
+            .map(|i| {
+                let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                (a.x / a.z) * (b.y / b.z) - (b.x / b.z) * (a.y / a.z)
+            })
+            .sum::<f64>()
+            / 2.0;
+        area.abs().sqrt()
+    });
+
+    let n = (points_2d.len() / 4) as f64;
+    sizes.sum::<f64>() / n
+}
+
+/// Corners in pixels, and how to get to them from the camera's frame, for refining a solve against
+pub struct Reprojection<'a> {
+    /// Corners in pixel coordinates, in the same order as the bearings
+    pub pixels: &'a [Vec2],
+    /// Project a point in the camera's frame to pixel coordinates, distortion and all
+    pub project: &'a dyn Fn(&Vec3) -> Option<Vec2>,
+}
+
+/// Tags seen by one camera, for solving with several cameras at once
+#[derive(Clone, Debug)]
+pub struct CameraObservation {
+    /// Where the tags are on the field
+    pub tags: Vec<Iso3>,
+    /// Bearing vectors to the tags' corners in the camera's frame, four per tag
+    pub bearings: Vec<Vec3>,
+    /// How much to trust each tag, relative to the others
+    ///
+    /// Leave this empty to trust them all the same.
+    pub weights: Vec<f64>,
+    /// How big each tag is (m)
+    ///
+    /// Leave this empty for them all to be the solver's [`SqPnP::tag_size`].
+    pub tag_sizes: Vec<f64>,
+    /// The camera's `robot_to_cam` transform
+    pub robot_to_cam: Iso3,
+}
+
 #[derive(Clone, Debug)]
 pub struct SqPnP {
     max_iter: usize,
     tol_sq: f64,
     buffer: Vec<Vec3>,
+    /// How much to trust each corner in `buffer`, averaging out to 1
+    weights: Vec<f64>,
+    /// How big each tag in `buffer` is (m)
+    sizes: Vec<f64>,
     candidates: Vec<(Vec9, f64)>,
     gyro_cos: f64,
     gyro_sin: f64,
     sign_change_error: f64,
     fwd_in_cam: Vec3,
+    /// Rotates the candidates' frame into the robot's, which is identity for multi-camera solves
+    cam_to_robot: Rot3,
+    xy_std_dev_scalar: f64,
+    theta_std_dev_scalar: f64,
+    max_trustable_rms: f64,
+    max_gyro_delta: f64,
+    uncertainty_model: UncertaintyModel,
+    min_image_noise: f64,
+    max_trustable_pixel_rms: f64,
+    tag_size: f64,
 }
 
 impl Default for SqPnP {
@@ -203,11 +520,22 @@ impl SqPnP {
             max_iter: 15,
             tol_sq: 1e-16,
             buffer: Vec::with_capacity(32),
+            weights: Vec::with_capacity(32),
+            sizes: Vec::with_capacity(8),
             candidates: Vec::with_capacity(6),
             gyro_cos: 0.0,
             gyro_sin: 0.0,
             sign_change_error: 0.0,
             fwd_in_cam: Vec3::new(0.0, 0.0, 1.0),
+            cam_to_robot: Rot3::identity(),
+            xy_std_dev_scalar: XY_STD_DEV_SCALAR,
+            theta_std_dev_scalar: THETA_STD_DEV_SCALAR,
+            max_trustable_rms: MAX_TRUSTABLE_RMS,
+            max_gyro_delta: MAX_GYRO_DELTA,
+            uncertainty_model: UncertaintyModel::Covariance,
+            min_image_noise: MIN_IMAGE_NOISE,
+            max_trustable_pixel_rms: MAX_TRUSTABLE_PIXEL_RMS,
+            tag_size: TAG_SIZE,
         }
     }
 
@@ -221,24 +549,107 @@ impl SqPnP {
         self
     }
 
-    fn compute_std_devs(&self, pure_geometric_energy: f64, distance: f64, n_tags: usize) -> Vec3 {
+    /// Scale X and Y std devs by this. Increase it to trust vision less.
+    pub const fn xy_std_dev_scalar(mut self, scalar: f64) -> Self {
+        self.xy_std_dev_scalar = scalar;
+        self
+    }
+
+    /// Scale rotation std devs by this. Increase it to trust vision less.
+    pub const fn theta_std_dev_scalar(mut self, scalar: f64) -> Self {
+        self.theta_std_dev_scalar = scalar;
+        self
+    }
+
+    /// Don't trust solves with a higher RMS error than this (m) at all
+    pub const fn max_trustable_rms(mut self, rms: f64) -> Self {
+        self.max_trustable_rms = rms;
+        self
+    }
+
+    /// How far vision's heading can be from the gyro's before the pose is pivoted all the way to
+    /// match it (degrees)
+    pub const fn max_gyro_delta(mut self, degrees: f64) -> Self {
+        self.max_gyro_delta = degrees;
+        self
+    }
+
+    /// How std devs are worked out
+    pub const fn uncertainty_model(mut self, model: UncertaintyModel) -> Self {
+        self.uncertainty_model = model;
+        self
+    }
+
+    /// Least noise to assume corners have when finding the covariance, in normalized image
+    /// coordinates (pixels over focal length)
+    pub const fn min_image_noise(mut self, noise: f64) -> Self {
+        self.min_image_noise = noise;
+        self
+    }
+
+    /// Don't trust refined solves with a higher pixel RMS than this at all
+    pub const fn max_trustable_pixel_rms(mut self, rms: f64) -> Self {
+        self.max_trustable_pixel_rms = rms;
+        self
+    }
+
+    /// How big the tags are, edge to edge of the black border (m)
+    pub const fn tag_size(mut self, size: f64) -> Self {
+        self.tag_size = size;
+        self
+    }
+
+    /// Rotation candidates from the last full solve and their energies, best first
+    ///
+    /// These are world-to-robot rotations, whichever frame the solve itself was in, and the
+    /// energies include the heading penalty. Planar solves don't have any.
+    pub fn rotation_candidates(&self) -> impl Iterator<Item = (Rot3, f64)> + '_ {
+        self.candidates.iter().map(|(r, energy)| {
+            (
+                self.cam_to_robot * Rot3::from_matrix(&Mat3::from_column_slice(r.as_slice())),
+                *energy,
+            )
+        })
+    }
+
+    fn compute_std_devs(
+        &self,
+        pure_geometric_energy: f64,
+        distance: f64,
+        apparent_size: f64,
+        n_tags: usize,
+        covariance: Option<Vec3>,
+    ) -> Vec3 {
         let n_points = (n_tags * 4) as f64;
         let rms_error = (pure_geometric_energy / n_points).sqrt();
 
-        if rms_error > MAX_TRUSTABLE_RMS {
+        if rms_error > self.max_trustable_rms {
             return Vec3::new(f64::MAX, f64::MAX, f64::MAX);
         }
 
-        let distance_multiplier = 1.0 + (distance / TAG_SIZE);
+        if let Some(std_devs) = covariance {
+            return Vec3::new(
+                (std_devs.x * self.xy_std_dev_scalar).max(0.01),
+                (std_devs.y * self.xy_std_dev_scalar).max(0.01),
+                (std_devs.z * self.theta_std_dev_scalar).max(0.01),
+            );
+        }
+
+        let tag_size = self.mean_tag_size();
+        let distance_multiplier = match self.uncertainty_model {
+            UncertaintyModel::Covariance | UncertaintyModel::Linear => 1.0 + (distance / tag_size),
+            UncertaintyModel::DistanceSquared => 1.0 + (distance * distance / tag_size),
+            UncertaintyModel::TagArea => 1.0 + 1.0 / apparent_size.max(f64::EPSILON),
+        };
 
         let base_xy_std = rms_error * distance_multiplier;
-        let xy_std = (base_xy_std / (n_tags as f64).sqrt()) * XY_STD_DEV_SCALAR;
+        let xy_std = (base_xy_std / (n_tags as f64).sqrt()) * self.xy_std_dev_scalar;
         let xy_std = xy_std.clamp(0.01, 10.0);
 
         let theta_std = {
-            let base_theta_std = rms_error / TAG_SIZE;
+            let base_theta_std = rms_error / tag_size;
             let val = (base_theta_std * distance_multiplier / (n_tags as f64).sqrt())
-                * THETA_STD_DEV_SCALAR;
+                * self.theta_std_dev_scalar;
             val.clamp(0.05, PI)
         };
 
@@ -249,8 +660,13 @@ impl SqPnP {
         &mut self,
         points_isometry: &[Isometry3<f64>],
         points_2d: &[Vec3],
+        weights: Option<&[f64]>,
+        sizes: Option<&[f64]>,
     ) -> Option<(Rot3, Vec3, f64)> {
-        self.corner_points_from_center(points_isometry);
+        self.corner_points_from_center(points_isometry, sizes);
+        self.weights.clear();
+        self.push_corner_weights(weights, points_isometry.len());
+        self.normalize_weights();
 
         if self.buffer.len() < 3 || self.buffer.len() != points_2d.len() {
             return None;
@@ -260,7 +676,7 @@ impl SqPnP {
             self.buffer.iter().fold(Vec3::zeros(), |acc, p| acc + p) / self.buffer.len() as f64;
         let points_3d_local: Vec<Vec3> = self.buffer.iter().map(|p| p - centroid).collect();
 
-        let sys = build_linear_system(&points_3d_local, points_2d);
+        let sys = build_linear_system(&points_3d_local, points_2d, &self.weights);
 
         self.solve_rotation_candidates(&sys.omega);
 
@@ -294,18 +710,192 @@ impl SqPnP {
         best_result
     }
 
+    /// Spread per-tag weights over their corners
+    ///
+    /// Missing, non-finite, and negative weights count as 1.
+    fn push_corner_weights(&mut self, tag_weights: Option<&[f64]>, n_tags: usize) {
+        let weight = |i: usize| {
+            tag_weights
+                .and_then(|weights| weights.get(i))
+                .copied()
+                .filter(|w| w.is_finite() && *w >= 0.0)
+                .unwrap_or(1.0)
+        };
+
+        self.weights
+            .extend((0..n_tags).flat_map(|i| std::iter::repeat_n(weight(i), 4)));
+    }
+
+    /// Scale weights so they average out to 1, which keeps energies comparable to unweighted ones
+    fn normalize_weights(&mut self) {
+        let mean = self.weights.iter().sum::<f64>() / self.weights.len() as f64;
+        if mean > 0.0 {
+            self.weights.iter_mut().for_each(|w| *w /= mean);
+        } else {
+            self.weights.fill(1.0);
+        }
+    }
+
+    /// Find the std devs of the robot's x, y, and heading on the field from the pose's covariance
+    ///
+    /// Takes each corner on the field, the bearing to it, the `robot_to_cam` of the camera that
+    /// saw it, and its weight. Corner noise is estimated from the residuals.
+    fn covariance_std_devs<'a>(
+        &self,
+        world_to_robot: &Iso3,
+        points: impl Iterator<Item = (&'a Vec3, &'a Vec3, &'a Iso3, f64)>,
+    ) -> Option<Vec3> {
+        let rot = world_to_robot.rotation.to_rotation_matrix().into_inner();
+        let trans = world_to_robot.translation.vector;
+
+        // Information matrix, with the pose perturbed as R -> exp(δθ)R and t -> t + δt
+        let mut info = Mat6::zeros();
+        let mut sq_error = 0.0;
+        let mut n_points = 0i32;
+        for (p_world, bearing, robot_to_cam, w) in points {
+            let rotated = rot * p_world;
+            let p_cam = robot_to_cam * Pnt3::from(rotated + trans);
+            if p_cam.z <= 0.0 || bearing.z <= 0.0 {
+                return None;
+            }
+
+            let (x, y, z) = (p_cam.x, p_cam.y, p_cam.z);
+            sq_error += w
+                * ((x / z - bearing.x / bearing.z).powi(2)
+                    + (y / z - bearing.y / bearing.z).powi(2));
+            n_points += 1;
+
+            #[rustfmt::skip]
+            let d_proj = Matrix2x3::new(
+                1.0 / z,     0.0, -x / (z * z),
+                    0.0, 1.0 / z, -y / (z * z),
+            );
+            let d_cam = d_proj * robot_to_cam.rotation.to_rotation_matrix().into_inner();
+
+            let mut jac = Mat2x6::zeros();
+            jac.fixed_view_mut::<2, 3>(0, 0)
+                .copy_from(&(d_cam * -rotated.cross_matrix()));
+            jac.fixed_view_mut::<2, 3>(0, 3).copy_from(&d_cam);
+
+            info += jac.transpose() * jac * w;
+        }
+
+        // Two residuals per corner, and six of them go to fitting the pose
+        let dof = 2 * n_points - 6;
+        let noise_sq = if dof > 0 { sq_error / dof as f64 } else { 0.0 }
+            .max(self.min_image_noise * self.min_image_noise);
+
+        let cov = info.try_inverse()? * noise_sq;
+
+        // The robot's position on the field is -Rᵀt, and its heading turns by the Z part of -Rᵀδθ
+        let rot_t = rot.transpose();
+        let mut proj = Mat3x6::zeros();
+        proj.fixed_view_mut::<2, 3>(0, 0)
+            .copy_from(&(-rot_t * trans.cross_matrix()).fixed_rows::<2>(0));
+        proj.fixed_view_mut::<2, 3>(0, 3)
+            .copy_from(&(-rot_t).fixed_rows::<2>(0));
+        proj.fixed_view_mut::<1, 3>(2, 0)
+            .copy_from(&(-rot_t).fixed_rows::<1>(2));
+
+        let cov = proj * cov * proj.transpose();
+        if !cov.iter().all(|v| v.is_finite()) {
+            return None;
+        }
+
+        Some(Vec3::new(
+            cov[(0, 0)].max(0.0).sqrt(),
+            cov[(1, 1)].max(0.0).sqrt(),
+            cov[(2, 2)].max(0.0).sqrt(),
+        ))
+    }
+
+    /// Solve for the robot's pose on the field
+    ///
+    /// With a `gyro` heading, rotation candidates that disagree with it are penalized by
+    /// `sign_change_error` and the final pose is pivoted towards it. Without one, the pose comes
+    /// from vision alone.
+    ///
+    /// `weights` says how much to trust each tag relative to the others, so tags that are small or
+    /// barely decoded don't pull the pose around as much. With `None`, they're all the same.
+    /// `sizes` says how big each tag is (m), for mixing families. With `None`, they're all
+    /// [`Self::tag_size`].
+    #[allow(clippy::too_many_arguments)]
     pub fn solve_robot_pose(
         &mut self,
         points_isometry: &[Isometry3<f64>],
         points_2d: &[Vec3],
+        weights: Option<&[f64]>,
+        sizes: Option<&[f64]>,
         robot_to_cam: &Isometry3<f64>,
-        gyro: f64,
+        gyro: Option<f64>,
         sign_change_error: f64,
     ) -> Option<(Rot3, Vec3, Vec3)> {
-        self.gyro_cos = gyro.cos();
-        self.gyro_sin = gyro.sin();
-        self.sign_change_error = sign_change_error;
+        self.solve_robot_pose_impl(
+            points_isometry,
+            points_2d,
+            weights,
+            sizes,
+            None,
+            robot_to_cam,
+            gyro,
+            sign_change_error,
+        )
+        .map(|(rot, pos, std_devs, _)| (rot, pos, std_devs))
+    }
+
+    /// Solve for the robot's pose on the field, then refine it against the corners in pixels
+    ///
+    /// This works like [`Self::solve_robot_pose`], but runs Levenberg-Marquardt on the pixel
+    /// reprojection error before working out std devs. Also returns the final RMS reprojection
+    /// error (px), which is a much better idea of how good the solve is than SqPnP's energy.
+    #[allow(clippy::too_many_arguments)]
+    pub fn solve_robot_pose_refined(
+        &mut self,
+        points_isometry: &[Isometry3<f64>],
+        points_2d: &[Vec3],
+        weights: Option<&[f64]>,
+        sizes: Option<&[f64]>,
+        reprojection: &Reprojection,
+        robot_to_cam: &Isometry3<f64>,
+        gyro: Option<f64>,
+        sign_change_error: f64,
+    ) -> Option<(Rot3, Vec3, Vec3, f64)> {
+        self.solve_robot_pose_impl(
+            points_isometry,
+            points_2d,
+            weights,
+            sizes,
+            Some(reprojection),
+            robot_to_cam,
+            gyro,
+            sign_change_error,
+        )
+        .and_then(|(rot, pos, std_devs, rms)| Some((rot, pos, std_devs, rms?)))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn solve_robot_pose_impl(
+        &mut self,
+        points_isometry: &[Isometry3<f64>],
+        points_2d: &[Vec3],
+        weights: Option<&[f64]>,
+        sizes: Option<&[f64]>,
+        reprojection: Option<&Reprojection>,
+        robot_to_cam: &Isometry3<f64>,
+        gyro: Option<f64>,
+        sign_change_error: f64,
+    ) -> Option<(Rot3, Vec3, Vec3, Option<f64>)> {
+        let heading = gyro.unwrap_or_default();
+        self.gyro_cos = heading.cos();
+        self.gyro_sin = heading.sin();
+        // Nothing to disagree with
+        self.sign_change_error = if gyro.is_some() {
+            sign_change_error
+        } else {
+            0.0
+        };
         self.buffer.clear();
+        self.sizes.clear();
         self.candidates.clear();
 
         trace!(sin = self.gyro_sin, cos = self.gyro_cos);
@@ -316,81 +906,487 @@ impl SqPnP {
             .matrix()
             .column(0)
             .into_owned();
+        self.cam_to_robot = robot_to_cam.rotation.inverse().to_rotation_matrix();
 
         let (rot_world_to_cam, trans_world_to_cam, pure_energy) =
-            self.solve(points_isometry, points_2d)?;
-
-        let distance = trans_world_to_cam.norm();
-        let n_tags = points_isometry.len();
-
-        let std_devs = self.compute_std_devs(pure_energy, distance, n_tags);
+            self.solve(points_isometry, points_2d, weights, sizes)?;
 
         let world_to_cam = Isometry3::from_parts(
             nalgebra::Translation3::from(trans_world_to_cam),
             nalgebra::UnitQuaternion::from_rotation_matrix(&rot_world_to_cam),
         );
 
+        let (world_to_cam, pixel_rms) = match reprojection {
+            Some(reprojection) => {
+                let (world_to_cam, rms) = refine::refine_reprojection(
+                    world_to_cam,
+                    &self.buffer,
+                    reprojection.pixels,
+                    &self.weights,
+                    reprojection.project,
+                    self.max_iter,
+                )?;
+                trace!("reprojection rms: {rms} px");
+
+                (world_to_cam, Some(rms))
+            }
+            None => (world_to_cam, None),
+        };
+
+        let distance = world_to_cam.translation.vector.norm();
+        let n_tags = points_isometry.len();
+
+        let covariance = if self.uncertainty_model == UncertaintyModel::Covariance {
+            self.covariance_std_devs(
+                &(robot_to_cam.inverse() * world_to_cam),
+                self.buffer
+                    .iter()
+                    .zip(points_2d)
+                    .zip(&self.weights)
+                    .map(|((p, v), w)| (p, v, robot_to_cam, *w)),
+            )
+        } else {
+            None
+        };
+        let std_devs = if pixel_rms.is_some_and(|rms| rms > self.max_trustable_pixel_rms) {
+            Vec3::new(f64::MAX, f64::MAX, f64::MAX)
+        } else {
+            self.compute_std_devs(
+                pure_energy,
+                distance,
+                apparent_size(points_2d),
+                n_tags,
+                covariance,
+            )
+        };
+
         let t_world_robot = world_to_cam.inverse() * (*robot_to_cam);
 
         let robot_pos = t_world_robot.translation.vector;
         let robot_rot = t_world_robot.rotation.to_rotation_matrix();
-        let robot_rot_mat = robot_rot.matrix();
+
+        let Some(gyro) = gyro else {
+            return Some((robot_rot, robot_pos, std_devs, pixel_rms));
+        };
 
         let tag_centroid = points_isometry
             .iter()
             .fold(Vec3::zeros(), |acc, iso| acc + iso.translation.vector)
             / n_tags as f64;
+        let (robot_rot, robot_pos) = pivot_to_gyro(
+            robot_rot,
+            robot_pos,
+            tag_centroid,
+            gyro,
+            self.max_gyro_delta,
+        );
 
-        let vision_fwd_x = robot_rot_mat[(0, 0)];
-        let vision_fwd_y = robot_rot_mat[(1, 0)];
-        let vision_yaw = vision_fwd_y.simd_atan2(vision_fwd_x);
+        Some((robot_rot, robot_pos, std_devs, pixel_rms))
+    }
 
-        trace!("vision yaw:      {vision_yaw}");
-        let mut delta_yaw = gyro - vision_yaw;
+    /// Solve for the robot's pose on the field from several cameras at once
+    ///
+    /// Every camera's bearings are moved into the robot's frame through its own `robot_to_cam`, so
+    /// all of their corners constrain one pose. That's much better conditioned than any camera on
+    /// its own, especially when they're looking in different directions. The observations should
+    /// come from frames captured at about the same time.
+    pub fn solve_robot_pose_multi(
+        &mut self,
+        observations: &[CameraObservation],
+        gyro: Option<f64>,
+        sign_change_error: f64,
+    ) -> Option<(Rot3, Vec3, Vec3)> {
+        let observations: Vec<&CameraObservation> = observations
+            .iter()
+            .filter(|obs| !obs.tags.is_empty() && obs.bearings.len() == obs.tags.len() * 4)
+            .collect();
+
+        let tag_weights =
+            |obs: &CameraObservation| (!obs.weights.is_empty()).then_some(obs.weights.clone());
+
+        if let [obs] = observations[..] {
+            return self.solve_robot_pose(
+                &obs.tags,
+                &obs.bearings,
+                tag_weights(obs).as_deref(),
+                Some(&obs.tag_sizes),
+                &obs.robot_to_cam,
+                gyro,
+                sign_change_error,
+            );
+        }
 
-        delta_yaw = (delta_yaw + PI).rem_euclid(2.0 * PI) - PI;
-        trace!("delta yaw norm:  {delta_yaw}");
+        // Each camera on its own gives us somewhere to start from
+        let mut seeds = Vec::with_capacity(observations.len());
+        for obs in &observations {
+            if let Some((robot_rot, _, _)) = self.solve_robot_pose(
+                &obs.tags,
+                &obs.bearings,
+                tag_weights(obs).as_deref(),
+                Some(&obs.tag_sizes),
+                &obs.robot_to_cam,
+                gyro,
+                sign_change_error,
+            ) {
+                let world_to_robot = robot_rot.inverse();
+                seeds.push(Vec9::from_column_slice(world_to_robot.matrix().as_slice()));
+            }
+        }
+        if seeds.is_empty() {
+            return None;
+        }
 
-        let delta_deg = delta_yaw.abs().to_degrees();
-        let mut weight = (delta_deg / MAX_GYRO_DELTA).clamp(0.0, 1.0);
-        weight = weight * weight * (3.0 - 2.0 * weight);
+        // Candidates are rotations from the field to the robot, so forward is just X
+        self.fwd_in_cam = Vec3::x();
+        self.cam_to_robot = Rot3::identity();
 
-        let applied_delta_yaw = delta_yaw * weight;
-        trace!("apply delta yaw: {applied_delta_yaw}");
+        // Corners on the field, and bearings to them and camera origins in the robot's frame
+        let mut bearings = Vec::new();
+        let mut origins = Vec::new();
+        let mut cameras = Vec::new();
+        self.buffer.clear();
+        self.sizes.clear();
+        self.weights.clear();
+        for (i, obs) in observations.iter().enumerate() {
+            self.corner_points_from_center(&obs.tags, Some(&obs.tag_sizes));
+            self.push_corner_weights(tag_weights(obs).as_deref(), obs.tags.len());
+
+            let cam_to_robot = obs.robot_to_cam.inverse();
+            bearings.extend(obs.bearings.iter().map(|v| cam_to_robot.rotation * v));
+            origins.extend(std::iter::repeat_n(
+                cam_to_robot.translation.vector,
+                obs.bearings.len(),
+            ));
+            cameras.extend(std::iter::repeat_n(i, obs.bearings.len()));
+        }
 
-        let cos_dt = applied_delta_yaw.cos();
-        let sin_dt = applied_delta_yaw.sin();
+        let centroid: Vec3 =
+            self.buffer.iter().fold(Vec3::zeros(), |acc, p| acc + p) / self.buffer.len() as f64;
+        let points_3d_local: Vec<Vec3> = self.buffer.iter().map(|p| p - centroid).collect();
+
+        self.normalize_weights();
+        let sys = build_linear_system(&points_3d_local, &bearings, &self.weights);
+
+        // Bearings no longer start at the origin, which adds linear terms to the energy
+        let mut g_r = Vec9::zeros();
+        let mut g_t = Vec3::zeros();
+        let mut constant = 0.0;
+        for (((p_3d, v), o), w) in points_3d_local
+            .iter()
+            .zip(&bearings)
+            .zip(&origins)
+            .zip(&self.weights)
+        {
+            let p =
+                (Mat3::identity() - (v * v.transpose()).scale(1.0 / v.norm_squared())).scale(*w);
+            let p_o = p * o;
+
+            g_t -= p_o;
+            g_r.fixed_view_mut::<3, 1>(0, 0)
+                .add_assign(&(-p_3d.x * p_o));
+            g_r.fixed_view_mut::<3, 1>(3, 0)
+                .add_assign(&(-p_3d.y * p_o));
+            g_r.fixed_view_mut::<3, 1>(6, 0)
+                .add_assign(&(-p_3d.z * p_o));
+            constant += o.dot(&p_o);
+        }
+        let linear = g_r - sys.q_rt * (sys.q_tt_inv * g_t);
+        constant -= g_t.dot(&(sys.q_tt_inv * g_t));
+
+        let mut best_result: Option<(Mat3, Vec3, f64)> = None;
+        let mut best_score = f64::MAX;
+
+        // The single camera solves left their own candidates behind
+        self.candidates.clear();
+        for seed in seeds {
+            let (r_vec, energy) = self.optimization(seed, &sys.omega, &linear);
+            let energy = (energy + constant).max(0.0);
+            let penalized_energy = energy + self.heading_penalty(&r_vec);
+            self.candidates.push((r_vec, penalized_energy));
+
+            let r_mat = Mat3::from_column_slice(r_vec.as_slice());
+            let t_local = -(sys.q_tt_inv * (sys.q_rt.tr_mul(&r_vec) + g_t));
+            let t = t_local - r_mat * centroid;
+
+            let all_in_front = self.buffer.iter().zip(&cameras).all(|(p, &i)| {
+                let p_cam = observations[i].robot_to_cam * Pnt3::from(r_mat * p + t);
+                p_cam.z > 0.0
+            });
+
+            if all_in_front && penalized_energy < best_score {
+                best_score = penalized_energy;
+                best_result = Some((r_mat, t, energy));
+            }
+        }
+
+        self.candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let (world_to_robot_rot, world_to_robot_trans, pure_energy) = best_result?;
+
+        let robot_rot = Rot3::from_matrix(&world_to_robot_rot.transpose());
+        let robot_pos = -(robot_rot * world_to_robot_trans);
+
+        let n_tags: usize = observations.iter().map(|obs| obs.tags.len()).sum();
+        let distance = self
+            .buffer
+            .iter()
+            .map(|p| (p - robot_pos).norm())
+            .sum::<f64>()
+            / self.buffer.len() as f64;
+
+        // Averaged over every tag, not every camera
+        let apparent_size = observations
+            .iter()
+            .map(|obs| apparent_size(&obs.bearings) * obs.tags.len() as f64)
+            .sum::<f64>()
+            / n_tags as f64;
+
+        let covariance = if self.uncertainty_model == UncertaintyModel::Covariance {
+            let world_to_robot = Isometry3::from_parts(
+                Translation3::from(world_to_robot_trans),
+                UnitQuaternion::from_matrix(&world_to_robot_rot),
+            );
+            let bearings = observations
+                .iter()
+                .flat_map(|obs| obs.bearings.iter().map(|v| (v, &obs.robot_to_cam)));
+
+            self.covariance_std_devs(
+                &world_to_robot,
+                self.buffer
+                    .iter()
+                    .zip(bearings)
+                    .zip(&self.weights)
+                    .map(|((p, (v, c)), w)| (p, v, c, *w)),
+            )
+        } else {
+            None
+        };
+        let std_devs =
+            self.compute_std_devs(pure_energy, distance, apparent_size, n_tags, covariance);
+
+        let Some(gyro) = gyro else {
+            return Some((robot_rot, robot_pos, std_devs));
+        };
+
+        let tag_centroid = observations
+            .iter()
+            .flat_map(|obs| &obs.tags)
+            .fold(Vec3::zeros(), |acc, iso| acc + iso.translation.vector)
+            / n_tags as f64;
+        let (robot_rot, robot_pos) = pivot_to_gyro(
+            robot_rot,
+            robot_pos,
+            tag_centroid,
+            gyro,
+            self.max_gyro_delta,
+        );
+
+        Some((robot_rot, robot_pos, std_devs))
+    }
+
+    /// Solve for the robot's pose on the field, assuming it's sitting flat on the floor
+    ///
+    /// Only x, y, and heading are solved for, with the camera's height, roll, and pitch taken from
+    /// `robot_to_cam`. That's far more stable than a full solve when there's only one tag, or the
+    /// tags are far away. With `fix_heading` and a `gyro` heading, only x and y are solved for.
+    /// Otherwise the gyro is just somewhere to start from. `weights` and `sizes` work like they do
+    /// in [`Self::solve_robot_pose`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn solve_robot_pose_planar(
+        &mut self,
+        points_isometry: &[Isometry3<f64>],
+        points_2d: &[Vec3],
+        weights: Option<&[f64]>,
+        sizes: Option<&[f64]>,
+        robot_to_cam: &Isometry3<f64>,
+        gyro: Option<f64>,
+        fix_heading: bool,
+    ) -> Option<(Rot3, Vec3, Vec3)> {
+        if points_2d.len() != points_isometry.len() * 4 {
+            return None;
+        }
+
+        self.buffer.clear();
+        self.sizes.clear();
+        self.weights.clear();
+        self.corner_points_from_center(points_isometry, sizes);
+        self.push_corner_weights(weights, points_isometry.len());
+
+        let bearings = points_2d
+            .iter()
+            .zip(std::iter::repeat_n(robot_to_cam, points_2d.len()));
+        self.solve_planar_impl(
+            bearings,
+            points_isometry.len(),
+            apparent_size(points_2d),
+            gyro,
+            fix_heading,
+        )
+    }
+
+    /// Solve for the robot's pose on the field from several cameras at once, assuming it's sitting
+    /// flat on the floor
+    ///
+    /// This is [`Self::solve_robot_pose_planar`] with every camera's corners constraining one pose,
+    /// like [`Self::solve_robot_pose_multi`].
+    pub fn solve_robot_pose_planar_multi(
+        &mut self,
+        observations: &[CameraObservation],
+        gyro: Option<f64>,
+        fix_heading: bool,
+    ) -> Option<(Rot3, Vec3, Vec3)> {
+        let observations: Vec<&CameraObservation> = observations
+            .iter()
+            .filter(|obs| !obs.tags.is_empty() && obs.bearings.len() == obs.tags.len() * 4)
+            .collect();
+
+        self.buffer.clear();
+        self.sizes.clear();
+        self.weights.clear();
+        for obs in &observations {
+            self.corner_points_from_center(&obs.tags, Some(&obs.tag_sizes));
+            let tag_weights = (!obs.weights.is_empty()).then_some(&obs.weights[..]);
+            self.push_corner_weights(tag_weights, obs.tags.len());
+        }
+
+        let n_tags: usize = observations.iter().map(|obs| obs.tags.len()).sum();
+        let apparent_size = observations
+            .iter()
+            .map(|obs| apparent_size(&obs.bearings) * obs.tags.len() as f64)
+            .sum::<f64>()
+            / n_tags as f64;
+
+        let bearings = observations
+            .iter()
+            .flat_map(|obs| obs.bearings.iter().map(|v| (v, &obs.robot_to_cam)));
+        self.solve_planar_impl(bearings, n_tags, apparent_size, gyro, fix_heading)
+    }
+
+    /// Solve for a planar pose from the corners in `buffer` and the bearings to them
+    fn solve_planar_impl<'a>(
+        &mut self,
+        bearings: impl Iterator<Item = (&'a Vec3, &'a Iso3)>,
+        n_tags: usize,
+        apparent_size: f64,
+        gyro: Option<f64>,
+        fix_heading: bool,
+    ) -> Option<(Rot3, Vec3, Vec3)> {
+        self.candidates.clear();
+        if n_tags == 0 {
+            return None;
+        }
+        self.normalize_weights();
 
-        #[rustfmt::skip]
-        let rot_z = Mat3::new(
-            cos_dt, -sin_dt, 0.0,
-            sin_dt,  cos_dt, 0.0,
-               0.0,     0.0, 1.0,
+        let corners: Vec<planar::Corner> = self
+            .buffer
+            .iter()
+            .zip(&self.weights)
+            .zip(bearings)
+            .map(|((p, w), (v, robot_to_cam))| planar::Corner {
+                world: *p,
+                bearing: *v,
+                robot_to_cam,
+                weight: *w,
+            })
+            .collect();
+
+        let fixed_heading = gyro.filter(|_| fix_heading);
+        let solution = planar::solve_planar(&corners, fixed_heading, gyro, self.max_iter)?;
+        let pose = solution.pose;
+        trace!(
+            x = pose.x,
+            y = pose.y,
+            heading = pose.heading,
+            "planar solve"
         );
-        let rot_z_rot3 = Rotation3::from_matrix(&rot_z);
 
-        let pos_relative_to_tag = robot_pos - tag_centroid;
-        let pivoted_pos = tag_centroid + (rot_z * pos_relative_to_tag);
-        let pivoted_robot_rot = rot_z_rot3 * robot_rot;
+        // The same energy SqPnP minimizes, so the heuristics work the same way
+        let energy = corners
+            .iter()
+            .map(|c| {
+                let p_cam = pose.to_cam(&c.world, c.robot_to_cam);
+                let along = p_cam.dot(&c.bearing.normalize());
+                c.weight * (p_cam.norm_squared() - along * along).max(0.0)
+            })
+            .sum::<f64>();
+
+        let robot_pos = Vec3::new(pose.x, pose.y, 0.0);
+        let distance = self
+            .buffer
+            .iter()
+            .map(|p| (p - robot_pos).norm())
+            .sum::<f64>()
+            / self.buffer.len() as f64;
+
+        let covariance = if self.uncertainty_model == UncertaintyModel::Covariance {
+            self.planar_std_devs(&solution, corners.len(), fixed_heading.is_some())
+        } else {
+            None
+        };
+        let mut std_devs =
+            self.compute_std_devs(energy, distance, apparent_size, n_tags, covariance);
+        // Vision didn't tell us anything about the heading
+        if fixed_heading.is_some() {
+            std_devs.z = std_devs.z.max(PI);
+        }
+
+        let robot_rot = Rot3::from_axis_angle(&Vec3::z_axis(), pose.heading);
 
-        Some((pivoted_robot_rot, pivoted_pos, std_devs))
+        Some((robot_rot, robot_pos, std_devs))
     }
 
-    fn corner_points_from_center(&mut self, isometry: &[Iso3]) -> () {
-        const S: f64 = CORNER_DISTANCE;
+    /// Find the std devs of a planar pose from its covariance
+    ///
+    /// Corner noise is estimated from the residuals, like [`Self::covariance_std_devs`]. With a
+    /// fixed heading, the heading's std dev is left at π.
+    fn planar_std_devs(
+        &self,
+        solution: &planar::PlanarSolution,
+        n_corners: usize,
+        fixed_heading: bool,
+    ) -> Option<Vec3> {
+        let n_params = if fixed_heading { 2 } else { 3 };
+        let dof = 2 * n_corners as i32 - n_params;
+        let noise_sq = if dof > 0 {
+            solution.cost / dof as f64
+        } else {
+            0.0
+        }
+        .max(self.min_image_noise * self.min_image_noise);
+
+        let std_devs = if fixed_heading {
+            let cov = solution.info.fixed_view::<2, 2>(0, 0).try_inverse()? * noise_sq;
+            Vec3::new(cov[(0, 0)].sqrt(), cov[(1, 1)].sqrt(), PI)
+        } else {
+            let cov = solution.info.try_inverse()? * noise_sq;
+            Vec3::new(cov[(0, 0)].sqrt(), cov[(1, 1)].sqrt(), cov[(2, 2)].sqrt())
+        };
 
-        #[rustfmt::skip]
-        const CORNER_POINTS_MAT: [Pnt3; 4] = [
-            Pnt3::new(0.0, -S, -S),
-            Pnt3::new(0.0,  S, -S),
-            Pnt3::new(0.0,  S,  S),
-            Pnt3::new(0.0, -S,  S),
-        ];
+        std_devs.iter().all(|v| v.is_finite()).then_some(std_devs)
+    }
 
-        isometry.iter().for_each(|iso: &Iso3| {
-            self.buffer
-                .extend(CORNER_POINTS_MAT.iter().map(|c| (iso * c).coords));
-        });
+    /// Add the corners of some tags to `buffer`
+    ///
+    /// Missing, non-finite, and non-positive sizes are [`Self::tag_size`].
+    fn corner_points_from_center(&mut self, isometry: &[Iso3], sizes: Option<&[f64]>) {
+        for (i, iso) in isometry.iter().enumerate() {
+            let size = sizes
+                .and_then(|sizes| sizes.get(i))
+                .copied()
+                .filter(|size| size.is_finite() && *size > 0.0)
+                .unwrap_or(self.tag_size);
+            self.buffer.extend(sized_tag_corners(iso, size));
+            self.sizes.push(size);
+        }
+    }
+
+    /// How big the tags in the last solve were on average (m)
+    fn mean_tag_size(&self) -> f64 {
+        if self.sizes.is_empty() {
+            self.tag_size
+        } else {
+            self.sizes.iter().sum::<f64>() / self.sizes.len() as f64
+        }
     }
 
     fn solve_rotation_candidates(&mut self, omega: &Mat9) {
@@ -405,19 +1401,9 @@ impl SqPnP {
             for sign in [-1.0, 1.0] {
                 let guess = e.scale(sign);
                 if let Some(r_start) = nearest_so3(&guess) {
-                    let (refined_r, mut energy) = self.optimization(r_start, omega);
-
-                    let d = &self.fwd_in_cam;
-
-                    let robot_fwd_x =
-                        refined_r[0] * d[0] + refined_r[1] * d[1] + refined_r[2] * d[2];
-                    let robot_fwd_y =
-                        refined_r[3] * d[0] + refined_r[4] * d[1] + refined_r[5] * d[2];
-
-                    let dot = (robot_fwd_x * self.gyro_cos) + (robot_fwd_y * self.gyro_sin);
-                    let angle_error = (1.0 - dot).max(0.0);
+                    let (refined_r, energy) = self.optimization(r_start, omega, &Vec9::zeros());
 
-                    energy += self.sign_change_error * angle_error;
+                    let energy = energy + self.heading_penalty(&refined_r);
 
                     self.candidates.push((refined_r, energy));
                 }
@@ -427,6 +1413,19 @@ impl SqPnP {
         self.candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
     }
 
+    /// How much a rotation candidate disagrees with the gyro, scaled by `sign_change_error`
+    fn heading_penalty(&self, r: &Vec9) -> f64 {
+        let d = &self.fwd_in_cam;
+
+        let robot_fwd_x = r[0] * d[0] + r[1] * d[1] + r[2] * d[2];
+        let robot_fwd_y = r[3] * d[0] + r[4] * d[1] + r[5] * d[2];
+
+        let dot = (robot_fwd_x * self.gyro_cos) + (robot_fwd_y * self.gyro_sin);
+        let angle_error = (1.0 - dot).max(0.0);
+
+        self.sign_change_error * angle_error
+    }
+
     pub fn create_solver_camera_transform(
         fwd_m: f64,
         left_m: f64,
@@ -445,26 +1444,37 @@ impl SqPnP {
 
         let robot_pose_of_cam_nwu = Isometry3::from_parts(nwu_translation, nwu_rotation);
 
-        #[rustfmt::skip]
-        let nwu_to_cv_rot = Rotation3::from_matrix_unchecked(Matrix3::new(
-             0.0,  0.0, 1.0,
-            -1.0,  0.0, 0.0,
-             0.0, -1.0, 0.0,
-        ));
-
-        let nwu_to_cv = Isometry3::from_parts(
-            Translation3::identity(),
-            UnitQuaternion::from_rotation_matrix(&nwu_to_cv_rot),
-        );
+        (robot_pose_of_cam_nwu * nwu_to_cv()).inverse()
+    }
 
-        (robot_pose_of_cam_nwu * nwu_to_cv).inverse()
+    /// Undo [`Self::create_solver_camera_transform`]
+    ///
+    /// Returns the camera's forward, left, and up offsets (m), and its roll, pitch, and yaw
+    /// (degrees), in that order.
+    pub fn decompose_solver_camera_transform(
+        robot_to_cam: &Iso3,
+    ) -> (f64, f64, f64, f64, f64, f64) {
+        let robot_pose_of_cam_nwu = robot_to_cam.inverse() * nwu_to_cv().inverse();
+
+        let t = robot_pose_of_cam_nwu.translation.vector;
+        let (roll, pitch, yaw) = robot_pose_of_cam_nwu.rotation.euler_angles();
+
+        (
+            t.x,
+            t.y,
+            t.z,
+            roll.to_degrees(),
+            pitch.to_degrees(),
+            yaw.to_degrees(),
+        )
     }
 
-    fn optimization(&self, start_r: Vec9, omega: &Mat9) -> (Vec9, f64) {
+    /// Minimize `rᵀΩr + 2·linearᵀr` over rotations, starting from `start_r`
+    fn optimization(&self, start_r: Vec9, omega: &Mat9, linear: &Vec9) -> (Vec9, f64) {
         let mut r = start_r;
         for _ in 0..self.max_iter {
             let (h, jac) = constraints_and_jacobian(&r);
-            match solve_newton(&r, omega, &h, &jac) {
+            match solve_newton(&r, omega, linear, &h, &jac) {
                 Some(delta_r) => {
                     r += delta_r;
                     if delta_r.norm_squared() < self.tol_sq {
@@ -474,7 +1484,319 @@ impl SqPnP {
                 None => break,
             }
         }
-        let energy = r.dot(&(omega * r));
+        let energy = r.dot(&(omega * r)) + 2.0 * linear.dot(&r);
         (r, energy)
     }
 }
+
+/// A robot sitting at `(x, y)` facing `heading` (rad)
+#[cfg(test)]
+fn robot_at(x: f64, y: f64, heading: f64) -> Iso3 {
+    Isometry3::from_parts(
+        Translation3::new(x, y, 0.0),
+        UnitQuaternion::from_euler_angles(0.0, 0.0, heading),
+    )
+}
+
+/// A tag in front of the robot, facing back at it
+///
+/// `offset` is where the tag is in the robot's frame.
+#[cfg(test)]
+fn tag_facing(robot: &Iso3, offset: Vec3) -> Iso3 {
+    robot
+        * Isometry3::from_parts(
+            offset.into(),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, PI),
+        )
+}
+
+/// What a camera on the robot sees of some tags, with perfect corners
+#[cfg(test)]
+fn observe(robot: &Iso3, robot_to_cam: &Iso3, tags: &[Iso3]) -> CameraObservation {
+    let world_to_cam = robot_to_cam * robot.inverse();
+
+    CameraObservation {
+        tags: tags.to_vec(),
+        bearings: tags
+            .iter()
+            .flat_map(tag_corners)
+            .map(|corner| {
+                let p = world_to_cam * Pnt3::from(corner);
+                p.coords / p.z
+            })
+            .collect(),
+        weights: Vec::new(),
+        tag_sizes: Vec::new(),
+        robot_to_cam: *robot_to_cam,
+    }
+}
+
+/// Whether one of the solver's rotation candidates turns the field into the robot's frame at `rot`
+#[cfg(test)]
+fn has_candidate(solver: &SqPnP, rot: &Rot3) -> bool {
+    solver.rotation_candidates().any(|(world_to_robot, _)| {
+        ((world_to_robot * rot).matrix() - Mat3::identity()).norm() < 1e-6
+    })
+}
+
+#[test]
+fn multi_camera_solve() {
+    let robot = robot_at(5.0, 3.0, 0.4);
+    let front = SqPnP::create_solver_camera_transform(0.3, 0.1, 0.5, 0.0, -10.0, 0.0);
+    let back = SqPnP::create_solver_camera_transform(-0.3, -0.1, 0.4, 0.0, -10.0, 180.0);
+    let behind = robot * Isometry3::rotation(Vec3::z() * PI);
+    let observations = [
+        observe(
+            &robot,
+            &front,
+            &[tag_facing(&robot, Vec3::new(4.0, 0.3, 1.0))],
+        ),
+        observe(
+            &robot,
+            &back,
+            &[tag_facing(&behind, Vec3::new(3.5, 0.5, 1.2))],
+        ),
+    ];
+
+    let mut solver = SqPnP::new();
+    for gyro in [None, Some(0.4)] {
+        let (rot, pos, std_dev) = solver
+            .solve_robot_pose_multi(&observations, gyro, 600.0)
+            .unwrap();
+        assert!((pos - robot.translation.vector).norm() < 1e-6, "{pos:?}");
+        assert!((rot.euler_angles().2 - 0.4).abs() < 1e-6);
+        assert!(std_dev.iter().all(|s| s.is_finite() && *s > 0.0));
+        assert!(has_candidate(&solver, &rot));
+    }
+
+    // Both cameras together are surer than either one on its own
+    let (_, _, fused) = solver
+        .solve_robot_pose_multi(&observations, None, 600.0)
+        .unwrap();
+    for observation in &observations {
+        let (rot, pos, alone) = solver
+            .solve_robot_pose_multi(std::slice::from_ref(observation), None, 600.0)
+            .unwrap();
+        assert!((pos - robot.translation.vector).norm() < 1e-6);
+        assert!(has_candidate(&solver, &rot));
+        assert!(fused.x <= alone.x && fused.y <= alone.y);
+    }
+}
+
+#[test]
+fn covariance_matches_spread() {
+    let robot = robot_at(2.0, 4.0, -0.3);
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.2, 0.0, 0.5, 0.0, -15.0, 0.0);
+    let tags = [
+        tag_facing(&robot, Vec3::new(3.0, -0.6, 1.0)),
+        tag_facing(&robot, Vec3::new(3.2, 0.8, 1.0)),
+    ];
+    let exact = observe(&robot, &robot_to_cam, &tags);
+
+    // Gaussian noise, from a fixed seed so this always does the same thing
+    let mut seed = 42u64;
+    let mut uniform = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((seed >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    };
+    let mut gaussian = move || (-2.0 * uniform().ln()).sqrt() * (2.0 * PI * uniform()).cos();
+
+    let mut solver = SqPnP::new()
+        .xy_std_dev_scalar(1.0)
+        .theta_std_dev_scalar(1.0);
+    let runs = 200;
+    let mut errors = Vec3::zeros();
+    let mut predicted = Vec3::zeros();
+    for _ in 0..runs {
+        let bearings = exact
+            .bearings
+            .iter()
+            .map(|b| Vec3::new(b.x + 0.003 * gaussian(), b.y + 0.003 * gaussian(), 1.0))
+            .collect::<Vec<_>>();
+        let (rot, pos, std_dev) = solver
+            .solve_robot_pose(&tags, &bearings, None, None, &robot_to_cam, None, 600.0)
+            .unwrap();
+
+        let error = Vec3::new(
+            pos.x - 2.0,
+            pos.y - 4.0,
+            (rot.euler_angles().2 + 0.3 + PI).rem_euclid(2.0 * PI) - PI,
+        );
+        errors += error.component_mul(&error);
+        predicted += std_dev;
+    }
+    let actual = (errors / runs as f64).map(f64::sqrt);
+    let predicted = predicted / runs as f64;
+
+    for i in 0..3 {
+        let ratio = predicted[i] / actual[i];
+        assert!((0.67..1.5).contains(&ratio), "{predicted:?} vs {actual:?}");
+    }
+
+    // The scalars apply on top of the covariance
+    let mut solver = solver.xy_std_dev_scalar(2.0).theta_std_dev_scalar(3.0);
+    let (_, _, scaled) = solver
+        .solve_robot_pose(
+            &tags,
+            &exact.bearings,
+            None,
+            None,
+            &robot_to_cam,
+            None,
+            600.0,
+        )
+        .unwrap();
+    let (_, _, unscaled) = SqPnP::new()
+        .xy_std_dev_scalar(1.0)
+        .theta_std_dev_scalar(1.0)
+        .solve_robot_pose(
+            &tags,
+            &exact.bearings,
+            None,
+            None,
+            &robot_to_cam,
+            None,
+            600.0,
+        )
+        .unwrap();
+    assert!((scaled.x - 2.0 * unscaled.x).abs() < 1e-9);
+    assert!((scaled.z - 3.0 * unscaled.z).abs() < 1e-9);
+}
+
+#[test]
+fn weights_favor_trusted_tags() {
+    let robot = robot_at(1.0, 2.0, 0.2);
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.2, 0.0, 0.5, 0.0, -15.0, 0.0);
+    let tags = [
+        tag_facing(&robot, Vec3::new(3.0, -0.6, 1.0)),
+        tag_facing(&robot, Vec3::new(3.2, 0.8, 1.0)),
+        tag_facing(&robot, Vec3::new(2.8, 0.1, 1.4)),
+    ];
+    let mut bearings = observe(&robot, &robot_to_cam, &tags).bearings;
+    // The last tag's corners are all dragged the same way, like a badly decoded tag would be
+    for b in &mut bearings[8..] {
+        b.x += 0.01;
+        b.y -= 0.005;
+    }
+
+    let mut solver = SqPnP::new();
+    let mut error = |weights: Option<&[f64]>| {
+        let (_, pos, _) = solver
+            .solve_robot_pose(&tags, &bearings, weights, None, &robot_to_cam, None, 600.0)
+            .unwrap();
+        (pos - robot.translation.vector).norm()
+    };
+
+    let unweighted = error(None);
+    let weighted = error(Some(&[1.0, 1.0, 0.01]));
+    assert!(weighted < unweighted / 3.0, "{weighted} vs {unweighted}");
+
+    // Only relative weights matter, and bad ones count as 1
+    assert!((error(Some(&[3.0, 3.0, 3.0])) - unweighted).abs() < 1e-9);
+    assert!((error(Some(&[f64::NAN, -1.0])) - unweighted).abs() < 1e-9);
+    assert!((error(Some(&[2.0, 2.0, 0.02])) - weighted).abs() < 1e-9);
+}
+
+#[test]
+fn planar_solve() {
+    let robot = robot_at(4.0, -1.5, 2.5);
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.25, -0.1, 0.6, 0.0, -12.0, 10.0);
+    let tags = [tag_facing(&robot, Vec3::new(6.0, 0.5, 1.1))];
+    let exact = observe(&robot, &robot_to_cam, &tags);
+
+    let mut solver = SqPnP::new();
+    for (gyro, fix_heading) in [(None, false), (Some(2.3), false), (Some(2.5), true)] {
+        let (rot, pos, std_dev) = solver
+            .solve_robot_pose_planar(
+                &tags,
+                &exact.bearings,
+                None,
+                None,
+                &robot_to_cam,
+                gyro,
+                fix_heading,
+            )
+            .unwrap();
+        assert!((pos - robot.translation.vector).norm() < 1e-6, "{pos:?}");
+        assert!((rot.euler_angles().2 - 2.5).abs() < 1e-6);
+        assert!(std_dev.iter().all(|s| s.is_finite() && *s > 0.0));
+        assert_eq!(std_dev.z >= PI, fix_heading);
+    }
+}
+
+#[test]
+fn ambiguity_from_tilt() {
+    let robot = robot_at(0.0, 0.0, 0.0);
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.0, 0.0, 1.0, 0.0, 0.0, 0.0);
+    let ambiguity = |distance: f64, tilt: f64| {
+        let tag = tag_facing(&robot, Vec3::new(distance, 0.0, 1.0))
+            * Isometry3::rotation(Vec3::z() * tilt.to_radians());
+        tag_ambiguity(&observe(&robot, &robot_to_cam, &[tag]).bearings)
+    };
+
+    for distance in [2.0, 6.0] {
+        // Square on, both tilts fit just as well
+        assert!(ambiguity(distance, 0.0) > 0.99);
+        // Turned away, only one does
+        assert!(ambiguity(distance, 30.0) < 0.01);
+        assert!(ambiguity(distance, -30.0) < 0.01);
+    }
+}
+
+#[test]
+fn mixed_tag_sizes() {
+    let robot = robot_at(3.0, 1.0, 0.1);
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.2, 0.0, 0.5, 0.0, -15.0, 0.0);
+    let tags = [
+        tag_facing(&robot, Vec3::new(3.0, -0.6, 1.0)),
+        tag_facing(&robot, Vec3::new(3.2, 0.8, 1.0)),
+    ];
+    let sizes = [TAG_SIZE, 0.1];
+    let world_to_cam = robot_to_cam * robot.inverse();
+    let bearings: Vec<Vec3> = tags
+        .iter()
+        .zip(sizes)
+        .flat_map(|(tag, size)| sized_tag_corners(tag, size))
+        .map(|corner| {
+            let p = world_to_cam * Pnt3::from(corner);
+            p.coords / p.z
+        })
+        .collect();
+
+    let mut solver = SqPnP::new();
+    let mut error = |sizes: Option<&[f64]>| {
+        let (_, pos, _) = solver
+            .solve_robot_pose(&tags, &bearings, None, sizes, &robot_to_cam, None, 600.0)
+            .unwrap();
+        (pos - robot.translation.vector).norm()
+    };
+    assert!(error(Some(&sizes)) < 1e-6);
+    assert!(error(None) > 1e-3);
+
+    let (_, pos, _) = solver
+        .solve_robot_pose_planar(
+            &tags,
+            &bearings,
+            None,
+            Some(&sizes),
+            &robot_to_cam,
+            None,
+            false,
+        )
+        .unwrap();
+    assert!((pos - robot.translation.vector).norm() < 1e-6);
+
+    let observation = CameraObservation {
+        tags: tags.to_vec(),
+        bearings,
+        weights: Vec::new(),
+        tag_sizes: sizes.to_vec(),
+        robot_to_cam,
+    };
+    let (_, pos, _) = solver
+        .solve_robot_pose_multi(&[observation.clone(), observation], None, 600.0)
+        .unwrap();
+    assert!((pos - robot.translation.vector).norm() < 1e-6);
+}
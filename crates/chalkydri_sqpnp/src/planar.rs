@@ -0,0 +1,233 @@
+//!
+//! Planar pose solving
+//!
+//! The robot drives around on a flat floor, and `robot_to_cam` already tells us each camera's
+//! height, roll, and pitch. So instead of a full 6-DoF pose, we only need x, y, and heading, which
+//! a single distant tag pins down far better. The heading can also be fixed from the gyro, leaving
+//! just x and y.
+//!
+
+use std::f64::consts::TAU;
+
+use nalgebra::{Matrix2, Matrix2x3, SMatrix, Vector2};
+
+use crate::{Iso3, Mat3, Pnt3, Vec3};
+
+/// How many headings we start from when the heading isn't fixed
+const HEADING_STEPS: usize = 16;
+/// Damping to start out with
+const INITIAL_LAMBDA: f64 = 1e-3;
+/// Give up once the damping gets this high, since we can't find a step that helps
+const MAX_LAMBDA: f64 = 1e10;
+/// Stop once steps get this small
+const MIN_STEP: f64 = 1e-12;
+
+/// A tag corner and the ray a camera saw it along
+pub(crate) struct Corner<'a> {
+    /// Where the corner is on the field
+    pub world: Vec3,
+    /// Bearing to the corner in the camera's frame
+    pub bearing: Vec3,
+    pub robot_to_cam: &'a Iso3,
+    pub weight: f64,
+}
+
+/// Where the robot is on the floor
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PlanarPose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+}
+impl PlanarPose {
+    /// Move a point on the field into the robot's frame
+    fn to_robot(self, p: &Vec3) -> Vec3 {
+        let (sin, cos) = self.heading.sin_cos();
+        let (dx, dy) = (p.x - self.x, p.y - self.y);
+
+        Vec3::new(cos * dx + sin * dy, -sin * dx + cos * dy, p.z)
+    }
+
+    /// Move a point on the field into a camera's frame
+    pub fn to_cam(self, p: &Vec3, robot_to_cam: &Iso3) -> Vec3 {
+        (robot_to_cam * Pnt3::from(self.to_robot(p))).coords
+    }
+}
+
+/// A solved pose and how well it fits
+pub(crate) struct PlanarSolution {
+    pub pose: PlanarPose,
+    /// Weighted sum of squared reprojection errors, in normalized image coordinates
+    pub cost: f64,
+    /// Information matrix for x, y, and heading, without the corner noise
+    ///
+    /// With a fixed heading, only the top left 2x2 block means anything.
+    pub info: Mat3,
+}
+
+/// Find the cost of a pose, and the normal equations for improving it
+///
+/// Returns `None` if any corner ends up behind its camera.
+fn linearize(pose: PlanarPose, corners: &[Corner], fix_heading: bool) -> Option<(f64, Mat3, Vec3)> {
+    let (sin, cos) = pose.heading.sin_cos();
+
+    let mut cost = 0.0;
+    let mut jtj = Mat3::zeros();
+    let mut jtr = Vec3::zeros();
+    for corner in corners {
+        let p_robot = pose.to_robot(&corner.world);
+        let p_cam = (corner.robot_to_cam * Pnt3::from(p_robot)).coords;
+        if p_cam.z <= 0.0 || corner.bearing.z <= 0.0 {
+            return None;
+        }
+
+        let (x, y, z) = (p_cam.x, p_cam.y, p_cam.z);
+        let b = &corner.bearing;
+        let res = Vector2::new(x / z - b.x / b.z, y / z - b.y / b.z);
+        cost += corner.weight * res.norm_squared();
+
+        // How the point moves in the robot's frame as the robot moves in x, y, and heading
+        #[rustfmt::skip]
+        let d_robot = Mat3::new(
+            -cos, -sin,  p_robot.y,
+             sin, -cos, -p_robot.x,
+             0.0,  0.0,  0.0,
+        );
+        #[rustfmt::skip]
+        let d_proj = Matrix2x3::new(
+            1.0 / z,     0.0, -x / (z * z),
+                0.0, 1.0 / z, -y / (z * z),
+        );
+        let mut jac = d_proj * corner.robot_to_cam.rotation.to_rotation_matrix().matrix() * d_robot;
+        if fix_heading {
+            jac.column_mut(2).fill(0.0);
+        }
+
+        jtj += jac.transpose() * jac * corner.weight;
+        jtr += jac.transpose() * res * corner.weight;
+    }
+
+    Some((cost, jtj, jtr))
+}
+
+/// Find where the robot is at a given heading, by least squares on the cross product of each
+/// bearing with its corner
+///
+/// This isn't the reprojection error, but it's linear in x and y, so it's a good place to start.
+fn position_at_heading(corners: &[Corner], heading: f64) -> Option<PlanarPose> {
+    let (sin, cos) = heading.sin_cos();
+    #[rustfmt::skip]
+    let world_to_robot = Mat3::new(
+         cos, sin, 0.0,
+        -sin, cos, 0.0,
+         0.0, 0.0, 1.0,
+    );
+
+    let mut lhs = Matrix2::zeros();
+    let mut rhs = Vector2::zeros();
+    for corner in corners {
+        let cross = corner.bearing.normalize().cross_matrix();
+        let m = cross * corner.robot_to_cam.rotation.to_rotation_matrix().matrix() * world_to_robot;
+        let m_xy: SMatrix<f64, 3, 2> = m.fixed_columns::<2>(0).into_owned();
+        let target = m * corner.world + cross * corner.robot_to_cam.translation.vector;
+
+        lhs += m_xy.transpose() * m_xy * corner.weight;
+        rhs += m_xy.transpose() * target * corner.weight;
+    }
+
+    let xy = lhs.lu().solve(&rhs)?;
+
+    Some(PlanarPose {
+        x: xy.x,
+        y: xy.y,
+        heading,
+    })
+}
+
+/// Minimize the reprojection error with Levenberg-Marquardt, starting from `pose`
+fn refine(
+    mut pose: PlanarPose,
+    corners: &[Corner],
+    fix_heading: bool,
+    max_iter: usize,
+) -> Option<PlanarSolution> {
+    let (mut cost, mut jtj, mut jtr) = linearize(pose, corners, fix_heading)?;
+    let mut lambda = INITIAL_LAMBDA;
+
+    'outer: for _ in 0..max_iter {
+        // Keep raising the damping until we find a step that helps
+        loop {
+            let mut damped = jtj;
+            for i in 0..3 {
+                damped[(i, i)] += lambda * jtj[(i, i)].max(1e-9);
+            }
+            if fix_heading {
+                damped[(2, 2)] = 1.0;
+            }
+            let Some(step) = damped.lu().solve(&-jtr) else {
+                break 'outer;
+            };
+
+            let candidate = PlanarPose {
+                x: pose.x + step.x,
+                y: pose.y + step.y,
+                heading: pose.heading + step.z,
+            };
+            if let Some((new_cost, new_jtj, new_jtr)) = linearize(candidate, corners, fix_heading)
+                && new_cost < cost
+            {
+                (pose, cost, jtj, jtr) = (candidate, new_cost, new_jtj, new_jtr);
+                lambda = (lambda / 10.0).max(1e-12);
+
+                if step.norm() < MIN_STEP {
+                    break 'outer;
+                }
+                break;
+            }
+
+            lambda *= 10.0;
+            if lambda > MAX_LAMBDA {
+                break 'outer;
+            }
+        }
+    }
+
+    pose.heading = pose.heading.rem_euclid(TAU);
+
+    Some(PlanarSolution {
+        pose,
+        cost,
+        info: jtj,
+    })
+}
+
+/// Solve for the robot's pose on the floor
+///
+/// With a `fixed_heading`, only x and y are solved for. Otherwise we start from headings all the
+/// way around, plus `seed_heading` if there is one, and keep whichever fits best.
+pub(crate) fn solve_planar(
+    corners: &[Corner],
+    fixed_heading: Option<f64>,
+    seed_heading: Option<f64>,
+    max_iter: usize,
+) -> Option<PlanarSolution> {
+    if corners.len() < 2 {
+        return None;
+    }
+
+    if let Some(heading) = fixed_heading {
+        return refine(
+            position_at_heading(corners, heading)?,
+            corners,
+            true,
+            max_iter,
+        );
+    }
+
+    (0..HEADING_STEPS)
+        .map(|i| i as f64 * TAU / HEADING_STEPS as f64)
+        .chain(seed_heading)
+        .filter_map(|heading| position_at_heading(corners, heading))
+        .filter_map(|pose| refine(pose, corners, false, max_iter))
+        .min_by(|a, b| a.cost.total_cmp(&b.cost))
+}
@@ -0,0 +1,189 @@
+//!
+//! Nonlinear refinement
+//!
+//! SqPnP minimizes an algebraic error on unprojected rays, which isn't quite what we care about.
+//! Starting from its solution, we minimize the actual pixel reprojection error with
+//! Levenberg-Marquardt, projecting through the camera's model so distortion is accounted for.
+//!
+
+use nalgebra::{Isometry3, Translation3, UnitQuaternion};
+
+use crate::{Iso3, Mat6, Vec2, Vec3, Vec6};
+
+/// Step used for numerical derivatives (rad or m)
+const DIFF_STEP: f64 = 1e-6;
+/// Damping to start out with
+const INITIAL_LAMBDA: f64 = 1e-3;
+/// Give up once the damping gets this high, since we can't find a step that helps
+const MAX_LAMBDA: f64 = 1e10;
+/// Stop once steps get this small
+const MIN_STEP: f64 = 1e-10;
+
+/// Nudge a pose by a small rotation and translation, in the camera's frame
+fn perturb(pose: &Iso3, delta: &Vec6) -> Iso3 {
+    let rotation = UnitQuaternion::from_scaled_axis(delta.fixed_rows::<3>(0).into_owned());
+    let translation = Translation3::from(delta.fixed_rows::<3>(3).into_owned());
+
+    Isometry3::from_parts(translation, rotation) * pose
+}
+
+/// Reprojection error of every corner, or `None` if any of them can't be projected
+fn residuals(
+    world_to_cam: &Iso3,
+    points: &[Vec3],
+    pixels: &[Vec2],
+    project: &dyn Fn(&Vec3) -> Option<Vec2>,
+) -> Option<Vec<Vec2>> {
+    points
+        .iter()
+        .zip(pixels)
+        .map(|(p, px)| {
+            let p_cam = world_to_cam.transform_point(&(*p).into()).coords;
+            (p_cam.z > 0.0).then_some(())?;
+            Some(project(&p_cam)? - px)
+        })
+        .collect()
+}
+
+/// Weighted sum of squared reprojection errors
+fn cost(residuals: &[Vec2], weights: &[f64]) -> f64 {
+    residuals
+        .iter()
+        .zip(weights)
+        .map(|(r, w)| w * r.norm_squared())
+        .sum()
+}
+
+/// Refine a world-to-camera pose by minimizing pixel reprojection error
+///
+/// Each corner's error is scaled by its weight. Returns the refined pose and its unweighted RMS
+/// reprojection error (px), or `None` if the corners can't be projected from the starting pose.
+pub(crate) fn refine_reprojection(
+    world_to_cam: Iso3,
+    points: &[Vec3],
+    pixels: &[Vec2],
+    weights: &[f64],
+    project: &dyn Fn(&Vec3) -> Option<Vec2>,
+    max_iter: usize,
+) -> Option<(Iso3, f64)> {
+    if points.len() != pixels.len() || points.len() != weights.len() || points.is_empty() {
+        return None;
+    }
+
+    let mut pose = world_to_cam;
+    let mut res = residuals(&pose, points, pixels, project)?;
+    let mut current = cost(&res, weights);
+    let mut lambda = INITIAL_LAMBDA;
+
+    'outer: for _ in 0..max_iter {
+        // Central differences, since the camera model doesn't give us derivatives
+        let mut jac = vec![[Vec2::zeros(); 6]; points.len()];
+        for k in 0..6 {
+            let mut delta = Vec6::zeros();
+            delta[k] = DIFF_STEP;
+            let (Some(ahead), Some(behind)) = (
+                residuals(&perturb(&pose, &delta), points, pixels, project),
+                residuals(&perturb(&pose, &-delta), points, pixels, project),
+            ) else {
+                break 'outer;
+            };
+
+            for (i, (a, b)) in ahead.iter().zip(&behind).enumerate() {
+                jac[i][k] = (a - b) / (2.0 * DIFF_STEP);
+            }
+        }
+
+        let mut jtj = Mat6::zeros();
+        let mut jtr = Vec6::zeros();
+        for ((j, r), w) in jac.iter().zip(&res).zip(weights) {
+            for a in 0..6 {
+                jtr[a] += w * j[a].dot(r);
+                for b in 0..6 {
+                    jtj[(a, b)] += w * j[a].dot(&j[b]);
+                }
+            }
+        }
+
+        // Keep raising the damping until we find a step that helps
+        loop {
+            let mut damped = jtj;
+            for i in 0..6 {
+                damped[(i, i)] += lambda * jtj[(i, i)].max(1e-9);
+            }
+            let Some(step) = damped.lu().solve(&-jtr) else {
+                break 'outer;
+            };
+
+            let candidate = perturb(&pose, &step);
+            if let Some(new_res) = residuals(&candidate, points, pixels, project) {
+                let new_cost = cost(&new_res, weights);
+                if new_cost < current {
+                    pose = candidate;
+                    res = new_res;
+                    current = new_cost;
+                    lambda = (lambda / 10.0).max(1e-12);
+
+                    if step.norm() < MIN_STEP {
+                        break 'outer;
+                    }
+                    break;
+                }
+            }
+
+            lambda *= 10.0;
+            if lambda > MAX_LAMBDA {
+                break 'outer;
+            }
+        }
+    }
+
+    let rms = (res.iter().map(|r| r.norm_squared()).sum::<f64>() / points.len() as f64).sqrt();
+
+    Some((pose, rms))
+}
+
+#[test]
+fn refine_synthetic_pose() {
+    use crate::tag_corners;
+
+    // A pinhole camera with a bit of barrel distortion
+    let project = |p: &Vec3| {
+        let (x, y) = (p.x / p.z, p.y / p.z);
+        let distortion = 1.0 - 0.1 * (x * x + y * y);
+        Some(Vec2::new(
+            600.0 * x * distortion + 320.0,
+            600.0 * y * distortion + 240.0,
+        ))
+    };
+
+    let tags = [
+        Isometry3::from_parts(
+            Translation3::new(3.0, 0.5, 1.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 3.0),
+        ),
+        Isometry3::from_parts(
+            Translation3::new(3.5, -0.7, 1.2),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, -3.0),
+        ),
+    ];
+    let points = tags.iter().flat_map(tag_corners).collect::<Vec<_>>();
+
+    // A camera on a robot at the origin, looking down the field's X axis
+    let world_to_cam = crate::SqPnP::create_solver_camera_transform(0.2, 0.1, 0.5, 0.0, -10.0, 5.0);
+    let pixels = points
+        .iter()
+        .map(|p| project(&world_to_cam.transform_point(&(*p).into()).coords).unwrap())
+        .collect::<Vec<_>>();
+
+    let start = perturb(
+        &world_to_cam,
+        &Vec6::new(0.02, -0.01, 0.015, 0.05, -0.03, 0.08),
+    );
+    let weights = vec![1.0; points.len()];
+    let (refined, rms) =
+        refine_reprojection(start, &points, &pixels, &weights, &project, 20).unwrap();
+
+    assert!(rms < 1e-6, "{rms}");
+    assert!((refined.translation.vector - world_to_cam.translation.vector).norm() < 1e-6);
+    assert!(refined.rotation.angle_to(&world_to_cam.rotation) < 1e-6);
+}
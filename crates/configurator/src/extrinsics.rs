@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+
+use aprilgrid::{TagFamily, detector::TagDetector};
+use camera_intrinsic_model::GenericModel;
+use chalkydri::subsystems::calibration::CALIB;
+use chalkydri_apriltags::RobotToCamOffset;
+use chalkydri_sqpnp::{Iso3, Reprojection, SqPnP, Vec2, Vec3, tag_corners};
+use nalgebra::{Translation3, UnitQuaternion};
+
+/// Frames to average each tag's corners over for one capture
+pub const FRAMES_PER_CAPTURE: usize = 10;
+
+/// Tags seen from one spot, with where they are relative to the robot
+struct Capture {
+    tags: Vec<Iso3>,
+    bearings: Vec<Vec3>,
+    pixels: Vec<Vec2>,
+}
+
+/// A solved robot-to-camera offset and how well it fits
+pub struct ExtrinsicSolution {
+    pub offsets: RobotToCamOffset,
+    /// RMS reprojection error over every capture (px)
+    pub rms: f64,
+    /// RMS reprojection error of each capture (px)
+    pub capture_rms: Vec<f64>,
+}
+
+/// Finds where a camera is on the robot from tags at known positions relative to it
+///
+/// The camera doesn't move on the robot, so every corner we see, moved into the robot's frame,
+/// constrains the same camera pose. Solving for it is just PnP with the robot's frame as the world.
+pub struct ExtrinsicCalibrator {
+    det: TagDetector,
+    cam_model: GenericModel<f64>,
+    /// Corners of every tag seen in each frame of the current capture
+    frames: Vec<HashMap<u32, [(f32, f32); 4]>>,
+    captures: Vec<Capture>,
+}
+impl ExtrinsicCalibrator {
+    pub fn new(cam_model: GenericModel<f64>) -> Self {
+        Self {
+            det: TagDetector::new(&TagFamily::T36H11, None),
+            cam_model,
+            frames: Vec::new(),
+            captures: Vec::new(),
+        }
+    }
+
+    /// Attempt to grab a frame and find tags in it
+    ///
+    /// Returns how many frames with tags in them the current capture has.
+    pub fn process(&mut self) -> usize {
+        if let Some((img, _)) = CALIB.lock().take() {
+            let detections = self.det.detect(&img);
+            if !detections.is_empty() {
+                self.frames.push(detections);
+            }
+        }
+
+        self.frames.len()
+    }
+
+    /// Throw out any frames we've grabbed for the current capture
+    pub fn clear(&mut self) {
+        CALIB.lock().take();
+        self.frames.clear();
+    }
+
+    /// Finish the current capture
+    ///
+    /// `tags` says where each tag is relative to the robot, by ID. Tags we saw that aren't in it are
+    /// ignored. Returns how many tags were used.
+    pub fn capture(&mut self, tags: &HashMap<usize, Iso3>) -> usize {
+        let mut capture = Capture {
+            tags: Vec::new(),
+            bearings: Vec::new(),
+            pixels: Vec::new(),
+        };
+
+        for (id, tag) in tags {
+            // Average the corners over every frame the tag was seen in
+            let seen: Vec<_> = self
+                .frames
+                .iter()
+                .filter_map(|frame| frame.get(&(*id as u32)))
+                .collect();
+            if seen.is_empty() {
+                continue;
+            }
+            let corners: Vec<Vec2> = (0..4)
+                .map(|i| {
+                    seen.iter()
+                        .map(|corners| Vec2::new(corners[i].0 as f64, corners[i].1 as f64))
+                        .sum::<Vec2>()
+                        / seen.len() as f64
+                })
+                .collect();
+
+            let bearings: Vec<Vec3> = corners.iter().filter_map(|c| self.unproject(c)).collect();
+
+            // Only use it if the corners could be unprojected
+            if bearings.len() == 4 {
+                capture.tags.push(*tag);
+                capture.bearings.extend(bearings);
+                capture.pixels.extend(corners);
+            }
+        }
+        self.frames.clear();
+
+        let n_tags = capture.tags.len();
+        if n_tags > 0 {
+            self.captures.push(capture);
+        }
+
+        n_tags
+    }
+
+    /// How many captures we have
+    pub fn captures(&self) -> usize {
+        self.captures.len()
+    }
+
+    /// Solve for the camera's offset from every capture
+    pub fn solve(&self) -> Option<ExtrinsicSolution> {
+        let tags: Vec<Iso3> = self.captures.iter().flat_map(|c| c.tags.clone()).collect();
+        let bearings: Vec<Vec3> = self
+            .captures
+            .iter()
+            .flat_map(|c| c.bearings.clone())
+            .collect();
+        let pixels: Vec<Vec2> = self
+            .captures
+            .iter()
+            .flat_map(|c| c.pixels.clone())
+            .collect();
+
+        // With the robot's frame as the world and no offset, the "robot" SqPnP finds is the camera
+        let project = |p: &Vec3| self.project(p);
+        let (rot, pos, _, rms) = SqPnP::new().solve_robot_pose_refined(
+            &tags,
+            &bearings,
+            None,
+            None,
+            &Reprojection {
+                pixels: &pixels,
+                project: &project,
+            },
+            &Iso3::identity(),
+            None,
+            0.0,
+        )?;
+
+        let robot_to_cam = Iso3::from_parts(
+            Translation3::from(pos),
+            UnitQuaternion::from_rotation_matrix(&rot),
+        )
+        .inverse();
+
+        let capture_rms = self
+            .captures
+            .iter()
+            .map(|capture| {
+                let (sq_error, n) = capture
+                    .tags
+                    .iter()
+                    .flat_map(tag_corners)
+                    .zip(&capture.pixels)
+                    .filter_map(|(corner, px)| {
+                        let p_cam = robot_to_cam.transform_point(&corner.into()).coords;
+                        Some((self.project(&p_cam)? - px).norm_squared())
+                    })
+                    .fold((0.0, 0), |(sum, n), e| (sum + e, n + 1));
+
+                if n > 0 {
+                    (sq_error / n as f64).sqrt()
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect();
+
+        let (x, y, z, roll, pitch, yaw) = SqPnP::decompose_solver_camera_transform(&robot_to_cam);
+
+        Some(ExtrinsicSolution {
+            offsets: RobotToCamOffset {
+                roll,
+                pitch,
+                yaw,
+                x,
+                y,
+                z,
+            },
+            rms,
+            capture_rms,
+        })
+    }
+
+    /// Turn pixel coordinates into a bearing in the camera's frame
+    fn unproject(&self, px: &Vec2) -> Option<Vec3> {
+        self.cam_model
+            .unproject(&[[px.x, px.y].into()])
+            .pop()
+            .flatten()
+            .map(|v| Vec3::new(v.x, v.y, v.z))
+    }
+
+    /// Project a point in the camera's frame to pixel coordinates
+    fn project(&self, p: &Vec3) -> Option<Vec2> {
+        if p.z <= 0.0 {
+            return None;
+        }
+
+        self.cam_model
+            .project(&[[p.x, p.y, p.z].into()])
+            .pop()
+            .flatten()
+            .map(|px| Vec2::new(px.x, px.y))
+    }
+}
+
+/// Where the tags in a field layout are relative to the robot, with the robot at a known pose on
+/// the field
+///
+/// `heading` is in degrees.
+pub fn tags_from_field_pose(
+    layout: &HashMap<usize, Iso3>,
+    x: f64,
+    y: f64,
+    heading: f64,
+) -> HashMap<usize, Iso3> {
+    let robot = Iso3::from_parts(
+        Translation3::new(x, y, 0.0),
+        UnitQuaternion::from_euler_angles(0.0, 0.0, heading.to_radians()),
+    );
+    let field_to_robot = robot.inverse();
+
+    layout
+        .iter()
+        .map(|(id, tag)| (*id, field_to_robot * tag))
+        .collect()
+}
+
+/// A tag at a measured position relative to the robot
+///
+/// The tag faces along `yaw` (degrees), so a tag in front of the robot facing it has a yaw of 180.
+pub fn tag_from_robot_offset(x: f64, y: f64, z: f64, yaw: f64) -> Iso3 {
+    Iso3::from_parts(
+        Translation3::new(x, y, z),
+        UnitQuaternion::from_euler_angles(0.0, 0.0, yaw.to_radians()),
+    )
+}
+
+#[test]
+fn extrinsics_round_trip() {
+    use camera_intrinsic_model::OpenCVModel5;
+    use nalgebra::DVector;
+
+    let cam_model = GenericModel::OpenCVModel5(OpenCVModel5::new(
+        &DVector::from_vec(vec![600.0, 600.0, 640.0, 400.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        1280,
+        800,
+    ));
+    let robot_to_cam = SqPnP::create_solver_camera_transform(0.3, -0.15, 0.5, 0.0, -12.0, 8.0);
+    let layout: HashMap<usize, Iso3> = [(2.0, 1.0), (3.0, 1.4), (4.0, 1.1)]
+        .into_iter()
+        .enumerate()
+        .map(|(id, (y, z))| (id, tag_from_robot_offset(6.0, y, z, 180.0)))
+        .collect();
+
+    let mut calibrator = ExtrinsicCalibrator::new(cam_model);
+    for (x, y, heading) in [(2.0, 3.0, 0.0), (2.5, 2.5, 15.0), (1.5, 3.5, -10.0)] {
+        let tags = tags_from_field_pose(&layout, x, y, heading);
+        let frame = tags
+            .iter()
+            .map(|(id, tag)| {
+                let corners = tag_corners(tag).map(|corner| {
+                    let px = calibrator
+                        .project(&robot_to_cam.transform_point(&corner.into()).coords)
+                        .unwrap();
+                    (px.x as f32, px.y as f32)
+                });
+                (*id as u32, corners)
+            })
+            .collect();
+        calibrator.frames.push(frame);
+        assert_eq!(calibrator.capture(&tags), layout.len());
+    }
+
+    let solution = calibrator.solve().unwrap();
+    let offsets = solution.offsets;
+    let expected = [0.3, -0.15, 0.5, 0.0, -12.0, 8.0];
+    let solved = [
+        offsets.x,
+        offsets.y,
+        offsets.z,
+        offsets.roll,
+        offsets.pitch,
+        offsets.yaw,
+    ];
+    for (solved, expected) in solved.iter().zip(expected) {
+        assert!((solved - expected).abs() < 1e-3, "{solved} vs {expected}");
+    }
+    assert!(solution.rms < 1e-3);
+    assert!(solution.capture_rms.iter().all(|rms| *rms < 1e-3));
+}
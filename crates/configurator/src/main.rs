@@ -8,22 +8,24 @@ use std::time::Duration;
 use chalkydri::cameras::GstToCuImage;
 use chalkydri::cameras::pipeline::CamPipeline;
 use chalkydri::cameras::providers::{CamProvider, PROVIDER};
-use chalkydri_apriltags::RobotToCamOffset;
+use chalkydri_apriltags::{FieldLayouts, RobotToCamOffset};
 use clap::Parser;
 use color_eyre::Result;
 use cu29::config::{CuConfig, Node};
 use cu29::prelude::*;
 use cu29::reflect::GetField;
 use cu29_helpers::basic_copper_setup;
-use dialoguer::{Input, Select};
+use dialoguer::{Confirm, Input, Select};
 use gstreamer::State;
 use gstreamer::prelude::{DeviceExt, ElementExt, PadExt};
 use indexmap::IndexMap;
 use indicatif::ProgressBar;
 
 mod calibration;
+mod extrinsics;
 mod monitor;
 use calibration::*;
+use extrinsics::*;
 use monitor::Monitor;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -83,6 +85,12 @@ impl Configurator {
                 config: None,
                 missions: None,
             });
+            cu.resources.push(ResourceBundleConfig {
+                id: "nt".to_owned(),
+                provider: "chalkydri::ntables::NtBundle".to_owned(),
+                config: None,
+                missions: None,
+            });
         }
 
         Self {
@@ -194,6 +202,20 @@ impl Configurator {
                 apriltags_id
             };
 
+            // NetworkTables publishing
+            let nt = {
+                let text_id = format!("nt_{cam_id}");
+                let nt_id = g.get_node_id_by_name(&text_id).unwrap_or_else(|| {
+                    let node = Node::new(&text_id, "chalkydri::ntables::NtPublisher");
+                    g.add_node(node).expect("this should never fail")
+                });
+                let nt = g.get_node_mut(nt_id).expect("very wonk config");
+
+                nt.set_resources(Some([("nt".to_owned(), "nt.nt".to_owned())]));
+
+                nt_id
+            };
+
             // Make all the connections
             for (src, target, msg) in [
                 (cam, gst_to_cu, "(cu_gstreamer::CuGstBuffer, CuDuration)"),
@@ -202,6 +224,7 @@ impl Configurator {
                     apriltags,
                     "(cu_sensor_payloads::CuImage<Vec<u8>>, CuDuration)",
                 ),
+                (apriltags, nt, "chalkydri_apriltags::PoseEstimate"),
             ] {
                 if !g.connection_exists(src, target) {
                     g.connect_ext(src, target, msg, None, None, None)
@@ -323,32 +346,7 @@ impl Configurator {
         let pathbuf = PathBuf::from_str("config/calibration.ron").unwrap();
         let copper_ctx = basic_copper_setup(pathbuf.as_path(), None, true, None).unwrap();
 
-        let mut config: CuConfig = read_configuration_str(
-            include_str!("../../../config/calibration.ron").to_owned(),
-            None,
-        )
-        .unwrap();
-
-        let g = config.get_graph_mut(None).unwrap();
-
-        let cam_node = g
-            .get_node_mut(g.get_node_id_by_name("camera").unwrap())
-            .unwrap();
-        cam_node.set_param("id", dev_id.to_owned());
-        cam_node.set_param("width", width);
-        cam_node.set_param("height", height);
-
-        let gst_to_cu = g
-            .get_node_mut(g.get_node_id_by_name("gst_to_cu").unwrap())
-            .unwrap();
-        gst_to_cu.set_param("width", width);
-        gst_to_cu.set_param("height", height);
-
-        let calib_node = g
-            .get_node_mut(g.get_node_id_by_name("calibrator").unwrap())
-            .unwrap();
-        calib_node.set_param("width", width);
-        calib_node.set_param("height", height);
+        let config = capture_config(dev_id, width, height);
 
         //{
         //    let mut app = AppBuilder::new()
@@ -416,6 +414,149 @@ impl Configurator {
         true
     }
 
+    /// Run the robot-to-camera extrinsic calibration procedure
+    ///
+    /// Tags are put at known positions relative to the robot, either by parking the robot at known
+    /// poses on the field or by measuring where the tags are from the robot. The camera's offsets
+    /// are solved for from every capture, instead of being measured by hand.
+    ///
+    /// Returns `true` if new offsets were saved to the camera's settings.
+    pub fn configure_cam_extrinsics(&mut self) -> Result<bool> {
+        let cam_index = Select::new()
+            .with_prompt("Select a camera to find the offsets of")
+            .items(self.discovered_cams.values().filter_map(|v| v.to_owned()))
+            .default(0)
+            .interact()?;
+        let (dev_id, Some(cam_id)) = self.discovered_cams.get_index(cam_index).unwrap() else {
+            panic!();
+        };
+        let cam = self.c.cameras.get_mut(cam_id).unwrap();
+        let (Some(width), Some(height), Some(calib)) = (cam.width, cam.height, &cam.calib) else {
+            println!("   > {cam_id} needs to be calibrated first");
+            return Ok(false);
+        };
+        let mut calibrator = ExtrinsicCalibrator::new(calib.inner_model());
+
+        let mode = Select::new()
+            .with_prompt("Where are the tags?")
+            .item("Robot at known poses on the field")
+            .item("Tags at measured positions relative to the robot")
+            .default(0)
+            .interact()?;
+        let layout = if mode == 0 {
+            Some(FieldLayouts::load()?.tags().clone())
+        } else {
+            None
+        };
+
+        let pathbuf = PathBuf::from_str("configurator.copper").unwrap();
+        let copper_ctx = basic_copper_setup(pathbuf.as_path(), None, true, None).unwrap();
+        let config = capture_config(dev_id, width, height);
+
+        let mut app = AppBuilder::new()
+            .with_context(&copper_ctx)
+            .with_config(config)
+            .build()
+            .unwrap();
+        app.start_all_tasks().unwrap();
+
+        loop {
+            let next = Select::new()
+                .with_prompt(format!("{} captures so far", calibrator.captures()))
+                .item("Capture")
+                .item("Solve")
+                .default(0)
+                .interact()?;
+            if next == 1 {
+                break;
+            }
+
+            let tags = match &layout {
+                Some(layout) => {
+                    println!("Robot pose on the field");
+                    let x: f64 = Input::new().with_prompt(" |- X (m)").interact_text()?;
+                    let y: f64 = Input::new().with_prompt(" |- Y (m)").interact_text()?;
+                    let heading: f64 = Input::new()
+                        .with_prompt(" '- Heading (deg)")
+                        .interact_text()?;
+
+                    tags_from_field_pose(layout, x, y, heading)
+                }
+                None => {
+                    let mut tags = HashMap::new();
+                    loop {
+                        let id: String = Input::new()
+                            .with_prompt("Tag ID (leave empty when done)")
+                            .allow_empty(true)
+                            .interact_text()?;
+                        if id.is_empty() {
+                            break;
+                        }
+
+                        println!("Tag position relative to the robot");
+                        let x: f64 = Input::new().with_prompt(" |- X (m)").interact_text()?;
+                        let y: f64 = Input::new().with_prompt(" |- Y (m)").interact_text()?;
+                        let z: f64 = Input::new().with_prompt(" |- Z (m)").interact_text()?;
+                        let yaw: f64 = Input::new()
+                            .with_prompt(" '- Facing (deg, 180 is facing the robot)")
+                            .interact_text()?;
+
+                        tags.insert(id.parse()?, tag_from_robot_offset(x, y, z, yaw));
+                    }
+                    tags
+                }
+            };
+
+            calibrator.clear();
+            let progress = ProgressBar::new(FRAMES_PER_CAPTURE as u64);
+            // Don't wait forever if the camera can't see any tags
+            for _ in 0..FRAMES_PER_CAPTURE * 20 {
+                app.run_one_iteration().unwrap();
+                progress.set_position(calibrator.process() as u64);
+                if progress.position() >= FRAMES_PER_CAPTURE as u64 {
+                    break;
+                }
+            }
+            progress.finish_and_clear();
+
+            let n_tags = calibrator.capture(&tags);
+            println!("   > captured {n_tags} tags");
+        }
+
+        app.stop_all_tasks().unwrap();
+
+        let Some(solution) = calibrator.solve() else {
+            println!("   > failed to solve for the offsets");
+            return Ok(false);
+        };
+
+        println!("Reprojection error");
+        for (i, rms) in solution.capture_rms.iter().enumerate() {
+            println!(" |- Capture {i}: {rms:.2} px");
+        }
+        println!(" '- Overall: {:.2} px", solution.rms);
+
+        let offsets = solution.offsets;
+        println!("Camera offsets");
+        println!(" |- Translation X: {:.4}", offsets.x);
+        println!(" |- Translation Y: {:.4}", offsets.y);
+        println!(" |- Translation Z: {:.4}", offsets.z);
+        println!(" |- Roll: {:.3}", offsets.roll);
+        println!(" |- Pitch: {:.3}", offsets.pitch);
+        println!(" '- Yaw: {:.3}", offsets.yaw);
+
+        if !Confirm::new()
+            .with_prompt("Save these offsets?")
+            .default(true)
+            .interact()?
+        {
+            return Ok(false);
+        }
+        cam.cam_offsets = Some(offsets);
+
+        Ok(true)
+    }
+
     pub fn configure_cam_id(&mut self, camera_index: usize) -> Result<()> {
         let cam_config = self.cam_by_dev_index_mut(camera_index).unwrap();
 
@@ -592,6 +733,38 @@ impl Configurator {
     }
 }
 
+/// Set up the calibration Copper config to grab frames from a camera
+fn capture_config(dev_id: &str, width: u32, height: u32) -> CuConfig {
+    let mut config: CuConfig = read_configuration_str(
+        include_str!("../../../config/calibration.ron").to_owned(),
+        None,
+    )
+    .unwrap();
+
+    let g = config.get_graph_mut(None).unwrap();
+
+    let cam_node = g
+        .get_node_mut(g.get_node_id_by_name("camera").unwrap())
+        .unwrap();
+    cam_node.set_param("id", dev_id.to_owned());
+    cam_node.set_param("width", width);
+    cam_node.set_param("height", height);
+
+    let gst_to_cu = g
+        .get_node_mut(g.get_node_id_by_name("gst_to_cu").unwrap())
+        .unwrap();
+    gst_to_cu.set_param("width", width);
+    gst_to_cu.set_param("height", height);
+
+    let calib_node = g
+        .get_node_mut(g.get_node_id_by_name("calibrator").unwrap())
+        .unwrap();
+    calib_node.set_param("width", width);
+    calib_node.set_param("height", height);
+
+    config
+}
+
 /// Chalkydri configurator
 #[derive(clap::Parser)]
 #[command(version, about, long_about = None)]
@@ -599,6 +772,8 @@ pub enum Command {
     Configure,
     Generate,
     Calibrate(CmdCalibrate),
+    /// Find where a camera is on the robot from tags at known positions
+    Extrinsics,
 }
 
 #[derive(clap::Args)]
@@ -645,6 +820,15 @@ fn main() -> Result<()> {
             config.save_cuconfig();
             config.save();
         }
+        Command::Extrinsics => {
+            config.find_cameras();
+            config.refresh_cameras();
+
+            if config.configure_cam_extrinsics()? {
+                config.save_cuconfig();
+                config.save();
+            }
+        }
         Command::Generate => {
             config.save_cuconfig();
         }
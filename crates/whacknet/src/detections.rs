@@ -0,0 +1,116 @@
+//!
+//! Per-tag detections
+//!
+//! Measurements only carry the pose solved from every tag a camera saw. Robot code that wants to do
+//! its own targeting or fusion can get each tag on its own too, in a detections packet: a small
+//! header, followed by up to [`MAX_DETECTIONS`] tags. Even full, it fits in one datagram.
+//!
+
+use bytemuck::{Pod, Zeroable};
+
+/// Most tags a single detections packet can carry
+pub const MAX_DETECTIONS: usize = 16;
+
+/// One tag a camera saw
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct TagObservation {
+    pub id: u32,
+    /// Which family `id` is in, since IDs are only unique within a family
+    ///
+    /// 0 is tag36h11, 1 tag25h9, 2 tag16h5, 3 tagCircle21h7, 4 tagCircle49h12, 5 tagCustom48h12,
+    /// 6 tagStandard41h12, and 7 tagStandard52h13.
+    pub family: u8,
+    /// Reserved for future use
+    pub _reserved: [u8; 3],
+    /// Corners in pixel coordinates, counter-clockwise from the tag's bottom left corner
+    pub corners: [[f32; 2]; 4],
+    /// Where the tag's center is in the camera's frame (x right, y down, z forward, in meters)
+    pub translation: [f32; 3],
+    /// Which way the tag faces in the camera's frame, as a quaternion (w, x, y, z)
+    pub rotation: [f32; 4],
+    /// How close the next best solution came to this one, from 0 (unambiguous) to 1 (no idea which
+    /// is right)
+    pub ambiguity: f32,
+}
+
+/// What comes before the tags in a detections packet
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub(crate) struct DetectionsHeader {
+    /// Same as `VisionMeasurement::ts`
+    pub ts: u64,
+    pub camera_id: u8,
+    /// Same as `VisionMeasurement::flags`
+    pub flags: u8,
+    /// How many tags follow
+    pub tag_count: u8,
+    /// Reserved for future use
+    pub _reserved: [u8; 5],
+}
+
+/// Encode the payload of a detections packet
+///
+/// Only the first [`MAX_DETECTIONS`] tags are sent.
+pub(crate) fn encode(header: DetectionsHeader, tags: &[TagObservation]) -> Vec<u8> {
+    let tags = &tags[..tags.len().min(MAX_DETECTIONS)];
+    let header = DetectionsHeader {
+        tag_count: tags.len() as u8,
+        ..header
+    };
+
+    let mut buf = bytemuck::bytes_of(&header).to_vec();
+    buf.extend_from_slice(bytemuck::cast_slice(tags));
+
+    buf
+}
+
+/// Decode the payload of a detections packet
+///
+/// Returns `None` if the payload doesn't have as many tags as the header says.
+pub(crate) fn decode(payload: &[u8]) -> Option<(DetectionsHeader, Vec<TagObservation>)> {
+    let header: DetectionsHeader =
+        bytemuck::pod_read_unaligned(payload.get(..size_of::<DetectionsHeader>())?);
+    let tags = &payload[size_of::<DetectionsHeader>()..];
+
+    let tag_count = header.tag_count as usize;
+    if tag_count > MAX_DETECTIONS || tags.len() != tag_count * size_of::<TagObservation>() {
+        return None;
+    }
+    let tags = tags
+        .chunks_exact(size_of::<TagObservation>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect();
+
+    Some((header, tags))
+}
+
+#[test]
+fn detections_fit_in_a_datagram() {
+    let tags = vec![
+        TagObservation {
+            id: 7,
+            family: 2,
+            translation: [0.1, -0.2, 3.0],
+            rotation: [1.0, 0.0, 0.0, 0.0],
+            ambiguity: 0.05,
+            ..Default::default()
+        };
+        MAX_DETECTIONS + 2
+    ];
+    let header = DetectionsHeader {
+        ts: 1234,
+        camera_id: 2,
+        ..Default::default()
+    };
+
+    let payload = encode(header, &tags);
+    // Leave room for the frame header in a typical 1472 byte UDP payload
+    assert!(payload.len() + crate::frame::HEADER_LEN <= 1472);
+
+    let (header, decoded) = decode(&payload).unwrap();
+    assert_eq!(header.camera_id, 2);
+    assert_eq!(header.tag_count as usize, MAX_DETECTIONS);
+    assert_eq!(decoded, tags[..MAX_DETECTIONS]);
+    assert_eq!(decode(&payload[..payload.len() - 1]), None);
+}
@@ -0,0 +1,337 @@
+//!
+//! Packet framing
+//!
+//! Every packet we send starts with a small header, so the RIO can tell our packets apart from
+//! anything else on the port, reject ones from a build with a different layout, and notice when
+//! packets go missing. All of it is little-endian:
+//!
+//! | Bytes  | Field                                                  |
+//! |--------|--------------------------------------------------------|
+//! | 0..2   | Magic, `b"WN"`                                         |
+//! | 2      | Protocol version                                       |
+//! | 3      | Packet kind                                            |
+//! | 4..8   | Sequence number, counted separately by each camera     |
+//! | 8..12  | CRC-32 (IEEE) of bytes 0..8 followed by the payload    |
+//! | 12..   | Payload                                                |
+//!
+//! The CRC is the same one as Java's `java.util.zip.CRC32`.
+//!
+
+use std::fmt;
+
+/// Marks the start of every packet
+pub const MAGIC: [u8; 2] = *b"WN";
+/// Bumped whenever the header or any payload's layout changes
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Size of the header in bytes
+pub const HEADER_LEN: usize = 12;
+
+/// How far back a sequence number can be before we assume the sender restarted, rather than the
+/// packet just showing up late
+const REORDER_WINDOW: u32 = 64;
+
+/// What's in a packet's payload
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketKind {
+    /// A pose estimate, see `VisionMeasurement`
+    Measurement = 0x01,
+    /// The robot's state from the RIO, see [`RobotState`](crate::RobotState)
+    RobotState = 0x02,
+    /// A coprocessor asking for the RIO's time, see [`crate::TimeSync`]
+    TimeSyncRequest = 0x03,
+    /// The RIO's reply to a [`Self::TimeSyncRequest`]
+    TimeSyncResponse = 0x04,
+    /// Every tag a camera saw, see [`crate::TagObservation`]
+    Detections = 0x05,
+    /// How a coprocessor is doing, see [`crate::Health`]
+    Health = 0x06,
+}
+impl TryFrom<u8> for PacketKind {
+    type Error = FrameError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0x01 => Ok(Self::Measurement),
+            0x02 => Ok(Self::RobotState),
+            0x03 => Ok(Self::TimeSyncRequest),
+            0x04 => Ok(Self::TimeSyncResponse),
+            0x05 => Ok(Self::Detections),
+            0x06 => Ok(Self::Health),
+            _ => Err(FrameError::UnknownKind(kind)),
+        }
+    }
+}
+
+/// Why a packet couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Shorter than the header
+    TooShort,
+    /// Doesn't start with [`MAGIC`], so it probably isn't ours
+    BadMagic,
+    /// Sent with a different protocol version
+    UnsupportedVersion(u8),
+    /// Has a kind we don't know about
+    UnknownKind(u8),
+    /// The CRC doesn't match, so it was corrupted along the way
+    BadChecksum,
+}
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "packet is too short"),
+            Self::BadMagic => write!(f, "packet doesn't start with the whacknet magic"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "packet has protocol version {version}, expected {PROTOCOL_VERSION}"
+            ),
+            Self::UnknownKind(kind) => write!(f, "packet has unknown kind {kind:#04x}"),
+            Self::BadChecksum => write!(f, "packet checksum doesn't match"),
+        }
+    }
+}
+impl std::error::Error for FrameError {}
+
+/// A decoded packet header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: PacketKind,
+    pub sequence: u32,
+}
+
+/// Put a header in front of a payload
+pub fn encode(kind: PacketKind, sequence: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(kind as u8);
+    buf.extend_from_slice(&sequence.to_le_bytes());
+
+    let crc = crc32(&[&buf, payload]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(payload);
+
+    buf
+}
+
+/// Check a packet's header, and split it from the payload
+pub fn decode(buf: &[u8]) -> Result<(Header, &[u8]), FrameError> {
+    if buf.len() < HEADER_LEN {
+        return Err(FrameError::TooShort);
+    }
+    let (header, payload) = buf.split_at(HEADER_LEN);
+
+    if header[0..2] != MAGIC {
+        return Err(FrameError::BadMagic);
+    }
+    if header[2] != PROTOCOL_VERSION {
+        return Err(FrameError::UnsupportedVersion(header[2]));
+    }
+    let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if crc != crc32(&[&header[0..8], payload]) {
+        return Err(FrameError::BadChecksum);
+    }
+
+    Ok((
+        Header {
+            kind: header[3].try_into()?,
+            sequence: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        },
+        payload,
+    ))
+}
+
+/// Lookup table for CRC-32 with the reflected IEEE polynomial
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 of several chunks of bytes, one after another
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc = (crc >> 8) ^ CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize];
+    }
+
+    !crc
+}
+
+/// Keeps track of one sender's sequence numbers, to measure packet loss
+#[derive(Debug, Default, Clone)]
+pub struct SequenceTracker {
+    /// The sequence number we expect next
+    next: Option<u32>,
+    /// Which of the `REORDER_WINDOW` sequence numbers before `next` we counted as lost
+    ///
+    /// Bit `i` is `next - 1 - i`.
+    missing: u64,
+    received: u64,
+    lost: u64,
+}
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a packet's sequence number
+    ///
+    /// Returns `false` if it showed up after a newer packet did, or is a duplicate. Duplicates
+    /// aren't counted as received.
+    ///
+    /// A sender that restarts far enough back is noticed here, but one that ends up within
+    /// `REORDER_WINDOW` of where it left off looks just like late packets. Call [`Self::restart`]
+    /// when the sender is known to have restarted.
+    pub fn record(&mut self, sequence: u32) -> bool {
+        let Some(next) = self.next else {
+            self.received += 1;
+            self.next = Some(sequence.wrapping_add(1));
+            return true;
+        };
+
+        let behind = next.wrapping_sub(sequence);
+        if behind == 0 {
+            self.received += 1;
+            self.missing <<= 1;
+            self.next = Some(sequence.wrapping_add(1));
+            true
+        } else if behind > REORDER_WINDOW && behind <= u32::MAX / 2 {
+            // So far back that the sender must have restarted
+            self.received += 1;
+            self.missing = 0;
+            self.next = Some(sequence.wrapping_add(1));
+            true
+        } else if behind <= REORDER_WINDOW {
+            let bit = 1 << (behind - 1);
+            if self.missing & bit != 0 {
+                // We counted it as lost when the packets after it arrived
+                self.missing &= !bit;
+                self.received += 1;
+                self.lost = self.lost.saturating_sub(1);
+            }
+            false
+        } else {
+            // Everything in between went missing
+            let gap = sequence.wrapping_sub(next);
+            self.received += 1;
+            self.lost += gap as u64;
+            self.missing = self.missing.checked_shl(gap + 1).unwrap_or(0)
+                | (1u64.checked_shl(gap).unwrap_or(0).wrapping_sub(1) << 1);
+            self.next = Some(sequence.wrapping_add(1));
+            true
+        }
+    }
+
+    /// Start over, because the sender restarted
+    ///
+    /// Its next packet is taken as new, whatever its sequence number. The totals are kept.
+    pub fn restart(&mut self) {
+        self.next = None;
+        self.missing = 0;
+    }
+
+    /// How many packets have been received
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// How many packets have gone missing
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Fraction of packets that have gone missing
+    pub fn loss(&self) -> f64 {
+        let total = self.received + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f64 / total as f64
+        }
+    }
+}
+
+#[test]
+fn frame_roundtrip() {
+    let payload = [1, 2, 3, 4, 5];
+    let buf = encode(PacketKind::Measurement, 42, &payload);
+    assert_eq!(buf.len(), HEADER_LEN + payload.len());
+
+    let (header, decoded) = decode(&buf).unwrap();
+    assert_eq!(header.kind, PacketKind::Measurement);
+    assert_eq!(header.sequence, 42);
+    assert_eq!(decoded, payload);
+
+    let mut corrupted = buf.clone();
+    corrupted[HEADER_LEN + 2] ^= 0x10;
+    assert_eq!(decode(&corrupted), Err(FrameError::BadChecksum));
+
+    let mut old = buf.clone();
+    old[2] = 0;
+    assert_eq!(decode(&old), Err(FrameError::UnsupportedVersion(0)));
+    assert_eq!(decode(&buf[..4]), Err(FrameError::TooShort));
+}
+
+#[test]
+fn crc_matches_ieee() {
+    assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+}
+
+#[test]
+fn sequence_loss() {
+    let mut tracker = SequenceTracker::new();
+    for sequence in [1000, 1001, 1003, 1004, 1002, 1005] {
+        tracker.record(sequence);
+    }
+    assert_eq!(tracker.received(), 6);
+    assert_eq!(tracker.lost(), 0);
+
+    // 1006 and 1007 never show up, then the sender restarts
+    assert!(tracker.record(1008));
+    assert_eq!(tracker.lost(), 2);
+    assert!(tracker.record(0));
+    assert!(tracker.record(1));
+    assert_eq!(tracker.lost(), 2);
+}
+
+#[test]
+fn sequence_tracker_ignores_duplicates() {
+    let mut tracker = SequenceTracker::new();
+    for sequence in [10, 11, 11, 13, 13, 12, 12, 11] {
+        tracker.record(sequence);
+    }
+    assert_eq!(tracker.received(), 4);
+    assert_eq!(tracker.lost(), 0);
+
+    // A huge gap loses the whole window, and only the missing packets can come back
+    assert!(tracker.record(200));
+    assert_eq!(tracker.lost(), 186);
+    assert!(!tracker.record(199));
+    assert!(!tracker.record(199));
+    assert!(!tracker.record(200));
+    assert_eq!(tracker.received(), 6);
+    assert_eq!(tracker.lost(), 185);
+
+    // Once we know the sender restarted, it can start over anywhere
+    tracker.restart();
+    assert!(tracker.record(195));
+    assert!(tracker.record(196));
+    assert_eq!(tracker.received(), 8);
+    assert_eq!(tracker.lost(), 185);
+}
@@ -0,0 +1,180 @@
+//!
+//! Gyro readings from the RIO
+//!
+//! Frames are usually 50+ ms old by the time they're solved, so the latest heading is wrong
+//! whenever the robot is spinning. We keep a short history of readings instead, and interpolate the
+//! heading at the moment the frame was captured.
+//!
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+/// How many readings to keep around (a bit over a second at 50 Hz)
+const HISTORY_LEN: usize = 64;
+
+/// A single gyro reading
+#[derive(Debug, Clone, Copy)]
+struct GyroSample {
+    /// Heading in radians
+    angle: f64,
+    /// When we received it (µs since the history's epoch)
+    received: i64,
+    /// When the RIO sent it (µs in the RIO's timebase), if it told us
+    rio_ts: Option<u64>,
+}
+
+/// A ring buffer of timestamped gyro readings
+#[derive(Debug)]
+pub(crate) struct GyroHistory {
+    epoch: Instant,
+    samples: VecDeque<GyroSample>,
+}
+impl GyroHistory {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            samples: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    /// Parse a gyro datagram
+    ///
+    /// The heading comes first as a little-endian `f64`. It can optionally be followed by the RIO's
+    /// timestamp as a little-endian `u64` in microseconds, which takes network jitter out of the
+    /// picture.
+    pub fn parse(buf: &[u8]) -> Option<(f64, Option<u64>)> {
+        let angle = f64::from_le_bytes(buf.get(0..8)?.try_into().ok()?);
+        let rio_ts = buf
+            .get(8..16)
+            .map(|ts| u64::from_le_bytes(ts.try_into().unwrap()));
+
+        Some((angle, rio_ts))
+    }
+
+    /// Add a reading
+    pub fn push(&mut self, angle: f64, rio_ts: Option<u64>, received_at: Instant) {
+        // The RIO must have rebooted, so none of the old timestamps line up anymore
+        if let (Some(ts), Some(last_ts)) = (rio_ts, self.samples.back().and_then(|s| s.rio_ts))
+            && ts < last_ts
+        {
+            self.samples.clear();
+        }
+
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(GyroSample {
+            angle,
+            received: self.micros(received_at),
+            rio_ts,
+        });
+    }
+
+    /// Get the latest heading
+    pub fn latest(&self) -> Option<f64> {
+        self.samples.back().map(|sample| sample.angle)
+    }
+
+    /// Get how long ago the latest reading arrived
+    pub fn latest_age(&self) -> Option<Duration> {
+        let received = self.samples.back()?.received;
+        let age = self.micros(Instant::now()) - received;
+
+        Some(Duration::from_micros(age.max(0) as u64))
+    }
+
+    /// Get the heading at an instant
+    ///
+    /// Readings on either side of `at` are interpolated. If `at` is newer than the latest reading,
+    /// the latest heading is used as-is. Returns `None` if `at` is older than anything we've kept.
+    pub fn angle_at(&self, at: Instant) -> Option<f64> {
+        let at = self.micros(at);
+        let offset = self.rio_offset();
+
+        let mut newer: Option<(i64, f64)> = None;
+        for sample in self.samples.iter().rev() {
+            let t = Self::sample_time(sample, offset);
+            if t <= at {
+                let Some((newer_t, newer_angle)) = newer else {
+                    return Some(sample.angle);
+                };
+
+                let frac = (at - t) as f64 / (newer_t - t).max(1) as f64;
+                let delta = (newer_angle - sample.angle + PI).rem_euclid(2.0 * PI) - PI;
+
+                return Some(sample.angle + delta * frac);
+            }
+            newer = Some((t, sample.angle));
+        }
+
+        None
+    }
+
+    /// Best guess at the offset from the RIO's clock to ours (µs)
+    ///
+    /// Network delay only ever makes readings arrive later, so the smallest difference between
+    /// receive time and send time is the closest to the real offset. Only looking at the readings
+    /// we've kept lets the estimate follow the clocks as they drift apart.
+    fn rio_offset(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .filter_map(|sample| Some(sample.received - sample.rio_ts? as i64))
+            .min()
+    }
+
+    /// When a reading was taken, in our timebase (µs)
+    fn sample_time(sample: &GyroSample, offset: Option<i64>) -> i64 {
+        match (sample.rio_ts, offset) {
+            (Some(ts), Some(offset)) => ts as i64 + offset,
+            _ => sample.received,
+        }
+    }
+
+    /// Turn an instant into µs since the epoch
+    fn micros(&self, instant: Instant) -> i64 {
+        match instant.checked_duration_since(self.epoch) {
+            Some(since) => since.as_micros() as i64,
+            None => -(self.epoch.duration_since(instant).as_micros() as i64),
+        }
+    }
+}
+
+#[test]
+fn gyro_interpolation() {
+    let mut history = GyroHistory::new();
+    let start = history.epoch + Duration::from_secs(1);
+
+    history.push(0.0, None, start);
+    history.push(0.2, None, start + Duration::from_millis(20));
+
+    let angle = history.angle_at(start + Duration::from_millis(5)).unwrap();
+    assert!((angle - 0.05).abs() < 1e-9);
+    assert_eq!(history.angle_at(start + Duration::from_secs(1)), Some(0.2));
+    assert_eq!(history.angle_at(start - Duration::from_millis(1)), None);
+}
+
+#[test]
+fn gyro_interpolation_wraps() {
+    let mut history = GyroHistory::new();
+    let start = history.epoch + Duration::from_secs(1);
+
+    history.push(PI - 0.1, None, start);
+    history.push(-PI + 0.1, None, start + Duration::from_millis(20));
+
+    let angle = history.angle_at(start + Duration::from_millis(10)).unwrap();
+    assert!((angle - PI).abs() < 1e-9);
+}
+
+#[test]
+fn gyro_rio_timestamps_remove_jitter() {
+    let mut history = GyroHistory::new();
+    let start = history.epoch + Duration::from_secs(1);
+
+    // Sent exactly 20 ms apart, but the second one got held up on the network
+    history.push(0.0, Some(1_000_000), start);
+    history.push(0.2, Some(1_020_000), start + Duration::from_millis(35));
+
+    let angle = history.angle_at(start + Duration::from_millis(10)).unwrap();
+    assert!((angle - 0.1).abs() < 1e-9);
+}
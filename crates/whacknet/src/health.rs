@@ -0,0 +1,292 @@
+//!
+//! Coprocessor health
+//!
+//! Every coprocessor sends the RIO a health packet once a second, so robot code can tell "no tags
+//! visible" from "camera unplugged" from "coprocessor crashed", and warn the drivers. The payload is
+//! a small header, one entry per camera, then the software version as UTF-8.
+//!
+
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "system-stats")]
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bytemuck::{Pod, Zeroable};
+#[cfg(feature = "system-stats")]
+use sysinfo::{Components, Disks, System};
+
+/// How often we send a health packet
+pub(crate) const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+/// A camera is offline if it hasn't produced a frame in this long
+const CAMERA_TIMEOUT: Duration = Duration::from_secs(1);
+/// How far back frames are counted towards a camera's frame rate
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// How one camera is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CameraHealth {
+    pub camera_id: u8,
+    /// Whether it's produced a frame recently
+    pub online: bool,
+    /// Frames processed per second
+    pub fps: f32,
+}
+
+/// How a coprocessor is doing
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Health {
+    /// How long Chalkydri has been running
+    pub uptime: Duration,
+    /// Every camera, including ones that never produced a frame
+    pub cameras: Vec<CameraHealth>,
+    /// Temperature of the hottest sensor (°C), if there are any
+    ///
+    /// The stats are only collected when whacknet is built with `system-stats`, otherwise they
+    /// are all empty.
+    pub cpu_temp: Option<f32>,
+    /// How busy the CPU is, from 0 (idle) to 1 (every core busy)
+    pub cpu_load: f32,
+    /// Free space on the root filesystem (bytes)
+    pub disk_free: u64,
+    /// Chalkydri's version
+    pub version: String,
+}
+
+/// What comes before the cameras in a health packet
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+struct HealthHeader {
+    /// Uptime (ms)
+    uptime: u64,
+    /// Free disk space (bytes)
+    disk_free: u64,
+    /// CPU temperature (°C), or NaN if unknown
+    cpu_temp: f32,
+    /// CPU load (0-1)
+    cpu_load: f32,
+    /// How many cameras follow
+    camera_count: u8,
+    /// How many bytes the version takes up, after the cameras
+    version_len: u8,
+    /// Reserved for future use
+    _reserved: [u8; 6],
+}
+
+/// One camera in a health packet
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+struct CameraHealthPayload {
+    camera_id: u8,
+    online: u8,
+    /// Reserved for future use
+    _reserved: [u8; 2],
+    fps: f32,
+}
+
+impl Health {
+    /// Encode the payload of a health packet
+    ///
+    /// Only the first 255 cameras and 255 bytes of the version are sent.
+    pub fn encode(&self) -> Vec<u8> {
+        let cameras = &self.cameras[..self.cameras.len().min(u8::MAX as usize)];
+        let mut version_len = self.version.len().min(u8::MAX as usize);
+        while !self.version.is_char_boundary(version_len) {
+            version_len -= 1;
+        }
+
+        let header = HealthHeader {
+            uptime: self.uptime.as_millis() as u64,
+            disk_free: self.disk_free,
+            cpu_temp: self.cpu_temp.unwrap_or(f32::NAN),
+            cpu_load: self.cpu_load,
+            camera_count: cameras.len() as u8,
+            version_len: version_len as u8,
+            ..Default::default()
+        };
+
+        let mut buf = bytemuck::bytes_of(&header).to_vec();
+        for camera in cameras {
+            let payload = CameraHealthPayload {
+                camera_id: camera.camera_id,
+                online: camera.online as u8,
+                fps: camera.fps,
+                ..Default::default()
+            };
+            buf.extend_from_slice(bytemuck::bytes_of(&payload));
+        }
+        buf.extend_from_slice(&self.version.as_bytes()[..version_len]);
+
+        buf
+    }
+
+    /// Decode the payload of a health packet
+    ///
+    /// Returns `None` if the payload doesn't match what the header says.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let header: HealthHeader =
+            bytemuck::pod_read_unaligned(payload.get(..size_of::<HealthHeader>())?);
+        let rest = &payload[size_of::<HealthHeader>()..];
+
+        let cameras_len = header.camera_count as usize * size_of::<CameraHealthPayload>();
+        if rest.len() != cameras_len + header.version_len as usize {
+            return None;
+        }
+        let (cameras, version) = rest.split_at(cameras_len);
+
+        Some(Self {
+            uptime: Duration::from_millis(header.uptime),
+            cameras: cameras
+                .chunks_exact(size_of::<CameraHealthPayload>())
+                .map(|camera| {
+                    let camera: CameraHealthPayload = bytemuck::pod_read_unaligned(camera);
+                    CameraHealth {
+                        camera_id: camera.camera_id,
+                        online: camera.online != 0,
+                        fps: camera.fps,
+                    }
+                })
+                .collect(),
+            cpu_temp: (!header.cpu_temp.is_nan()).then_some(header.cpu_temp),
+            cpu_load: header.cpu_load,
+            disk_free: header.disk_free,
+            version: String::from_utf8(version.to_vec()).ok()?,
+        })
+    }
+}
+
+/// Keeps track of when each camera produced a frame
+#[derive(Debug, Default)]
+pub(crate) struct CameraStats {
+    frames: BTreeMap<u8, VecDeque<Instant>>,
+}
+impl CameraStats {
+    /// Start tracking a camera, so it shows up as offline until it produces a frame
+    pub fn register(&mut self, camera_id: u8) {
+        self.frames.entry(camera_id).or_default();
+    }
+
+    /// Record that a camera produced a frame
+    pub fn frame(&mut self, camera_id: u8, at: Instant) {
+        let frames = self.frames.entry(camera_id).or_default();
+        frames.push_back(at);
+        while frames
+            .front()
+            .is_some_and(|first| at.duration_since(*first) > FPS_WINDOW)
+        {
+            frames.pop_front();
+        }
+    }
+
+    /// Get how every camera is doing
+    pub fn health(&self, now: Instant) -> Vec<CameraHealth> {
+        self.frames
+            .iter()
+            .map(|(camera_id, frames)| {
+                let recent = frames
+                    .iter()
+                    .filter(|at| now.saturating_duration_since(**at) <= FPS_WINDOW)
+                    .count();
+                CameraHealth {
+                    camera_id: *camera_id,
+                    online: frames
+                        .back()
+                        .is_some_and(|last| now.saturating_duration_since(*last) <= CAMERA_TIMEOUT),
+                    fps: recent as f32 / FPS_WINDOW.as_secs_f32(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads the temperature, load, and disk space
+#[cfg(feature = "system-stats")]
+pub(crate) struct SystemStats {
+    system: System,
+    components: Components,
+    disks: Disks,
+}
+#[cfg(feature = "system-stats")]
+impl SystemStats {
+    pub fn new() -> Self {
+        Self {
+            system: System::new(),
+            components: Components::new_with_refreshed_list(),
+            disks: Disks::new_with_refreshed_list(),
+        }
+    }
+
+    /// Get the CPU temperature, load, and free disk space
+    ///
+    /// The load is measured since the last call, so the first one is always 0.
+    pub fn sample(&mut self) -> (Option<f32>, f32, u64) {
+        self.system.refresh_cpu_usage();
+        self.components.refresh(false);
+        self.disks.refresh(false);
+
+        let cpu_temp = self
+            .components
+            .iter()
+            .filter_map(|component| component.temperature())
+            .filter(|temp| temp.is_finite())
+            .reduce(f32::max);
+        let cpu_load = self.system.global_cpu_usage() / 100.0;
+        let disk_free = self
+            .disks
+            .iter()
+            .find(|disk| disk.mount_point() == Path::new("/"))
+            .map(|disk| disk.available_space())
+            .unwrap_or_default();
+
+        (cpu_temp, cpu_load, disk_free)
+    }
+}
+
+/// Stand-in when built without `system-stats`, so the robot side doesn't need sysinfo
+#[cfg(not(feature = "system-stats"))]
+pub(crate) struct SystemStats;
+#[cfg(not(feature = "system-stats"))]
+impl SystemStats {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// No temperature, no load, and no disk space
+    pub fn sample(&mut self) -> (Option<f32>, f32, u64) {
+        (None, 0.0, 0)
+    }
+}
+
+#[test]
+fn health_roundtrip() {
+    let health = Health {
+        uptime: Duration::from_secs(90),
+        cameras: vec![
+            CameraHealth {
+                camera_id: 0,
+                online: true,
+                fps: 59.5,
+            },
+            CameraHealth {
+                camera_id: 1,
+                online: false,
+                fps: 0.0,
+            },
+        ],
+        cpu_temp: None,
+        cpu_load: 0.25,
+        disk_free: 12 << 30,
+        version: "0.1.0".to_owned(),
+    };
+    assert_eq!(Health::decode(&health.encode()), Some(health.clone()));
+    assert_eq!(Health::decode(&health.encode()[1..]), None);
+
+    let mut stats = CameraStats::default();
+    let start = Instant::now();
+    stats.register(3);
+    for i in 0..30 {
+        stats.frame(4, start + Duration::from_millis(i * 20));
+    }
+    let cameras = stats.health(start + Duration::from_millis(600));
+    assert!(!cameras[0].online);
+    assert_eq!(cameras[1].fps, 30.0);
+}
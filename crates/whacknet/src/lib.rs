@@ -1,17 +1,57 @@
 extern crate cu_bincode as bincode;
 
+mod detections;
+pub mod frame;
+mod gyro;
+mod health;
+mod outbox;
+mod receiver;
+mod state;
+mod sync;
+
 use bincode::{Decode, Encode};
 use bytemuck::{Pod, Zeroable};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::mpsc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{io, net::UdpSocket, sync::Arc};
 
-use chalkydri_core::prelude::RwLock;
+use chalkydri_core::prelude::{Cfg, RwLock, warn};
+use chalkydri_core::utils::gen_team_ip;
 use cu29::prelude::*;
 
+use crate::detections::DetectionsHeader;
+pub use crate::detections::{MAX_DETECTIONS, TagObservation};
+use crate::frame::{FrameError, PacketKind};
+use crate::gyro::GyroHistory;
+pub use crate::health::{CameraHealth, Health};
+use crate::health::{CameraStats, HEALTH_INTERVAL, SystemStats};
+use crate::outbox::{Outbox, Slotted};
+pub use crate::receiver::{Detections, HealthReport, Measurement, Packet, Receiver, RecvError};
+pub use crate::state::{Alliance, MatchPhase, RobotState};
+pub use crate::sync::TimeSync;
+use crate::sync::{Clock, SYNC_INTERVAL, SyncEstimator, SyncRequest, SyncResponse};
+
 const BIND_ADDR: &str = "0.0.0.0:0";
-const REMOTE_ADDR: &str = "10.45.33.2:7001";
+/// Port the RIO listens for measurements on
+const REMOTE_PORT: u16 = 7001;
+/// Port we listen for gyro readings on
+const GYRO_PORT: u16 = 7002;
+/// Port we listen for commands on
+const COMMAND_PORT: u16 = 7003;
+
+/// How long threads wait on a socket before checking whether they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait before trying to set up the measurement socket again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a gyro reading can be trusted after it's received
+///
+/// The RIO sends one every 20 ms, so this allows for a few dropped packets.
+const GYRO_TIMEOUT: Duration = Duration::from_millis(100);
 
 // The acutal positioning data from the code
 #[repr(C)]
@@ -37,7 +77,7 @@ pub struct VisionUncertainty {
     pub rot: f64,
 }
 
-/// This is what gets sent over the wire to rio. 64 bytes, just like minecraft...
+/// This is what gets sent over the wire to rio, after the header. 64 bytes, just like minecraft...
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
 struct VisionMeasurement {
@@ -45,14 +85,15 @@ struct VisionMeasurement {
     pub pose: RobotPose, // 24 bytes
     /// Our accurracy stdevs to send to the bot
     pub std_devs: VisionUncertainty, // 24 bytes
-    /// Timestamp (in micro secs)
+    /// Capture time on the RIO's clock with [`Self::TS_RIO_TIME`] set, otherwise how long ago the
+    /// frame was captured (in micro secs)
     ts: u64,
     /// Camera id
     camera_id: u8,
     /// Tag count
     tag_count: u8,
-    /// Reserved for future use
-    _reserved_1: u8,
+    /// Flags describing the rest of the measurement
+    flags: u8,
     /// Reserved for future use
     _reserved_2: u8,
     /// Reserved for future use
@@ -65,25 +106,195 @@ struct VisionMeasurement {
     _reserved_6: u8,
 }
 
+impl VisionMeasurement {
+    /// `ts` is the capture time on the RIO's clock, since our clocks are synced
+    const TS_RIO_TIME: u8 = 0x01;
+}
+
+/// Where measurements go, and which ports we listen on
+///
+/// [`CommBundle`] reads this from its resource config:
+///
+/// - `host`: the RIO's IP address or hostname, or `"localhost"` for a robot simulation running on
+///   the same machine
+/// - `team_number`: finds the RIO at `10.TE.AM.2` when there's no `host`
+/// - `port`, `gyro_port`, and `command_port`: default to 7001, 7002, and 7003
+///
+/// Without either of the first two, the Chalkydri config's `ntables_ip` or `team_number` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommConfig {
+    /// Where measurements are sent
+    pub remote: SocketAddr,
+    /// Port we listen for gyro readings on
+    pub gyro_port: u16,
+    /// Port we listen for commands on
+    pub command_port: u16,
+}
+impl CommConfig {
+    /// Talk to a RIO at `ip` on the default ports
+    pub fn new(ip: IpAddr) -> Self {
+        Self {
+            remote: SocketAddr::new(ip, REMOTE_PORT),
+            gyro_port: GYRO_PORT,
+            command_port: COMMAND_PORT,
+        }
+    }
+
+    /// Talk to a robot simulation running on the same machine
+    pub fn localhost() -> Self {
+        Self::new(Ipv4Addr::LOCALHOST.into())
+    }
+
+    /// Talk to a team's RIO
+    ///
+    /// Returns `None` if the team number can't be turned into an IP address.
+    pub fn team(team_number: u16) -> Option<Self> {
+        gen_team_ip(team_number).map(|ip| Self::new(Ipv4Addr::from(ip).into()))
+    }
+
+    /// Read the config from a resource config, falling back to the Chalkydri config
+    pub fn from_component_config(config: Option<&ComponentConfig>) -> CuResult<Self> {
+        let get_port = |key: &str, default: u16| -> CuResult<u16> {
+            match config {
+                Some(config) => Ok(config
+                    .get::<u16>(key)
+                    .map_err(|err| CuError::from(format!("invalid {key}: {err}")))?
+                    .unwrap_or(default)),
+                None => Ok(default),
+            }
+        };
+        let port = get_port("port", REMOTE_PORT)?;
+
+        let host = config
+            .map(|config| config.get::<String>("host"))
+            .transpose()
+            .map_err(|err| CuError::from(format!("invalid host: {err}")))?
+            .flatten();
+        let team_number = config
+            .map(|config| config.get::<u16>("team_number"))
+            .transpose()
+            .map_err(|err| CuError::from(format!("invalid team_number: {err}")))?
+            .flatten();
+
+        let mut comm_config = match (host, team_number) {
+            (Some(host), _) => Self::resolve(&host, port)?,
+            (None, Some(team_number)) => Self::team(team_number)
+                .ok_or_else(|| CuError::from(format!("invalid team number: {team_number}")))?,
+            (None, None) => {
+                let (ntables_ip, team_number) = {
+                    let cfg = Cfg.read();
+                    (cfg.ntables_ip.clone(), cfg.team_number)
+                };
+
+                match ntables_ip {
+                    Some(host) => Self::resolve(&host, port)?,
+                    None => Self::team(team_number).unwrap_or_else(|| {
+                        warn!("no whacknet host or team number configured, using localhost");
+                        Self::localhost()
+                    }),
+                }
+            }
+        };
+        comm_config.remote.set_port(port);
+        comm_config.gyro_port = get_port("gyro_port", GYRO_PORT)?;
+        comm_config.command_port = get_port("command_port", COMMAND_PORT)?;
+
+        Ok(comm_config)
+    }
+
+    /// Look up a host
+    fn resolve(host: &str, port: u16) -> CuResult<Self> {
+        if host == "localhost" {
+            return Ok(Self::localhost());
+        }
+
+        let addr = (host, port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| CuError::from(format!("failed to resolve whacknet host '{host}'")))?;
+
+        Ok(Self::new(addr.ip()))
+    }
+}
+
+#[test]
+fn team_config() {
+    let config = CommConfig::team(4533).unwrap();
+    assert_eq!(config.remote, "10.45.33.2:7001".parse().unwrap());
+    assert_eq!(CommConfig::team(25_600), None);
+}
+
+/// Something waiting to be sent to the RIO
+enum Outgoing {
+    Measurement(VisionMeasurement),
+    Detections(DetectionsHeader, Vec<TagObservation>),
+}
+impl Slotted for Outgoing {
+    fn slot(&self) -> (PacketKind, u8) {
+        match self {
+            Self::Measurement(measurement) => (PacketKind::Measurement, measurement.camera_id),
+            Self::Detections(header, _) => (PacketKind::Detections, header.camera_id),
+        }
+    }
+
+    fn replaces(&self, pending: &Self) -> bool {
+        match (self, pending) {
+            // A heartbeat would throw away the only pose the RIO could have used
+            (Self::Measurement(measurement), Self::Measurement(pending)) => {
+                measurement.tag_count > 0 || pending.tag_count == 0
+            }
+            _ => true,
+        }
+    }
+}
+
 pub struct WhacknetClient {
     socket: Arc<UdpSocket>,
+    /// Sequence number of the last packet of each kind sent for each camera
+    sequences: HashMap<(PacketKind, u8), u32>,
 }
 impl WhacknetClient {
     /// Initialize a new whacknet client
-    pub fn new() -> io::Result<Self> {
+    pub fn new(remote: SocketAddr) -> io::Result<Self> {
         // Create and connect to server
         let socket = UdpSocket::bind(BIND_ADDR)?;
-        socket.connect(REMOTE_ADDR)?;
+        socket.connect(remote)?;
 
         Ok(Self {
             socket: Arc::new(socket),
+            sequences: HashMap::new(),
         })
     }
     /// Send a pose with std dev
-    pub fn send(&self, measurement: VisionMeasurement) -> io::Result<()> {
+    pub fn send(&mut self, measurement: VisionMeasurement) -> io::Result<()> {
         // Turn the measurement into raw bytes and send it over the UDP sock
-        let bytes = bytemuck::bytes_of(&measurement);
-        self.socket.send(bytes)?;
+        self.send_packet(
+            PacketKind::Measurement,
+            measurement.camera_id,
+            bytemuck::bytes_of(&measurement),
+        )
+    }
+
+    /// Send every tag a camera saw
+    fn send_detections(
+        &mut self,
+        header: DetectionsHeader,
+        tags: &[TagObservation],
+    ) -> io::Result<()> {
+        self.send_packet(
+            PacketKind::Detections,
+            header.camera_id,
+            &detections::encode(header, tags),
+        )
+    }
+
+    fn send_packet(&mut self, kind: PacketKind, camera_id: u8, payload: &[u8]) -> io::Result<()> {
+        // Each camera counts its own packets, so the RIO can tell which one is dropping them
+        let sequence = self.sequences.entry((kind, camera_id)).or_default();
+        *sequence = sequence.wrapping_add(1);
+
+        self.socket.send(&frame::encode(kind, *sequence, payload))?;
 
         Ok(())
     }
@@ -96,59 +307,428 @@ fn check_size() {
 
 // TODO: add a benchmark
 
+/// A command sent to the coprocessor over the command port
+///
+/// Every command is a single datagram: a one byte opcode, followed by the opcode's payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Switch every AprilTag pipeline over to the named field layout
+    SelectFieldLayout(String),
+    /// Ask which field layout is currently active
+    QueryFieldLayout,
+}
+impl Command {
+    const SELECT_FIELD_LAYOUT: u8 = 0x01;
+    const QUERY_FIELD_LAYOUT: u8 = 0x02;
+    /// Opcode of the reply carrying the active field layout's name
+    const ACTIVE_FIELD_LAYOUT: u8 = 0x81;
+
+    /// Parse a command datagram
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        match buf.split_first()? {
+            (&Self::SELECT_FIELD_LAYOUT, name) => {
+                let name = std::str::from_utf8(name).ok()?.trim();
+                (!name.is_empty()).then(|| Self::SelectFieldLayout(name.to_owned()))
+            }
+            (&Self::QUERY_FIELD_LAYOUT, _) => Some(Self::QueryFieldLayout),
+            _ => None,
+        }
+    }
+
+    /// Encode a command datagram
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::SelectFieldLayout(name) => {
+                let mut buf = vec![Self::SELECT_FIELD_LAYOUT];
+                buf.extend_from_slice(name.as_bytes());
+                buf
+            }
+            Self::QueryFieldLayout => vec![Self::QUERY_FIELD_LAYOUT],
+        }
+    }
+
+    /// Encode the reply to a field layout command
+    ///
+    /// An empty name means no field layout has been loaded yet.
+    pub fn encode_active_field_layout(name: Option<&str>) -> Vec<u8> {
+        let mut buf = vec![Self::ACTIVE_FIELD_LAYOUT];
+        buf.extend_from_slice(name.unwrap_or_default().as_bytes());
+        buf
+    }
+}
+
+#[test]
+fn command_roundtrip() {
+    for cmd in [
+        Command::SelectFieldLayout("welded".to_owned()),
+        Command::QueryFieldLayout,
+    ] {
+        assert_eq!(Command::parse(&cmd.encode()), Some(cmd));
+    }
+    assert_eq!(Command::parse(&[Command::SELECT_FIELD_LAYOUT]), None);
+    assert_eq!(Command::parse(&[]), None);
+}
+
+/// Which field layout has been asked for over the network, and which one is actually in use
+#[derive(Debug, Default)]
+struct FieldLayoutSelection {
+    /// Bumped every time a new layout is requested
+    generation: u64,
+    requested: Option<String>,
+    active: Option<String>,
+    /// The last generation someone was told about
+    replied: u64,
+    /// Whoever sent the last command, so they can be told when the switch goes through
+    reply_to: Option<SocketAddr>,
+}
+
+/// What's gone wrong in whacknet, if anything
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommStatus {
+    /// Why the socket measurements go out on couldn't be set up, until it can be
+    pub client_error: Option<String>,
+    /// Why we can't listen for the robot's state
+    pub gyro_error: Option<String>,
+    /// Why we can't listen for commands
+    pub command_error: Option<String>,
+    /// Why the last packet couldn't be sent, until one is
+    pub send_error: Option<String>,
+    /// How many packets have been sent
+    pub sent: u64,
+    /// How many packets were replaced by a newer one before they could be sent
+    pub dropped: u64,
+}
+
+/// Set one of the status's errors, logging it if it's new
+fn set_error(slot: &mut Option<String>, what: &str, err: Option<io::Error>) {
+    let err = err.map(|err| format!("{what}: {err}"));
+    if err != *slot {
+        if let Some(err) = &err {
+            warn!("{err}");
+        }
+        *slot = err;
+    }
+}
+
+/// Stops the background threads once the last [`Comm`] is dropped
+struct Shutdown {
+    running: Arc<AtomicBool>,
+    outbox: Arc<Outbox<Outgoing>>,
+}
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.outbox.close();
+    }
+}
+
 #[derive(Clone)]
 pub struct Comm {
     clients: Arc<RwLock<HashMap<u8, WhacknetClient>>>,
-    gyro_angle: Arc<RwLock<Option<f64>>>,
-    measurements_tx: Arc<mpsc::Sender<VisionMeasurement>>,
+    /// Recent gyro readings
+    gyro: Arc<RwLock<GyroHistory>>,
+    /// The latest robot state from the RIO, and when it arrived
+    robot_state: Arc<RwLock<Option<(RobotState, Instant)>>>,
+    /// Our clock, which is synced with the RIO's
+    clock: Clock,
+    time_sync: Arc<RwLock<SyncEstimator>>,
+    /// When each camera produced a frame, for the health packets
+    cameras: Arc<RwLock<CameraStats>>,
+    /// Packets waiting to be sent
+    outbox: Arc<Outbox<Outgoing>>,
+    status: Arc<RwLock<CommStatus>>,
+    field_layout: Arc<RwLock<FieldLayoutSelection>>,
+    /// `None` if the command port couldn't be bound
+    command_socket: Option<Arc<UdpSocket>>,
+    _shutdown: Arc<Shutdown>,
 }
 impl Comm {
     /// Initialize the communication handler thingie
-    pub fn new() -> Self {
-        let gyro_angle = Arc::new(RwLock::new(Some(0f64)));
+    ///
+    /// This doesn't fail. Anything that goes wrong shows up in [`Self::status`] instead, and the
+    /// background threads stop once every clone has been dropped.
+    pub fn new(config: CommConfig) -> Self {
+        let gyro = Arc::new(RwLock::new(GyroHistory::new()));
+        let robot_state = Arc::new(RwLock::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+        let status = Arc::new(RwLock::new(CommStatus::default()));
 
-        // Just putting the gyro value listener on its own thread
-        let gyro_angle_ = gyro_angle.clone();
+        // Just putting the robot state listener on its own thread
+        match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.gyro_port)).and_then(|socket| {
+            // Wake up every so often to check whether we should stop
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            Ok(socket)
+        }) {
+            Ok(gyro_socket) => {
+                let gyro_ = gyro.clone();
+                let robot_state_ = robot_state.clone();
+                let running_ = running.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 128];
+                    while running_.load(Ordering::Relaxed) {
+                        let Ok(bytes) = gyro_socket.recv(&mut buf) else {
+                            continue;
+                        };
+                        let received_at = Instant::now();
+
+                        match frame::decode(&buf[..bytes]) {
+                            Ok((header, payload)) if header.kind == PacketKind::RobotState => {
+                                if let Some(state) = RobotState::decode(payload) {
+                                    gyro_.write().push(
+                                        state.heading,
+                                        Some(state.rio_ts),
+                                        received_at,
+                                    );
+                                    *robot_state_.write() = Some((state, received_at));
+                                }
+                            }
+                            // Older robot code only sends the bare heading
+                            Err(FrameError::BadMagic | FrameError::TooShort) => {
+                                if let Some((angle, rio_ts)) = GyroHistory::parse(&buf[..bytes]) {
+                                    gyro_.write().push(angle, rio_ts, received_at);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+            Err(err) => set_error(
+                &mut status.write().gyro_error,
+                "failed to listen for the robot's state",
+                Some(err),
+            ),
+        }
+
+        let field_layout = Arc::new(RwLock::new(FieldLayoutSelection::default()));
+        let command_socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.command_port))
+            .and_then(|socket| {
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                Ok(Arc::new(socket))
+            }) {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                set_error(
+                    &mut status.write().command_error,
+                    "failed to listen for commands",
+                    Some(err),
+                );
+                None
+            }
+        };
+
+        // Listen for commands on another thread too
+        if let Some(command_socket_) = command_socket.clone() {
+            let field_layout_ = field_layout.clone();
+            let running_ = running.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 256];
+                while running_.load(Ordering::Relaxed) {
+                    let Ok((len, from)) = command_socket_.recv_from(&mut buf) else {
+                        continue;
+                    };
+
+                    let reply = match Command::parse(&buf[..len]) {
+                        Some(Command::SelectFieldLayout(name)) => {
+                            let mut selection = field_layout_.write();
+                            selection.generation += 1;
+                            selection.requested = Some(name);
+                            selection.reply_to = Some(from);
+
+                            // The pipelines will report back once they've switched
+                            None
+                        }
+                        Some(Command::QueryFieldLayout) => {
+                            Some(Command::encode_active_field_layout(
+                                field_layout_.read().active.as_deref(),
+                            ))
+                        }
+                        None => None,
+                    };
+
+                    if let Some(reply) = reply {
+                        command_socket_.send_to(&reply, from).ok();
+                    }
+                }
+            });
+        }
+
+        // The socket measurements go out on, once it's been set up
+        let socket: Arc<OnceLock<Arc<UdpSocket>>> = Arc::new(OnceLock::new());
+        let clock = Clock::new();
+        let time_sync = Arc::new(RwLock::new(SyncEstimator::new()));
+
+        // Keep our clock synced with the RIO's, over the same socket the measurements go out on
+        let socket_ = socket.clone();
+        let time_sync_ = time_sync.clone();
+        let running_ = running.clone();
         std::thread::spawn(move || {
-            let gyro_socket = UdpSocket::bind("0.0.0.0:7002").unwrap();
-
-            let mut buf = [0u8; 8];
-            loop {
-                match gyro_socket.recv(&mut buf) {
-                    Ok(_bytes) => {
-                        let mut guard = gyro_angle_.write();
-                        if guard.is_none() {
+            let mut buf = [0u8; 64];
+            let mut sequence = 0u32;
+            while running_.load(Ordering::Relaxed) {
+                let Some(sync_socket) = socket_.get() else {
+                    std::thread::sleep(SYNC_INTERVAL);
+                    continue;
+                };
+
+                sequence = sequence.wrapping_add(1);
+                let request = SyncRequest { t0: clock.now() };
+                let packet = frame::encode(
+                    PacketKind::TimeSyncRequest,
+                    sequence,
+                    bytemuck::bytes_of(&request),
+                );
+                sync_socket.send(&packet).ok();
+
+                // Wait out the rest of the interval for the reply
+                let deadline = Instant::now() + SYNC_INTERVAL;
+                while let Some(remaining) = deadline
+                    .checked_duration_since(Instant::now())
+                    .filter(|remaining| !remaining.is_zero())
+                {
+                    sync_socket.set_read_timeout(Some(remaining)).ok();
+                    let len = match sync_socket.recv(&mut buf) {
+                        Ok(len) => len,
+                        Err(err)
+                            if matches!(
+                                err.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            break;
+                        }
+                        // Nothing's listening on the RIO yet
+                        Err(_) => {
+                            std::thread::sleep(remaining);
                             break;
                         }
-                        *guard = Some(f64::from_le_bytes(buf));
+                    };
+                    let received_at = Instant::now();
+
+                    if let Ok((header, payload)) = frame::decode(&buf[..len])
+                        && header.kind == PacketKind::TimeSyncResponse
+                        && let Some(response) = sync::decode_payload::<SyncResponse>(payload)
+                    {
+                        time_sync_
+                            .write()
+                            .add(&response, clock.micros(received_at), received_at);
                     }
-                    Err(_err) => {}
+                }
+            }
+        });
+
+        let cameras = Arc::new(RwLock::new(CameraStats::default()));
+
+        // Let the RIO know we're alive, and how we're doing
+        let socket_ = socket.clone();
+        let cameras_ = cameras.clone();
+        let running_ = running.clone();
+        std::thread::spawn(move || {
+            let mut system = SystemStats::new();
+            let mut sequence = 0u32;
+            while running_.load(Ordering::Relaxed) {
+                if let Some(health_socket) = socket_.get() {
+                    let (cpu_temp, cpu_load, disk_free) = system.sample();
+                    let health = Health {
+                        uptime: Duration::from_micros(clock.now()),
+                        cameras: cameras_.read().health(Instant::now()),
+                        cpu_temp,
+                        cpu_load,
+                        disk_free,
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                    };
+
+                    sequence = sequence.wrapping_add(1);
+                    let packet = frame::encode(PacketKind::Health, sequence, &health.encode());
+                    health_socket.send(&packet).ok();
                 }
 
-                buf = [0u8; 8];
+                std::thread::sleep(HEALTH_INTERVAL);
             }
         });
 
-        let (tx, rx) = mpsc::channel();
-        let measurements_tx = Arc::new(tx);
+        let outbox = Arc::new(Outbox::new());
 
+        // Send measurements on yet another thread, so the pipelines never wait on the network
+        let socket_ = socket.clone();
+        let outbox_ = outbox.clone();
+        let status_ = status.clone();
+        let running_ = running.clone();
         std::thread::spawn(move || {
-            let client = WhacknetClient::new().expect("failed to initialize client");
-            loop {
-                while let Ok(measurement) = rx.recv() {
-                    client.send(measurement).ok();
+            let mut client = loop {
+                if !running_.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                match WhacknetClient::new(config.remote) {
+                    Ok(client) => {
+                        status_.write().client_error = None;
+                        socket_.set(client.socket.clone()).ok();
+                        break client;
+                    }
+                    // Usually the network isn't up yet
+                    Err(err) => {
+                        set_error(
+                            &mut status_.write().client_error,
+                            "failed to set up the measurement socket",
+                            Some(err),
+                        );
+                        std::thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            };
+
+            // Stops once the outbox is closed
+            while let Some(outgoing) = outbox_.pop(POLL_INTERVAL) {
+                let Some(outgoing) = outgoing else {
+                    continue;
+                };
+                let result = match outgoing {
+                    Outgoing::Measurement(measurement) => client.send(measurement),
+                    Outgoing::Detections(header, tags) => client.send_detections(header, &tags),
+                };
+
+                let mut status = status_.write();
+                match result {
+                    Ok(()) => {
+                        status.sent += 1;
+                        status.send_error = None;
+                    }
+                    Err(err) => set_error(
+                        &mut status.send_error,
+                        "failed to send to the RIO",
+                        Some(err),
+                    ),
                 }
             }
         });
 
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
-            gyro_angle,
-            measurements_tx,
+            gyro,
+            robot_state,
+            clock,
+            time_sync,
+            cameras,
+            outbox: outbox.clone(),
+            status,
+            field_layout,
+            command_socket,
+            _shutdown: Arc::new(Shutdown { running, outbox }),
+        }
+    }
+
+    /// Get what's gone wrong, if anything
+    pub fn status(&self) -> CommStatus {
+        CommStatus {
+            dropped: self.outbox.dropped(),
+            ..self.status.read().clone()
         }
     }
 
     /// Send a pose estimate to the RIO
+    ///
+    /// `ts` is how long ago the frame was captured (µs).
     pub fn publish(
         &self,
         cam_id: u8,
@@ -157,6 +737,8 @@ impl Comm {
         pose: RobotPose,
         std_devs: VisionUncertainty,
     ) {
+        let (ts, flags) = self.capture_ts(ts);
+
         // Pack up all the data in the struct
         let measurement = VisionMeasurement {
             pose,
@@ -164,24 +746,251 @@ impl Comm {
             camera_id: cam_id,
             tag_count,
             ts,
+            flags,
             ..Default::default()
         };
 
-        self.measurements_tx.send(measurement).unwrap();
+        self.outbox.push(Outgoing::Measurement(measurement));
+    }
+
+    /// Send every tag a camera saw to the RIO
+    ///
+    /// `ts` is how long ago the frame was captured (µs). Only the first [`MAX_DETECTIONS`] tags are
+    /// sent.
+    pub fn publish_detections(&self, cam_id: u8, ts: u64, tags: &[TagObservation]) {
+        let (ts, flags) = self.capture_ts(ts);
+        let header = DetectionsHeader {
+            ts,
+            camera_id: cam_id,
+            flags,
+            ..Default::default()
+        };
+
+        self.outbox
+            .push(Outgoing::Detections(header, tags.to_vec()));
+    }
+
+    /// Start reporting a camera's health
+    ///
+    /// It shows up as offline until [`Self::report_frame`] is called for it.
+    pub fn register_camera(&self, cam_id: u8) {
+        self.cameras.write().register(cam_id);
+    }
+
+    /// Record that a camera produced a frame, for its health
+    pub fn report_frame(&self, cam_id: u8) {
+        self.cameras.write().frame(cam_id, Instant::now());
+    }
+
+    /// Turn how long ago a frame was captured into what goes in a packet's `ts` and `flags`
+    fn capture_ts(&self, ts: u64) -> (u64, u8) {
+        // Once our clocks are synced, the RIO can have the capture time instead of guessing it
+        match self.time_sync() {
+            Some(sync) => {
+                let captured = self.clock.now().saturating_sub(ts) as i64 + sync.offset;
+                (captured.max(0) as u64, VisionMeasurement::TS_RIO_TIME)
+            }
+            None => (ts, 0),
+        }
     }
 
     /// Get the robot's heading from the gyro
+    ///
+    /// Returns `None` until the RIO has sent a reading, or if the latest one has gone stale.
     pub fn gyro_angle(&self) -> Option<f64> {
-        self.gyro_angle
-            .try_read()
-            .map(|ga| ga.expect("this should not be possible"))
+        let gyro = self.gyro.read();
+        gyro.latest_age()
+            .filter(|age| *age <= GYRO_TIMEOUT)
+            .and_then(|_| gyro.latest())
+    }
+
+    /// Get the robot's heading from the gyro, as it was `age` ago
+    ///
+    /// Use this with a frame's capture latency to get the heading the robot had when the frame was
+    /// exposed. Returns `None` if the gyro is stale, or if `age` is further back than we remember.
+    pub fn gyro_angle_at(&self, age: Duration) -> Option<f64> {
+        let now = Instant::now();
+        let gyro = self.gyro.read();
+        gyro.latest_age()
+            .filter(|latest_age| *latest_age <= GYRO_TIMEOUT)
+            .and_then(|_| gyro.angle_at(now.checked_sub(age)?))
+    }
+
+    /// Get how long ago the latest gyro reading arrived
+    pub fn gyro_age(&self) -> Option<Duration> {
+        self.gyro.read().latest_age()
+    }
+
+    /// Get how well our clock is synced with the RIO's
+    ///
+    /// Returns `None` until the RIO has answered, or if it's stopped answering.
+    pub fn time_sync(&self) -> Option<TimeSync> {
+        self.time_sync.read().estimate()
+    }
+
+    /// Get the latest robot state from the RIO, and how long ago it arrived
+    ///
+    /// Returns `None` until the RIO has sent one. Robot code that only sends the bare heading never
+    /// will.
+    pub fn robot_state(&self) -> Option<(RobotState, Duration)> {
+        self.robot_state
+            .read()
+            .map(|(state, received_at)| (state, received_at.elapsed()))
     }
 }
-impl Drop for Comm {
-    fn drop(&mut self) {
-        // Tells the gyro listener thread to exit
-        *self.gyro_angle.write() = None;
+impl Comm {
+    /// Get the field layout requested over the network, if it's newer than `seen`
+    ///
+    /// Returns the request's generation along with the layout name, so callers can remember
+    /// which requests they've already handled.
+    pub fn requested_field_layout(&self, seen: u64) -> Option<(u64, String)> {
+        let selection = self.field_layout.read();
+        if selection.generation > seen {
+            selection
+                .requested
+                .clone()
+                .map(|name| (selection.generation, name))
+        } else {
+            None
+        }
+    }
+
+    /// Record which field layout is actually in use, after handling request `generation`
+    ///
+    /// Whoever sent the request is told which layout ended up active, even if the switch failed.
+    pub fn set_active_field_layout(&self, generation: u64, name: &str) {
+        let mut selection = self.field_layout.write();
+        let changed = selection.active.as_deref() != Some(name);
+        selection.active = Some(name.to_owned());
+
+        // Every pipeline handles the same request, but the sender only needs to hear back once
+        if changed || generation > selection.replied {
+            selection.replied = selection.replied.max(generation);
+            if let Some(reply_to) = selection.reply_to
+                && let Some(command_socket) = &self.command_socket
+            {
+                let reply = Command::encode_active_field_layout(Some(name));
+                command_socket.send_to(&reply, reply_to).ok();
+            }
+        }
     }
+
+    /// Get the name of the field layout currently in use
+    pub fn active_field_layout(&self) -> Option<String> {
+        self.field_layout.read().active.clone()
+    }
+}
+#[test]
+fn select_field_layout_over_localhost() {
+    let mut config = CommConfig::localhost();
+    config.remote.set_port(47111);
+    config.gyro_port = 47112;
+    config.command_port = 47113;
+    let comm = Comm::new(config);
+
+    let robot = UdpSocket::bind("127.0.0.1:0").unwrap();
+    robot
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    robot
+        .send_to(
+            &Command::SelectFieldLayout("welded".to_owned()).encode(),
+            ("127.0.0.1", config.command_port),
+        )
+        .unwrap();
+
+    // The pipelines pick the request up on their next frame
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let (generation, name) = loop {
+        if let Some(request) = comm.requested_field_layout(0) {
+            break request;
+        }
+        assert!(Instant::now() < deadline, "request never arrived");
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    assert_eq!(name, "welded");
+    assert_eq!(comm.requested_field_layout(generation), None);
+
+    // Every pipeline reports back, but the robot only hears about it once
+    comm.set_active_field_layout(generation, "welded");
+    comm.set_active_field_layout(generation, "welded");
+    let mut buf = [0u8; 256];
+    let len = robot.recv(&mut buf).unwrap();
+    assert_eq!(
+        &buf[..len],
+        Command::encode_active_field_layout(Some("welded"))
+    );
+    robot
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    assert!(robot.recv(&mut buf).is_err());
+
+    robot
+        .send_to(
+            &Command::QueryFieldLayout.encode(),
+            ("127.0.0.1", config.command_port),
+        )
+        .unwrap();
+    robot
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let len = robot.recv(&mut buf).unwrap();
+    assert_eq!(
+        &buf[..len],
+        Command::encode_active_field_layout(Some("welded"))
+    );
+    assert_eq!(comm.active_field_layout().as_deref(), Some("welded"));
+}
+#[test]
+fn dropping_comm_releases_its_ports() {
+    let mut config = CommConfig::localhost();
+    config.remote.set_port(47121);
+    config.gyro_port = 47122;
+    config.command_port = 47123;
+    let comm = Comm::new(config);
+
+    // Clones share the same threads, so dropping one doesn't stop anything
+    drop(comm.clone());
+    std::thread::sleep(POLL_INTERVAL * 2);
+    assert!(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.gyro_port)).is_err());
+
+    // A second one can't listen on the same ports, and says so instead of panicking
+    let other = Comm::new(config);
+    let status = other.status();
+    assert!(status.gyro_error.is_some(), "{status:?}");
+    assert!(status.command_error.is_some(), "{status:?}");
+    drop(other);
+
+    drop(comm);
+    let deadline = Instant::now() + Duration::from_secs(1);
+    for port in [config.gyro_port, config.command_port] {
+        while let Err(err) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)) {
+            assert!(Instant::now() < deadline, "port {port} still taken: {err}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[test]
+fn send_failures_show_up_in_status() {
+    let mut config = CommConfig::localhost();
+    // Nothing listens here, so the kernel refuses the packets
+    config.remote.set_port(47131);
+    config.gyro_port = 47132;
+    config.command_port = 47133;
+    let comm = Comm::new(config);
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let error = loop {
+        comm.publish(0, 1, 0, RobotPose::default(), VisionUncertainty::default());
+        std::thread::sleep(Duration::from_millis(10));
+        if let Some(error) = comm.status().send_error {
+            break error;
+        }
+        assert!(Instant::now() < deadline, "send never failed");
+    };
+    assert!(error.starts_with("failed to send to the RIO"), "{error}");
+    assert_eq!(comm.status().client_error, None);
 }
 
 pub struct CommBundle;
@@ -190,12 +999,13 @@ bundle_resources!(CommBundle: Comm);
 impl ResourceBundle for CommBundle {
     fn build(
         bundle: BundleContext<Self>,
-        _config: Option<&ComponentConfig>,
+        config: Option<&ComponentConfig>,
         manager: &mut ResourceManager,
     ) -> CuResult<()> {
         let comm_key = bundle.key(CommBundleId::Comm);
+        let config = CommConfig::from_component_config(config)?;
 
-        manager.add_owned(comm_key, Comm::new())?;
+        manager.add_owned(comm_key, Comm::new(config))?;
 
         Ok(())
     }
@@ -0,0 +1,149 @@
+//!
+//! Packets waiting to be sent
+//!
+//! The pipelines shouldn't have to wait on the network, and the RIO only cares about the newest
+//! pose from each camera. So instead of queueing everything, each camera gets one slot per kind of
+//! packet, and a newer packet replaces whatever's still waiting in its slot.
+//!
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use chalkydri_core::prelude::Mutex;
+use chalkydri_core::prelude::parking_lot::Condvar;
+
+use crate::frame::PacketKind;
+
+/// Something that goes in an [`Outbox`]
+pub(crate) trait Slotted {
+    /// Which slot it goes in
+    fn slot(&self) -> (PacketKind, u8);
+
+    /// Whether it's worth replacing `pending`, which is in the same slot
+    fn replaces(&self, _pending: &Self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    pending: VecDeque<T>,
+    closed: bool,
+    /// How many packets were replaced before they could be sent
+    dropped: u64,
+}
+
+/// A queue that keeps only the latest packet in each slot
+#[derive(Debug)]
+pub(crate) struct Outbox<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+impl<T: Slotted> Outbox<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                pending: VecDeque::new(),
+                closed: false,
+                dropped: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queue a packet, replacing the one in its slot if that hasn't been sent yet
+    ///
+    /// If the pending packet is worth more than this one (see [`Slotted::replaces`]), this one is
+    /// thrown away instead. Returns `false` if the outbox has been closed.
+    pub fn push(&self, item: T) -> bool {
+        let mut state = self.state.lock();
+        if state.closed {
+            return false;
+        }
+
+        let slot = item.slot();
+        match state
+            .pending
+            .iter_mut()
+            .find(|pending| pending.slot() == slot)
+        {
+            Some(pending) if item.replaces(pending) => {
+                *pending = item;
+                state.dropped += 1;
+            }
+            Some(_) => return true,
+            None => state.pending.push_back(item),
+        }
+        self.ready.notify_one();
+
+        true
+    }
+
+    /// Wait up to `timeout` for the oldest packet
+    ///
+    /// Returns `None` once the outbox has been closed.
+    pub fn pop(&self, timeout: Duration) -> Option<Option<T>> {
+        let mut state = self.state.lock();
+        if state.pending.is_empty() && !state.closed {
+            self.ready.wait_for(&mut state, timeout);
+        }
+
+        if state.closed {
+            None
+        } else {
+            Some(state.pending.pop_front())
+        }
+    }
+
+    /// Stop accepting packets, and wake up whoever's waiting in [`Self::pop`]
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Get how many packets were replaced before they could be sent
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().dropped
+    }
+}
+
+#[test]
+fn outbox_keeps_latest() {
+    use crate::{Outgoing, VisionMeasurement};
+
+    let measurement = |camera_id, tag_count| {
+        Outgoing::Measurement(VisionMeasurement {
+            camera_id,
+            tag_count,
+            ..Default::default()
+        })
+    };
+    let popped = |outbox: &Outbox<Outgoing>| match outbox.pop(Duration::from_millis(1)) {
+        Some(Some(Outgoing::Measurement(measurement))) => {
+            Some((measurement.camera_id, measurement.tag_count))
+        }
+        _ => None,
+    };
+
+    let outbox = Outbox::new();
+    outbox.push(measurement(1, 1));
+    outbox.push(measurement(2, 1));
+    outbox.push(measurement(1, 2));
+    assert_eq!(outbox.dropped(), 1);
+
+    assert_eq!(popped(&outbox), Some((1, 2)));
+    assert_eq!(popped(&outbox), Some((2, 1)));
+    assert!(matches!(outbox.pop(Duration::from_millis(1)), Some(None)));
+
+    // A heartbeat doesn't replace a pose, but a pose does replace a heartbeat
+    outbox.push(measurement(1, 2));
+    outbox.push(measurement(1, 0));
+    assert_eq!(popped(&outbox), Some((1, 2)));
+    outbox.push(measurement(1, 0));
+    outbox.push(measurement(1, 1));
+    assert_eq!(popped(&outbox), Some((1, 1)));
+
+    outbox.close();
+    assert!(!outbox.push(measurement(1, 3)));
+    assert!(outbox.pop(Duration::from_millis(1)).is_none());
+}
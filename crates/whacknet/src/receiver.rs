@@ -0,0 +1,573 @@
+//!
+//! The robot's side of whacknet
+//!
+//! [`Receiver`] listens for packets from any number of coprocessors, decodes them, and keeps
+//! track of how fresh each camera is and how many of its packets went missing. It also sends the
+//! robot's state back and answers time sync requests, so anything that talks to Chalkydri (robot
+//! code, simulations, test harnesses) can share one implementation.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::detections;
+use crate::frame::{self, FrameError, PacketKind, SequenceTracker};
+use crate::sync::{self, Clock, SyncRequest, SyncResponse};
+use crate::{
+    GYRO_PORT, Health, RobotPose, RobotState, TagObservation, VisionMeasurement, VisionUncertainty,
+};
+
+/// A pose estimate from one camera
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub camera_id: u8,
+    /// How many tags the pose was solved from, or 0 if the camera didn't see any
+    pub tag_count: u8,
+    pub pose: RobotPose,
+    pub std_devs: VisionUncertainty,
+    /// When the frame was captured, on the receiver's clock (µs)
+    pub captured_at: u64,
+    /// Whether the coprocessor's clock was synced with ours
+    ///
+    /// Otherwise, `captured_at` doesn't count the time the packet spent on the network.
+    pub synced: bool,
+    pub sequence: u32,
+    /// When the packet arrived
+    pub received_at: Instant,
+}
+
+/// Every tag one camera saw in a frame
+#[derive(Debug, Clone)]
+pub struct Detections {
+    pub camera_id: u8,
+    /// When the frame was captured, on the receiver's clock (µs)
+    pub captured_at: u64,
+    /// Whether the coprocessor's clock was synced with ours, like [`Measurement::synced`]
+    pub synced: bool,
+    pub sequence: u32,
+    /// When the packet arrived
+    pub received_at: Instant,
+    pub tags: Vec<TagObservation>,
+}
+
+/// How a coprocessor is doing
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    /// Which coprocessor sent it
+    pub from: IpAddr,
+    pub sequence: u32,
+    /// When the packet arrived
+    pub received_at: Instant,
+    pub health: Health,
+}
+
+/// Something a coprocessor sent
+#[derive(Debug, Clone)]
+pub enum Packet {
+    Measurement(Measurement),
+    Detections(Detections),
+    Health(HealthReport),
+}
+
+/// Why a packet couldn't be received
+#[derive(Debug)]
+pub enum RecvError {
+    Io(io::Error),
+    /// The header was bad, so it was dropped
+    Frame(FrameError),
+    /// A kind of packet coprocessors don't send
+    UnexpectedKind(PacketKind),
+    /// The payload is the wrong size for its kind
+    BadPayload {
+        kind: PacketKind,
+        len: usize,
+    },
+}
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Frame(err) => write!(f, "{err}"),
+            Self::UnexpectedKind(kind) => write!(f, "unexpected {kind:?} packet"),
+            Self::BadPayload { kind, len } => {
+                write!(f, "{kind:?} packet has a {len} byte payload")
+            }
+        }
+    }
+}
+impl std::error::Error for RecvError {}
+impl From<io::Error> for RecvError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+impl From<FrameError> for RecvError {
+    fn from(err: FrameError) -> Self {
+        Self::Frame(err)
+    }
+}
+
+/// What we know about one camera
+#[derive(Debug, Default)]
+struct CameraState {
+    sequence: SequenceTracker,
+    last_seen: Option<Instant>,
+}
+
+/// Receives measurements from coprocessors and sends them the robot's state
+pub struct Receiver {
+    socket: UdpSocket,
+    cameras: HashMap<u8, CameraState>,
+    /// Every coprocessor we've heard from
+    coprocessors: HashSet<IpAddr>,
+    /// Each coprocessor's uptime in its latest health report, to tell when it restarts
+    uptimes: HashMap<IpAddr, Duration>,
+    /// Port coprocessors listen for the robot's state on
+    gyro_port: u16,
+    /// Sequence number of the last robot state sent
+    state_sequence: u32,
+    /// The robot's clock in µs, like the FPGA timestamp
+    clock: Box<dyn Fn() -> u64 + Send>,
+    /// How many packets have been dropped for being bad
+    rejected: u64,
+}
+impl Receiver {
+    /// Listen for measurements on `addr`
+    ///
+    /// Coprocessors send to port 7001 unless they've been configured otherwise.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            cameras: HashMap::new(),
+            coprocessors: HashSet::new(),
+            uptimes: HashMap::new(),
+            gyro_port: GYRO_PORT,
+            state_sequence: 0,
+            clock: {
+                let clock = Clock::new();
+                Box::new(move || clock.now())
+            },
+            rejected: 0,
+        })
+    }
+
+    /// Use another clock for timestamps and answering time sync requests
+    ///
+    /// Robot code should pass in the FPGA timestamp, so measurements line up with everything else.
+    /// By default, the clock counts µs since the receiver was created.
+    pub fn with_clock(mut self, clock: impl Fn() -> u64 + Send + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Send the robot's state to a port other than 7002
+    pub fn with_gyro_port(mut self, port: u16) -> Self {
+        self.gyro_port = port;
+        self
+    }
+
+    /// Get the address we're listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Set how long [`Self::recv`] waits for a packet, or `None` to wait forever
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Wait for the next packet, and decode it
+    ///
+    /// Time sync requests that arrive in the meantime are answered. Measurements that are
+    /// duplicates, or showed up after a newer one from the same camera, are dropped.
+    pub fn recv(&mut self) -> Result<Packet, RecvError> {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf)?;
+            let received_at = Instant::now();
+            let now = (self.clock)();
+
+            match self.handle(&buf[..len], from, received_at, now) {
+                Ok(Some(packet)) => return Ok(packet),
+                Ok(None) => {}
+                Err(err) => {
+                    self.rejected += 1;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Get every packet that's waiting, without blocking
+    ///
+    /// Bad packets are skipped, and counted in [`Self::rejected`].
+    pub fn poll(&mut self) -> io::Result<Vec<Packet>> {
+        self.socket.set_nonblocking(true)?;
+
+        let mut packets = Vec::new();
+        let result = loop {
+            match self.recv() {
+                Ok(packet) => packets.push(packet),
+                Err(RecvError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(RecvError::Io(err)) => break Err(err),
+                Err(_) => {}
+            }
+        };
+
+        self.socket.set_nonblocking(false)?;
+        result.map(|_| packets)
+    }
+
+    /// Handle a packet that arrived at `now` on our clock
+    ///
+    /// Returns `None` for packets that were handled here, like time sync requests.
+    fn handle(
+        &mut self,
+        buf: &[u8],
+        from: SocketAddr,
+        received_at: Instant,
+        now: u64,
+    ) -> Result<Option<Packet>, RecvError> {
+        let (header, payload) = frame::decode(buf)?;
+        let bad_payload = || RecvError::BadPayload {
+            kind: header.kind,
+            len: payload.len(),
+        };
+
+        match header.kind {
+            PacketKind::Measurement => {
+                let measurement: VisionMeasurement =
+                    sync::decode_payload(payload).ok_or_else(bad_payload)?;
+
+                let (captured_at, synced) =
+                    Self::capture_time(measurement.ts, measurement.flags, now);
+
+                self.coprocessors.insert(from.ip());
+                let camera = self.cameras.entry(measurement.camera_id).or_default();
+                // The camera's already moved on, so this would only take the pose back
+                if !camera.sequence.record(header.sequence) {
+                    return Ok(None);
+                }
+                camera.last_seen = Some(received_at);
+
+                Ok(Some(Packet::Measurement(Measurement {
+                    camera_id: measurement.camera_id,
+                    tag_count: measurement.tag_count,
+                    pose: measurement.pose,
+                    std_devs: measurement.std_devs,
+                    captured_at,
+                    synced,
+                    sequence: header.sequence,
+                    received_at,
+                })))
+            }
+            PacketKind::Detections => {
+                let (detections, tags) = detections::decode(payload).ok_or_else(bad_payload)?;
+                let (captured_at, synced) =
+                    Self::capture_time(detections.ts, detections.flags, now);
+
+                self.coprocessors.insert(from.ip());
+                let camera = self.cameras.entry(detections.camera_id).or_default();
+                camera.last_seen = Some(received_at);
+
+                Ok(Some(Packet::Detections(Detections {
+                    camera_id: detections.camera_id,
+                    captured_at,
+                    synced,
+                    sequence: header.sequence,
+                    received_at,
+                    tags,
+                })))
+            }
+            PacketKind::Health => {
+                let health = Health::decode(payload).ok_or_else(bad_payload)?;
+                self.coprocessors.insert(from.ip());
+
+                // Its sequence numbers started over too, so they shouldn't look late
+                let restarted = self
+                    .uptimes
+                    .insert(from.ip(), health.uptime)
+                    .is_some_and(|uptime| health.uptime < uptime);
+                if restarted {
+                    for camera in &health.cameras {
+                        if let Some(state) = self.cameras.get_mut(&camera.camera_id) {
+                            state.sequence.restart();
+                        }
+                    }
+                }
+
+                Ok(Some(Packet::Health(HealthReport {
+                    from: from.ip(),
+                    sequence: header.sequence,
+                    received_at,
+                    health,
+                })))
+            }
+            PacketKind::TimeSyncRequest => {
+                let request: SyncRequest = sync::decode_payload(payload).ok_or_else(bad_payload)?;
+                let response = SyncResponse {
+                    t0: request.t0,
+                    t1: now,
+                    t2: (self.clock)(),
+                };
+                let packet = frame::encode(
+                    PacketKind::TimeSyncResponse,
+                    header.sequence,
+                    bytemuck::bytes_of(&response),
+                );
+                self.socket.send_to(&packet, from)?;
+
+                Ok(None)
+            }
+            kind => Err(RecvError::UnexpectedKind(kind)),
+        }
+    }
+
+    /// Turn a packet's `ts` and `flags` into when the frame was captured on our clock, and whether
+    /// the clocks were synced
+    fn capture_time(ts: u64, flags: u8, now: u64) -> (u64, bool) {
+        if flags & VisionMeasurement::TS_RIO_TIME != 0 {
+            (ts, true)
+        } else {
+            (now.saturating_sub(ts), false)
+        }
+    }
+
+    /// Get how long ago we last heard from a camera
+    pub fn age(&self, camera_id: u8) -> Option<Duration> {
+        Some(self.cameras.get(&camera_id)?.last_seen?.elapsed())
+    }
+
+    /// Check whether we've heard from a camera within `max_age`
+    pub fn is_fresh(&self, camera_id: u8, max_age: Duration) -> bool {
+        self.age(camera_id).is_some_and(|age| age <= max_age)
+    }
+
+    /// Get the IDs of every camera we've heard from
+    pub fn cameras(&self) -> impl Iterator<Item = u8> + '_ {
+        self.cameras.keys().copied()
+    }
+
+    /// Get how many packets a camera has sent, and how many went missing
+    pub fn sequence(&self, camera_id: u8) -> Option<&SequenceTracker> {
+        self.cameras.get(&camera_id).map(|camera| &camera.sequence)
+    }
+
+    /// Get how many packets have been dropped for being bad
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Send the robot's state to every coprocessor we've heard from
+    pub fn send_robot_state(&mut self, state: &RobotState) -> io::Result<()> {
+        let addrs: Vec<_> = self
+            .coprocessors
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.gyro_port))
+            .collect();
+        for addr in addrs {
+            self.send_robot_state_to(addr, state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the robot's state to a specific coprocessor
+    ///
+    /// Use this before we've heard from it, since it won't solve without a heading by default.
+    pub fn send_robot_state_to(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        state: &RobotState,
+    ) -> io::Result<()> {
+        self.state_sequence = self.state_sequence.wrapping_add(1);
+        let packet = frame::encode(PacketKind::RobotState, self.state_sequence, &state.encode());
+        self.socket.send_to(&packet, addr)?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn receive_over_localhost() {
+    use crate::WhacknetClient;
+
+    // The robot's clock is 5 s ahead of ours
+    let clock = Clock::new();
+    let mut receiver = Receiver::bind("127.0.0.1:0")
+        .unwrap()
+        .with_clock(move || clock.now() + 5_000_000);
+    receiver.set_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut client = WhacknetClient::new(receiver.local_addr().unwrap()).unwrap();
+    client
+        .socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+
+    let request = SyncRequest { t0: clock.now() };
+    client
+        .socket
+        .send(&frame::encode(
+            PacketKind::TimeSyncRequest,
+            1,
+            bytemuck::bytes_of(&request),
+        ))
+        .unwrap();
+
+    for camera_id in [1, 2, 1] {
+        client
+            .send(VisionMeasurement {
+                pose: RobotPose {
+                    x: 1.0,
+                    y: 2.0,
+                    rot: 0.5,
+                },
+                ts: 30_000,
+                camera_id,
+                tag_count: 2,
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    let measurements: Vec<_> = (0..3)
+        .map(|_| match receiver.recv().unwrap() {
+            Packet::Measurement(measurement) => measurement,
+            packet => panic!("expected a measurement, got {packet:?}"),
+        })
+        .collect();
+    assert_eq!(measurements[0].pose.y, 2.0);
+    assert!(!measurements[0].synced);
+    assert!(clock.now() + 5_000_000 - measurements[0].captured_at >= 30_000);
+    assert_eq!(measurements[2].camera_id, 1);
+    assert_eq!(measurements[2].sequence, 2);
+    assert_eq!(receiver.sequence(1).unwrap().lost(), 0);
+    assert!(receiver.is_fresh(2, Duration::from_secs(1)));
+    assert!(!receiver.is_fresh(3, Duration::from_secs(1)));
+
+    // The time sync request was answered while waiting for measurements
+    let mut buf = [0u8; 128];
+    let len = client.socket.recv(&mut buf).unwrap();
+    let (header, payload) = frame::decode(&buf[..len]).unwrap();
+    assert_eq!(header.kind, PacketKind::TimeSyncResponse);
+    let mut estimator = sync::SyncEstimator::new();
+    estimator.add(
+        &sync::decode_payload(payload).unwrap(),
+        clock.now(),
+        Instant::now(),
+    );
+    let time_sync = estimator.estimate().unwrap();
+    assert!((time_sync.offset - 5_000_000).unsigned_abs() <= time_sync.rtt.as_micros() as u64);
+
+    // The robot's state goes back to whoever sent the measurements
+    let coprocessor = UdpSocket::bind("127.0.0.1:0").unwrap();
+    coprocessor
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let state = RobotState {
+        heading: 1.5,
+        rio_ts: 42,
+        ..Default::default()
+    };
+    receiver
+        .send_robot_state_to(coprocessor.local_addr().unwrap(), &state)
+        .unwrap();
+    let len = coprocessor.recv(&mut buf).unwrap();
+    let (header, payload) = frame::decode(&buf[..len]).unwrap();
+    assert_eq!(header.kind, PacketKind::RobotState);
+    assert_eq!(RobotState::decode(payload), Some(state));
+}
+
+#[test]
+fn restarted_coprocessor_starts_over() {
+    use crate::CameraHealth;
+
+    let mut receiver = Receiver::bind("127.0.0.1:0").unwrap();
+    let from: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+    let mut handle = |packet: Vec<u8>| {
+        receiver
+            .handle(&packet, from, Instant::now(), 1_000_000)
+            .unwrap();
+        receiver.sequence(1).map(|tracker| tracker.received())
+    };
+    let measurement = |sequence| {
+        let measurement = VisionMeasurement {
+            camera_id: 1,
+            tag_count: 1,
+            ..Default::default()
+        };
+        frame::encode(
+            PacketKind::Measurement,
+            sequence,
+            bytemuck::bytes_of(&measurement),
+        )
+    };
+    let health = |uptime| {
+        let health = Health {
+            uptime: Duration::from_secs(uptime),
+            cameras: vec![CameraHealth {
+                camera_id: 1,
+                online: true,
+                fps: 30.0,
+            }],
+            ..Default::default()
+        };
+        frame::encode(PacketKind::Health, 1, &health.encode())
+    };
+
+    for sequence in 1..=20 {
+        handle(measurement(sequence));
+    }
+    handle(health(60));
+    // Without anything else to go on, this looks like a late packet
+    assert_eq!(handle(measurement(3)), Some(20));
+
+    // Its uptime went backwards, so it restarted and its sequence numbers started over
+    handle(health(1));
+    assert_eq!(handle(measurement(4)), Some(21));
+    assert_eq!(handle(measurement(5)), Some(22));
+    assert_eq!(receiver.sequence(1).unwrap().lost(), 0);
+}
+
+#[test]
+fn drop_late_measurements_over_localhost() {
+    let mut receiver = Receiver::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let coprocessor = UdpSocket::bind("127.0.0.1:0").unwrap();
+    coprocessor.connect(receiver.local_addr().unwrap()).unwrap();
+
+    for sequence in [1, 2, 2, 1, 4, 3, 5] {
+        let measurement = VisionMeasurement {
+            camera_id: 1,
+            tag_count: 1,
+            ..Default::default()
+        };
+        coprocessor
+            .send(&frame::encode(
+                PacketKind::Measurement,
+                sequence,
+                bytemuck::bytes_of(&measurement),
+            ))
+            .unwrap();
+    }
+
+    let mut sequences = Vec::new();
+    while let Ok(packet) = receiver.recv() {
+        match packet {
+            Packet::Measurement(measurement) => sequences.push(measurement.sequence),
+            packet => panic!("expected a measurement, got {packet:?}"),
+        }
+    }
+    assert_eq!(sequences, [1, 2, 4, 5]);
+
+    // The one that was late isn't lost, it just wasn't used
+    let tracker = receiver.sequence(1).unwrap();
+    assert_eq!(tracker.received(), 5);
+    assert_eq!(tracker.lost(), 0);
+    assert_eq!(receiver.rejected(), 0);
+}
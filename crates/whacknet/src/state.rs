@@ -0,0 +1,139 @@
+//!
+//! Robot state from the RIO
+//!
+//! The RIO sends this to every coprocessor each loop (usually every 20 ms), framed like the rest of
+//! our packets. Besides the heading the solvers need, it tells us how fast the robot is moving, and
+//! where we are in the match.
+//!
+
+use bytemuck::{Pod, Zeroable};
+
+/// Which alliance the robot is on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Alliance {
+    /// The driver station hasn't told the robot yet
+    #[default]
+    Unknown,
+    Red,
+    Blue,
+}
+
+/// What part of the match we're in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchPhase {
+    /// Disabled, before, between, or after the match
+    #[default]
+    Disabled,
+    Autonomous,
+    Teleop,
+    Test,
+}
+
+/// The robot's state, as the RIO sees it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RobotState {
+    /// Heading in radians, CCW positive
+    pub heading: f64,
+    /// How fast the heading is changing (rad/s)
+    pub yaw_rate: f64,
+    /// Chassis velocity in the field's frame (m/s)
+    pub velocity_x: f64,
+    /// Chassis velocity in the field's frame (m/s)
+    pub velocity_y: f64,
+    /// When the state was read, in µs on the RIO's clock
+    pub rio_ts: u64,
+    pub alliance: Alliance,
+    pub phase: MatchPhase,
+}
+
+/// What actually goes over the wire, after the header
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+struct RobotStatePayload {
+    heading: f64,
+    yaw_rate: f64,
+    velocity_x: f64,
+    velocity_y: f64,
+    rio_ts: u64,
+    alliance: u8,
+    phase: u8,
+    /// Reserved for future use
+    _reserved: [u8; 6],
+}
+
+impl RobotState {
+    /// Size of the encoded payload in bytes
+    pub const PAYLOAD_LEN: usize = size_of::<RobotStatePayload>();
+
+    /// Encode the payload of a robot state packet
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = RobotStatePayload {
+            heading: self.heading,
+            yaw_rate: self.yaw_rate,
+            velocity_x: self.velocity_x,
+            velocity_y: self.velocity_y,
+            rio_ts: self.rio_ts,
+            alliance: match self.alliance {
+                Alliance::Unknown => 0,
+                Alliance::Red => 1,
+                Alliance::Blue => 2,
+            },
+            phase: match self.phase {
+                MatchPhase::Disabled => 0,
+                MatchPhase::Autonomous => 1,
+                MatchPhase::Teleop => 2,
+                MatchPhase::Test => 3,
+            },
+            ..Default::default()
+        };
+
+        bytemuck::bytes_of(&payload).to_vec()
+    }
+
+    /// Decode the payload of a robot state packet
+    ///
+    /// Unknown alliances and phases are treated as [`Alliance::Unknown`] and
+    /// [`MatchPhase::Disabled`]. Returns `None` if the payload is the wrong size.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != Self::PAYLOAD_LEN {
+            return None;
+        }
+        let payload: RobotStatePayload = bytemuck::pod_read_unaligned(payload);
+
+        Some(Self {
+            heading: payload.heading,
+            yaw_rate: payload.yaw_rate,
+            velocity_x: payload.velocity_x,
+            velocity_y: payload.velocity_y,
+            rio_ts: payload.rio_ts,
+            alliance: match payload.alliance {
+                1 => Alliance::Red,
+                2 => Alliance::Blue,
+                _ => Alliance::Unknown,
+            },
+            phase: match payload.phase {
+                1 => MatchPhase::Autonomous,
+                2 => MatchPhase::Teleop,
+                3 => MatchPhase::Test,
+                _ => MatchPhase::Disabled,
+            },
+        })
+    }
+}
+
+#[test]
+fn robot_state_roundtrip() {
+    assert_eq!(RobotState::PAYLOAD_LEN, 48);
+
+    let state = RobotState {
+        heading: 1.25,
+        yaw_rate: -0.5,
+        velocity_x: 2.0,
+        velocity_y: -1.0,
+        rio_ts: 123_456_789,
+        alliance: Alliance::Blue,
+        phase: MatchPhase::Teleop,
+    };
+    assert_eq!(RobotState::decode(&state.encode()), Some(state));
+    assert_eq!(RobotState::decode(&state.encode()[1..]), None);
+}
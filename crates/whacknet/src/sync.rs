@@ -0,0 +1,169 @@
+//!
+//! Clock synchronization with the RIO
+//!
+//! Every so often we send the RIO a request stamped with our clock (t0). It stamps when it got the
+//! request (t1) and when it replied (t2), and we stamp when the reply got back (t3). Like NTP,
+//! assuming the trip takes as long each way:
+//!
+//! ```text
+//! offset = ((t1 - t0) + (t2 - t3)) / 2
+//! rtt    = (t3 - t0) - (t2 - t1)
+//! ```
+//!
+//! Delays only ever make a trip longer, so out of the last few exchanges, the one with the shortest
+//! round trip gives the best offset.
+//!
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use bytemuck::{Pod, Zeroable};
+
+/// How often we ask the RIO for the time
+pub(crate) const SYNC_INTERVAL: Duration = Duration::from_millis(500);
+/// How many exchanges to pick the best one from
+const SYNC_WINDOW: usize = 16;
+/// Stop trusting the offset if we haven't heard back from the RIO in this long
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A monotonic clock in µs since it was created
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Clock {
+    epoch: Instant,
+}
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Turn an instant into µs on this clock
+    pub fn micros(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    /// Get the time in µs
+    pub fn now(&self) -> u64 {
+        self.micros(Instant::now())
+    }
+}
+
+/// How well our clock is synced with the RIO's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeSync {
+    /// Add this to our clock to get the RIO's (µs)
+    pub offset: i64,
+    /// Round trip time of the exchange the offset came from, which bounds how wrong it can be
+    pub rtt: Duration,
+    /// How long ago that exchange happened
+    pub age: Duration,
+}
+
+/// What the coprocessor sends
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub(crate) struct SyncRequest {
+    /// When we sent it, on our clock (µs)
+    pub t0: u64,
+}
+
+/// What the RIO replies with
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub(crate) struct SyncResponse {
+    /// Copied from the request
+    pub t0: u64,
+    /// When the RIO got the request, on its clock (µs)
+    pub t1: u64,
+    /// When the RIO replied, on its clock (µs)
+    pub t2: u64,
+}
+
+/// Decode a fixed size payload
+pub(crate) fn decode_payload<T: Pod>(payload: &[u8]) -> Option<T> {
+    (payload.len() == size_of::<T>()).then(|| bytemuck::pod_read_unaligned(payload))
+}
+
+/// Keeps the last few exchanges, to pick the best offset from
+#[derive(Debug, Default)]
+pub(crate) struct SyncEstimator {
+    /// Offset (µs), round trip time, and when we got the reply
+    samples: VecDeque<(i64, Duration, Instant)>,
+}
+impl SyncEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a finished exchange
+    ///
+    /// `t3` is when the reply got back, on our clock (µs). Replies that don't make sense, like ones
+    /// that took negative time, are ignored.
+    pub fn add(&mut self, response: &SyncResponse, t3: u64, received_at: Instant) {
+        let SyncResponse { t0, t1, t2 } = *response;
+        let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+
+        let rtt = (t3 - t0) - (t2 - t1);
+        if rtt < 0 || t2 < t1 {
+            return;
+        }
+        let offset = ((t1 - t0) + (t2 - t3)) / 2;
+
+        if self.samples.len() == SYNC_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back((offset, Duration::from_micros(rtt as u64), received_at));
+    }
+
+    /// Get the best estimate we have
+    ///
+    /// Returns `None` if we haven't heard from the RIO recently.
+    pub fn estimate(&self) -> Option<TimeSync> {
+        let (_, _, latest) = self.samples.back()?;
+        if latest.elapsed() > SYNC_TIMEOUT {
+            return None;
+        }
+
+        self.samples
+            .iter()
+            .min_by_key(|(_, rtt, _)| *rtt)
+            .map(|(offset, rtt, received_at)| TimeSync {
+                offset: *offset,
+                rtt: *rtt,
+                age: received_at.elapsed(),
+            })
+    }
+}
+
+#[test]
+fn sync_offset() {
+    let mut estimator = SyncEstimator::new();
+    let now = Instant::now();
+
+    // The RIO's clock is 1 s ahead, and the trip takes 2 ms each way, plus 3 ms of jitter
+    // on the way back the second time
+    estimator.add(
+        &SyncResponse {
+            t0: 10_000,
+            t1: 1_012_000,
+            t2: 1_012_100,
+        },
+        14_100,
+        now,
+    );
+    estimator.add(
+        &SyncResponse {
+            t0: 20_000,
+            t1: 1_022_000,
+            t2: 1_022_100,
+        },
+        27_100,
+        now,
+    );
+
+    let sync = estimator.estimate().unwrap();
+    assert_eq!(sync.offset, 1_000_000);
+    assert_eq!(sync.rtt, Duration::from_millis(4));
+}
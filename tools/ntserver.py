@@ -15,9 +15,9 @@ def main():
     inst.startServer()
 
     while True:
-        topics = inst.getTable("/chalkydri").getTopics()
+        topics = inst.getTopics("/Chalkydri/")
         if len(topics) > 0:
-            print(topics)
+            print([topic.getName() for topic in topics])
         time.sleep(0.5)
 
 